@@ -1,4 +1,6 @@
 
+use std::fmt;
+
 use crate::parser::SourceLoc;
 
 #[derive(Debug, Clone)]
@@ -6,12 +8,97 @@ use crate::parser::SourceLoc;
 pub struct MapDecl {
     pub name: String,
     pub map_type: MapType,
-    pub key_type: Type,
-    pub value_type: Type,
+    /// `None` for queues, stacks and ring buffers, which have no key.
+    pub key_type: Option<DataType>,
+    /// `None` for ring buffers, whose records are typed by each `reserve`
+    /// or `output`.
+    pub value_type: Option<DataType>,
     pub max_entries: u32,
+    /// Units a `prog_array` is filled with when the object is loaded, from
+    /// `programs: [0: parse_v4, 1: parse_v6];`.
+    pub programs: Vec<ProgramSlot>,
+    /// The map every value of a map of maps is shaped like, from
+    /// `inner: name;`.
+    pub inner: Option<String>,
+    /// Creation flags from `flags: [no_prealloc, rdonly_prog];`.
+    pub flags: Vec<MapFlag>,
+    /// `pinning: by_name;` keeps the map in bpffs, so a reloaded program
+    /// reuses it and its contents.
+    pub pinning: Pinning,
+    /// NUMA node to allocate the map on, from `numa_node: N;`.
+    pub numa_node: Option<u32>,
+    pub loc: SourceLoc,
+}
+
+/// One `index: unit` entry of a `programs` list.
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub struct ProgramSlot {
+    pub index: u32,
+    pub unit: String,
     pub loc: SourceLoc,
 }
 
+/// A `BPF_F_*` map creation flag.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum MapFlag {
+    /// Allocate hash entries on update instead of all up front.
+    NoPrealloc,
+    /// Give each CPU its own LRU list instead of one shared list.
+    NoCommonLru,
+    /// Userspace may only read the map.
+    Rdonly,
+    /// Userspace may only write the map.
+    Wronly,
+    /// Programs may only read the map.
+    RdonlyProg,
+    /// Programs may only write the map.
+    WronlyProg,
+    /// Userspace may `mmap` the array's values.
+    Mmapable,
+}
+
+impl MapFlag {
+    pub const ALL: [MapFlag; 7] = [
+        MapFlag::NoPrealloc,
+        MapFlag::NoCommonLru,
+        MapFlag::Rdonly,
+        MapFlag::Wronly,
+        MapFlag::RdonlyProg,
+        MapFlag::WronlyProg,
+        MapFlag::Mmapable,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|flag| flag.to_string() == name)
+    }
+}
+
+impl fmt::Display for MapFlag {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MapFlag::NoPrealloc => "no_prealloc",
+            MapFlag::NoCommonLru => "no_common_lru",
+            MapFlag::Rdonly => "rdonly",
+            MapFlag::Wronly => "wronly",
+            MapFlag::RdonlyProg => "rdonly_prog",
+            MapFlag::WronlyProg => "wronly_prog",
+            MapFlag::Mmapable => "mmapable",
+        };
+        f.write_str(name)
+    }
+}
+
+/// How libbpf shares the map between loads of the object.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum Pinning {
+    /// A fresh map each time the object is loaded.
+    #[default]
+    None,
+    /// Pinned at `<pin root>/<map name>`, and reused when already there.
+    ByName,
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 #[allow(unused)]
 pub enum MapType {
@@ -21,12 +108,211 @@ pub enum MapType {
     LruHash,
     ProgArray,
     PerfEventArray,
+    Queue,
+    Stack,
+    /// Hash, array and LRU hash maps with a separate value per CPU. A
+    /// program only sees the value of the CPU it runs on, so updates need
+    /// no atomics; userspace reads one value per possible CPU.
+    PercpuHash,
+    PercpuArray,
+    LruPercpuHash,
+    /// Longest-prefix-match trie, keyed by a struct of `prefixlen: u32`
+    /// followed by the address bytes.
+    LpmTrie,
+    /// Maps whose values are other maps, all shaped like an `inner`
+    /// prototype.
+    ArrayOfMaps,
+    HashOfMaps,
+    /// Redirect targets: network devices, CPUs, AF_XDP sockets and
+    /// sockets. Programs only use them through `redirect`.
+    Devmap,
+    DevmapHash,
+    Cpumap,
+    Xskmap,
+    Sockmap,
+    Sockhash,
+}
+
+impl fmt::Display for MapType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            MapType::Hash => "hash",
+            MapType::Array => "array",
+            MapType::Ringbuf => "ringbuf",
+            MapType::LruHash => "lru_hash",
+            MapType::ProgArray => "prog_array",
+            MapType::PerfEventArray => "perf_event_array",
+            MapType::Queue => "queue",
+            MapType::Stack => "stack",
+            MapType::PercpuHash => "percpu_hash",
+            MapType::PercpuArray => "percpu_array",
+            MapType::LruPercpuHash => "lru_percpu_hash",
+            MapType::LpmTrie => "lpm_trie",
+            MapType::ArrayOfMaps => "array_of_maps",
+            MapType::HashOfMaps => "hash_of_maps",
+            MapType::Devmap => "devmap",
+            MapType::DevmapHash => "devmap_hash",
+            MapType::Cpumap => "cpumap",
+            MapType::Xskmap => "xskmap",
+            MapType::Sockmap => "sockmap",
+            MapType::Sockhash => "sockhash",
+        };
+        f.write_str(name)
+    }
+}
+
+impl MapType {
+    /// Whether entries are addressed by a key. Queues and stacks are only
+    /// pushed to and popped from, and ring buffers only written.
+    pub fn has_key(self) -> bool {
+        !matches!(self, MapType::Queue | MapType::Stack | MapType::Ringbuf)
+    }
+
+    /// Whether every entry has the declared value type. The values of a
+    /// map of maps are typed by its `inner` prototype instead.
+    pub fn has_value(self) -> bool {
+        !matches!(self, MapType::Ringbuf | MapType::ArrayOfMaps | MapType::HashOfMaps)
+    }
+
+    pub fn is_map_of_maps(self) -> bool {
+        matches!(self, MapType::ArrayOfMaps | MapType::HashOfMaps)
+    }
+
+    /// Whether each CPU has its own copy of every value.
+    pub fn is_percpu(self) -> bool {
+        matches!(self, MapType::PercpuHash | MapType::PercpuArray | MapType::LruPercpuHash)
+    }
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum Type {
+    U8,
+    U16,
     U32,
     U64,
+    I8,
+    I16,
     I32,
     I64,
-}
\ No newline at end of file
+}
+
+/// Type of a map key or value, struct field or local buffer: a scalar, a
+/// declared struct, or a fixed-size array of scalars.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum DataType {
+    Scalar(Type),
+    Struct(String),
+    /// `u8[16]`: element type and length.
+    Array(Type, u32),
+}
+
+impl fmt::Display for DataType {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DataType::Scalar(ty) => ty.fmt(f),
+            DataType::Struct(name) => f.write_str(name),
+            DataType::Array(elem, len) => write!(f, "{}[{}]", elem, len),
+        }
+    }
+}
+
+impl DataType {
+    pub fn scalar(&self) -> Option<Type> {
+        match self {
+            DataType::Scalar(ty) => Some(*ty),
+            DataType::Struct(_) | DataType::Array(..) => None,
+        }
+    }
+}
+
+impl From<Type> for DataType {
+    fn from(ty: Type) -> Self {
+        DataType::Scalar(ty)
+    }
+}
+
+impl Type {
+    /// Size in bytes.
+    pub fn size(self) -> u8 {
+        match self {
+            Type::U8 | Type::I8 => 1,
+            Type::U16 | Type::I16 => 2,
+            Type::U32 | Type::I32 => 4,
+            Type::U64 | Type::I64 => 8,
+        }
+    }
+
+    pub fn is_signed(self) -> bool {
+        matches!(self, Type::I8 | Type::I16 | Type::I32 | Type::I64)
+    }
+
+    /// Whether every value of `self` is also a value of `other`, so the
+    /// conversion can happen implicitly.
+    pub fn widens_to(self, other: Type) -> bool {
+        match (self.is_signed(), other.is_signed()) {
+            (false, true) => self.size() < other.size(),
+            (true, false) => false,
+            _ => self.size() <= other.size(),
+        }
+    }
+
+    /// The type both operands of a binary operator are converted to, if one
+    /// of them widens to the other.
+    pub fn common(self, other: Type) -> Option<Type> {
+        if self.widens_to(other) {
+            Some(other)
+        } else if other.widens_to(self) {
+            Some(self)
+        } else {
+            None
+        }
+    }
+
+    /// Whether the constant `value` is representable in this type.
+    pub fn fits(self, value: i64) -> bool {
+        let bits = u32::from(self.size()) * 8;
+        if self.is_signed() {
+            bits == 64 || (value >= -(1i64 << (bits - 1)) && value < (1i64 << (bits - 1)))
+        } else {
+            value >= 0 && (bits == 64 || value < (1i64 << bits))
+        }
+    }
+
+    /// Whether `value` is a bit pattern of this width, read as either signed
+    /// or unsigned: `~0xff` is a fine mask for a `u32`.
+    pub fn holds_bits(self, value: i64) -> bool {
+        let bits = u32::from(self.size()) * 8;
+        bits == 64 || (value >= -(1i64 << (bits - 1)) && value < (1i64 << bits))
+    }
+
+    /// `value` converted to this type the way a C cast would: truncated to
+    /// the width, then sign- or zero-extended back.
+    pub fn wrap(self, value: i64) -> i64 {
+        let bits = u32::from(self.size()) * 8;
+        if bits == 64 {
+            return value;
+        }
+        let truncated = value & ((1i64 << bits) - 1);
+        if self.is_signed() && truncated >= (1i64 << (bits - 1)) {
+            truncated - (1i64 << bits)
+        } else {
+            truncated
+        }
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Type::U8 => "u8",
+            Type::U16 => "u16",
+            Type::U32 => "u32",
+            Type::U64 => "u64",
+            Type::I8 => "i8",
+            Type::I16 => "i16",
+            Type::I32 => "i32",
+            Type::I64 => "i64",
+        };
+        f.write_str(name)
+    }
+}
@@ -1,11 +1,15 @@
 
 pub mod program;
 pub mod map;
+pub mod structs;
 pub mod unit;
 
 pub use program::Program;
-pub use map::{MapDecl, MapType, Type};
+pub use map::{DataType, MapDecl, MapFlag, MapType, Pinning, ProgramSlot, Type};
+pub use structs::{StructDecl, StructField};
 pub use unit::{
-    Assignment, AssignmentOp, Expr, ExprKind, HeapLookup, HeapVarDecl,
-    IfGuard, MethodCall, Stmt, StmtKind, Unit, VarDecl, VarType,BinaryExpr, BinOp
+    Assignment, AssignmentOp, Expr, ExprKind, HeapVarDecl,
+    IfGuard, MethodCall, Stmt, StmtKind, Unit, VarDecl, VarType,BinaryExpr, BinOp,
+    UnaryExpr, UnaryOp, CastExpr, ForLoop, FieldAccess, StructLiteral, FieldInit, IndexExpr,
+    AtomicExpr, AtomicOp, Cidr, Redirect, TailCall
 };
\ No newline at end of file
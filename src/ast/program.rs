@@ -1,8 +1,9 @@
-use super::{MapDecl, Unit};
+use super::{MapDecl, StructDecl, Unit};
 
 #[derive(Debug, Clone)]
 #[allow(unused)]
 pub struct Program {
+    pub structs: Vec<StructDecl>,
     pub maps: Vec<MapDecl>,
     pub units: Vec<Unit>,
 }
\ No newline at end of file
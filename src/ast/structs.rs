@@ -0,0 +1,76 @@
+use crate::ast::{DataType, Type};
+use crate::parser::SourceLoc;
+
+/// `struct name { field: type, ... }` at program level. Structs can be map
+/// keys and values; their layout follows the C rules so userspace sees the
+/// same bytes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(unused)]
+pub struct StructDecl {
+    pub name: String,
+    pub fields: Vec<StructField>,
+    pub loc: SourceLoc,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[allow(unused)]
+pub struct StructField {
+    pub name: String,
+    /// A scalar or an array; structs do not nest.
+    pub ty: DataType,
+    pub loc: SourceLoc,
+}
+
+impl StructDecl {
+    pub fn field(&self, name: &str) -> Option<&StructField> {
+        self.fields.iter().find(|f| f.name == name)
+    }
+
+    /// Alignment of the struct: that of its most aligned field.
+    pub fn align(&self) -> u32 {
+        self.fields.iter().map(|f| field_layout(&f.ty).1).max().unwrap_or(1)
+    }
+
+    /// Byte offset of every field, each aligned to its element size, and
+    /// the total size including tail padding.
+    pub fn layout(&self) -> (Vec<u32>, u32) {
+        let mut offsets = Vec::with_capacity(self.fields.len());
+        let mut offset = 0;
+        for field in &self.fields {
+            let (size, align) = field_layout(&field.ty);
+            offset = align_up(offset, align);
+            offsets.push(offset);
+            offset += size;
+        }
+        (offsets, align_up(offset, self.align()))
+    }
+
+    pub fn size(&self) -> u32 {
+        self.layout().1
+    }
+
+    /// The address field of an `lpm_trie` key, which is laid out as a
+    /// `prefixlen: u32` followed by the address bytes.
+    pub fn lpm_data(&self) -> Option<&StructField> {
+        match self.fields.as_slice() {
+            [prefixlen, data] if prefixlen.name == "prefixlen" && prefixlen.ty == DataType::Scalar(Type::U32) => {
+                Some(data)
+            }
+            _ => None,
+        }
+    }
+}
+
+/// Size and alignment of a field. A nested struct is rejected by semantic
+/// analysis and takes no space here.
+pub fn field_layout(ty: &DataType) -> (u32, u32) {
+    match ty {
+        DataType::Scalar(ty) => (u32::from(ty.size()), u32::from(ty.size())),
+        DataType::Array(elem, len) => (u32::from(elem.size()) * len, u32::from(elem.size())),
+        DataType::Struct(_) => (0, 1),
+    }
+}
+
+fn align_up(offset: u32, align: u32) -> u32 {
+    offset.div_ceil(align) * align
+}
@@ -1,3 +1,6 @@
+use std::net::IpAddr;
+
+use crate::ast::{DataType, Type};
 use crate::parser::SourceLoc;
 
 #[derive(Debug, Clone)]
@@ -7,6 +10,9 @@ pub struct Unit {
     pub loc: SourceLoc,
     pub sections: Vec<String>,
     pub license: Option<String>,
+    /// Verdict returned when a packet is too short for the loads in the
+    /// body, from `fallback: XDP_DROP;`. Defaults per program type.
+    pub fallback: Option<String>,
     pub body: Vec<Stmt>,
 }
 
@@ -24,7 +30,11 @@ pub enum StmtKind {
     VarDecl(VarDecl),
     HeapVarDecl(HeapVarDecl),
     Assignment(Assignment),
+    /// A call made for its effect, such as `counts.delete(key);`.
+    Expr(Expr),
     IfGuard(IfGuard),
+    For(ForLoop),
+    TailCall(TailCall),
 }
 
 #[derive(Debug, Clone)]
@@ -33,6 +43,10 @@ pub struct Assignment {
     pub target: Box<Expr>,
     pub op: AssignmentOp,
     pub value: Box<Expr>,
+    /// `atomic *p += n`: the update of a map value is a single atomic
+    /// instruction, so concurrent updates from other CPUs are not lost.
+    /// Only `+=`, `-=`, `&=`, `|=` and `^=` have one.
+    pub atomic: bool,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -44,6 +58,30 @@ pub enum AssignmentOp {
     MulAssign,
     DivAssign,
     ModAssign,
+    AndAssign,
+    OrAssign,
+    XorAssign,
+    ShlAssign,
+    ShrAssign,
+}
+
+impl AssignmentOp {
+    /// The operator a compound assignment applies; `None` for plain `=`.
+    pub fn binary_op(self) -> Option<BinOp> {
+        match self {
+            AssignmentOp::Assign => None,
+            AssignmentOp::AddAssign => Some(BinOp::Add),
+            AssignmentOp::SubAssign => Some(BinOp::Sub),
+            AssignmentOp::MulAssign => Some(BinOp::Mul),
+            AssignmentOp::DivAssign => Some(BinOp::Div),
+            AssignmentOp::ModAssign => Some(BinOp::Mod),
+            AssignmentOp::AndAssign => Some(BinOp::BitAnd),
+            AssignmentOp::OrAssign => Some(BinOp::BitOr),
+            AssignmentOp::XorAssign => Some(BinOp::BitXor),
+            AssignmentOp::ShlAssign => Some(BinOp::Shl),
+            AssignmentOp::ShrAssign => Some(BinOp::Shr),
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -51,6 +89,39 @@ pub enum AssignmentOp {
 pub struct IfGuard {
     pub condition: Expr,
     pub body: Vec<Stmt>,
+    /// Statements of the `else` arm; an `else if` is a single nested
+    /// `IfGuard` statement. Empty when there is no `else`.
+    pub else_body: Vec<Stmt>,
+}
+
+/// `tail_call(map, index)`: jump to the program in slot `index` of a
+/// `prog_array`. Execution only continues past it when the slot is empty.
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub struct TailCall {
+    pub map_name: String,
+    pub index: Expr,
+}
+
+/// `redirect(map, key, fallback)`: XDP's `bpf_redirect_map` or the socket
+/// redirect helpers of sk_skb and sk_msg programs.
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub struct Redirect {
+    pub map_name: String,
+    pub key: Box<Expr>,
+    pub fallback: Box<Expr>,
+}
+
+/// `for var in start..end { body }`. Both bounds must be compile-time
+/// constants so the trip count is known.
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub struct ForLoop {
+    pub var: String,
+    pub start: Expr,
+    pub end: Expr,
+    pub body: Vec<Stmt>,
 }
 
 #[derive(Debug, Clone)]
@@ -68,6 +139,19 @@ pub enum BinOp {
     Mul,
     Div,
     Mod,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
 }
 
 #[derive(Debug, Clone)]
@@ -84,9 +168,117 @@ pub enum ExprKind {
     Variable(String),
     Number(i64),
     MethodCall(MethodCall),
-    HeapLookup(HeapLookup),
     Dereference(Box<Expr>),
     Binary(BinaryExpr),
+    Unary(UnaryExpr),
+    Cast(CastExpr),
+    /// `ptr.field` on a `heap` pointer to a struct.
+    Field(FieldAccess),
+    /// `name { field: value, ... }`, used to build struct map keys.
+    StructLiteral(StructLiteral),
+    /// `base[index]` on an array buffer, a `heap` pointer to an array, or an
+    /// array field.
+    Index(IndexExpr),
+    /// `atomic xchg(place, value)` or `atomic cmpxchg(place, old, new)` on
+    /// a map value; evaluates to the value the place held before.
+    Atomic(AtomicExpr),
+    /// `10.0.0.0/8` or `2001:db8::/32`, the key of an `lpm_trie` lookup.
+    Cidr(Cidr),
+    /// `redirect(map, key, fallback)`: the verdict that sends the packet or
+    /// message to the target in slot `key`, or `fallback` when it is empty.
+    Redirect(Redirect),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub prefix_len: u8,
+}
+
+impl Cidr {
+    /// The address bytes in network order, as the trie compares them.
+    pub fn octets(&self) -> Vec<u8> {
+        match self.addr {
+            IpAddr::V4(addr) => addr.octets().to_vec(),
+            IpAddr::V6(addr) => addr.octets().to_vec(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[allow(unused)]
+pub enum UnaryOp {
+    Not,
+    BitNot,
+}
+
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub struct UnaryExpr {
+    pub op: UnaryOp,
+    pub operand: Box<Expr>,
+}
+
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub struct CastExpr {
+    pub expr: Box<Expr>,
+    pub ty: Type,
+}
+
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub struct FieldAccess {
+    pub base: Box<Expr>,
+    pub field: String,
+}
+
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub struct IndexExpr {
+    pub base: Box<Expr>,
+    pub index: Box<Expr>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum AtomicOp {
+    /// Store the value.
+    Xchg,
+    /// Store the new value if the place holds the old one.
+    Cmpxchg,
+}
+
+impl AtomicOp {
+    /// Number of values the operation takes after the place.
+    pub fn arity(self) -> usize {
+        match self {
+            AtomicOp::Xchg => 1,
+            AtomicOp::Cmpxchg => 2,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub struct AtomicExpr {
+    pub op: AtomicOp,
+    pub place: Box<Expr>,
+    pub args: Vec<Expr>,
+}
+
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub struct StructLiteral {
+    pub name: String,
+    pub fields: Vec<FieldInit>,
+}
+
+#[derive(Debug, Clone)]
+#[allow(unused)]
+pub struct FieldInit {
+    pub name: String,
+    pub value: Expr,
+    pub loc: SourceLoc,
 }
 
 #[derive(Debug, Clone)]
@@ -94,7 +286,12 @@ pub enum ExprKind {
 pub struct VarDecl {
     pub name: String,
     pub var_type: VarType,
-    pub value: Box<Expr>,
+    /// Declared type from `reg x: u32 = ...`; inferred from the value when
+    /// absent.
+    pub ty: Option<DataType>,
+    /// Initial value; only an array buffer (`reg buf: u8[16];`) may omit
+    /// it, and starts zeroed.
+    pub value: Option<Box<Expr>>,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
@@ -104,24 +301,27 @@ pub enum VarType {
     Imm,
 }
 
-#[derive(Debug, Clone)]
-#[allow(unused)]
-pub struct HeapLookup {
-    pub map_name: String,
-    pub key_expr: Box<Expr>,
-}
-
 #[derive(Debug, Clone)]
 #[allow(unused)]
 pub struct MethodCall {
     pub receiver: String,
     pub method: String,
-    pub arg: Box<Expr>,
+    /// The record type of `ringbuf.reserve(T)`, which takes a type rather
+    /// than a value.
+    pub type_arg: Option<DataType>,
+    pub args: Vec<Expr>,
+    /// `outer.lookup(k).lookup(inner_key)` on a map of maps: the key looked
+    /// up in the inner map the first lookup finds.
+    pub inner_key: Option<Box<Expr>>,
 }
 
 #[derive(Debug, Clone)]
 #[allow(unused)]
 pub struct HeapVarDecl {
     pub name: String,
-    pub lookup: HeapLookup,
+    /// Declared type of the map value the pointer refers to.
+    pub ty: Option<DataType>,
+    /// The map method producing the pointer: `lookup`, `lookup_or_init`,
+    /// `pop`, `peek` or `reserve`.
+    pub call: MethodCall,
 }
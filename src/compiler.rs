@@ -1,5 +1,7 @@
+use crate::diagnostics::DiagnosticReporter;
 use crate::emit::ebpf_c::program::emit_program;
 use crate::parser;
+use crate::sema;
 use crate::source_manager::SourceManager;
 use miette::{IntoDiagnostic, Report, WrapErr};
 use std::fs;
@@ -27,8 +29,9 @@ pub fn compile(input_path: &Path, output_path: &Path) -> Result<(), miette::Repo
         return Err(miette::miette!("Empty input source code"));
     }
 
+    let file_name = input_path.display().to_string();
     let mut sources = SourceManager::new();
-    let _file_id = sources.add_file(input_path.display().to_string(), src.clone());
+    let _file_id = sources.add_file(file_name.clone(), src.clone());
 
     let program = match parser::parse(&src) {
         Ok(prog) => prog,
@@ -38,11 +41,139 @@ pub fn compile(input_path: &Path, output_path: &Path) -> Result<(), miette::Repo
             return Err(report);
         }
     };
+
+    let mut diagnostics = DiagnosticReporter::new();
+    sema::check_program(&program, &mut diagnostics);
+    flush_diagnostics(diagnostics, &file_name, &src)?;
+
     let program_ir = crate::ir::lower_program(&program).map_err(|e| miette::miette!("{e:?}"))?;
 
+    // Null safety and record release are properties of control flow, so
+    // they run on the lowered CFG rather than the AST.
+    let mut diagnostics = DiagnosticReporter::new();
+    for unit in &program_ir.units {
+        sema::null_safety::check_null_safety(unit, &mut diagnostics);
+        sema::ringbuf::check_records_released(unit, &mut diagnostics);
+    }
+    flush_diagnostics(diagnostics, &file_name, &src)?;
+
     emit_program(&program_ir, output_path)
         .map_err(|e| miette::miette!("{:?}", e))
         .wrap_err("Failed to emit program")?;
 
     Ok(())
 }
+
+/// Prints every collected diagnostic and fails if any of them is an error.
+fn flush_diagnostics(diagnostics: DiagnosticReporter, file_name: &str, src: &str) -> Result<(), miette::Report> {
+    let error_count = diagnostics.error_count();
+    for report in diagnostics.into_reports(file_name, src) {
+        eprintln!("{:?}", report);
+    }
+    if error_count > 0 {
+        return Err(miette::miette!(
+            "Semantic analysis failed with {} error(s)",
+            error_count
+        ));
+    }
+    Ok(())
+}
+
+/// Helpers running the same phases as [`compile`] on a source string, for
+/// the tests of every phase.
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use crate::diagnostics::Severity;
+
+    /// Every diagnostic `src` produces, as `(severity, message)`, stopping
+    /// after the first phase that reports an error, as [`compile`] does.
+    pub fn diagnostics(src: &str) -> Vec<(Severity, String)> {
+        let program = match parser::parse(src) {
+            Ok(program) => program,
+            Err(e) => return vec![(Severity::Error, e.message)],
+        };
+
+        let mut diagnostics = DiagnosticReporter::new();
+        sema::check_program(&program, &mut diagnostics);
+        let mut found = collect(&diagnostics);
+        if diagnostics.has_errors() {
+            return found;
+        }
+
+        let program_ir = match crate::ir::lower_program(&program) {
+            Ok(ir) => ir,
+            Err(e) => {
+                found.push((Severity::Error, e.to_string()));
+                return found;
+            }
+        };
+        let mut diagnostics = DiagnosticReporter::new();
+        for unit in &program_ir.units {
+            sema::null_safety::check_null_safety(unit, &mut diagnostics);
+            sema::ringbuf::check_records_released(unit, &mut diagnostics);
+        }
+        found.extend(collect(&diagnostics));
+        found
+    }
+
+    /// Messages of the errors `src` is rejected with.
+    pub fn errors(src: &str) -> Vec<String> {
+        messages(src, Severity::Error)
+    }
+
+    /// Messages of the warnings `src` compiles with.
+    pub fn warnings(src: &str) -> Vec<String> {
+        messages(src, Severity::Warning)
+    }
+
+    /// Asserts that `src` is rejected with an error containing `message`.
+    pub fn assert_error(src: &str, message: &str) {
+        let errors = errors(src);
+        assert!(
+            errors.iter().any(|e| e.contains(message)),
+            "expected an error containing {:?}, found {:?}",
+            message,
+            errors
+        );
+    }
+
+    /// The C generated for `src`, which must compile without errors.
+    pub fn compile_to_c(src: &str) -> String {
+        crate::emit::ebpf_c::program::emit_c(&lower(src)).expect("C emission failed")
+    }
+
+    /// The IR of `src`, which must compile without errors.
+    pub fn lower(src: &str) -> crate::ir::ProgramIr {
+        let errors = errors(src);
+        assert!(errors.is_empty(), "unexpected errors: {:?}", errors);
+        let program = parser::parse(src).expect("parsed above");
+        crate::ir::lower_program(&program).expect("lowered above")
+    }
+
+    /// The function generated for unit `name` in `c`, from its `SEC` line to
+    /// its closing brace.
+    pub fn function<'c>(c: &'c str, name: &str) -> &'c str {
+        let signature = format!("int {}(", name);
+        let start = c.find(&signature).unwrap_or_else(|| panic!("no function {} in:\n{}", name, c));
+        let start = c[..start].rfind("SEC(").unwrap_or(start);
+        let end = c[start..].find("\n}\n").map_or(c.len(), |end| start + end + 3);
+        &c[start..end]
+    }
+
+    fn messages(src: &str, severity: Severity) -> Vec<String> {
+        diagnostics(src)
+            .into_iter()
+            .filter(|(s, _)| *s == severity)
+            .map(|(_, message)| message)
+            .collect()
+    }
+
+    fn collect(diagnostics: &DiagnosticReporter) -> Vec<(Severity, String)> {
+        diagnostics
+            .diagnostics()
+            .iter()
+            .map(|d| (d.severity, d.message.clone()))
+            .collect()
+    }
+}
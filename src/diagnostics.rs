@@ -1,5 +1,6 @@
 #![allow(unused)]
 
+use crate::parser::SourceLoc;
 use miette::{Diagnostic, SourceSpan};
 use std::ops::Range;
 use thiserror::Error;
@@ -91,4 +92,113 @@ impl CompileDiagnostic {
             code: code.into(),
         }
     }
-}
\ No newline at end of file
+}
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+/// A single problem found after parsing, pointing at the offending source
+/// location.
+#[derive(Error, Debug)]
+#[error("{message}")]
+pub struct SemanticDiagnostic {
+    pub severity: Severity,
+    pub message: String,
+    pub span: SourceSpan,
+    pub help: Option<String>,
+}
+
+impl Diagnostic for SemanticDiagnostic {
+    fn severity(&self) -> Option<miette::Severity> {
+        Some(match self.severity {
+            Severity::Error => miette::Severity::Error,
+            Severity::Warning => miette::Severity::Warning,
+        })
+    }
+
+    fn help<'a>(&'a self) -> Option<Box<dyn std::fmt::Display + 'a>> {
+        self.help
+            .as_ref()
+            .map(|h| Box::new(h) as Box<dyn std::fmt::Display>)
+    }
+
+    fn labels(&self) -> Option<Box<dyn Iterator<Item = miette::LabeledSpan> + '_>> {
+        Some(Box::new(std::iter::once(miette::LabeledSpan::new_with_span(
+            Some("here".to_string()),
+            self.span,
+        ))))
+    }
+}
+
+/// Collects every error and warning of the semantic phases so they can all
+/// be shown at once instead of stopping at the first one.
+#[derive(Debug, Default)]
+pub struct DiagnosticReporter {
+    diagnostics: Vec<SemanticDiagnostic>,
+}
+
+impl DiagnosticReporter {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn report(
+        &mut self,
+        severity: Severity,
+        message: impl Into<String>,
+        loc: SourceLoc,
+    ) -> &mut SemanticDiagnostic {
+        self.diagnostics.push(SemanticDiagnostic {
+            severity,
+            message: message.into(),
+            span: (loc.offset..loc.offset + 1).into(),
+            help: None,
+        });
+        self.diagnostics.last_mut().unwrap()
+    }
+
+    pub fn report_error(&mut self, message: impl Into<String>, loc: SourceLoc) -> &mut SemanticDiagnostic {
+        self.report(Severity::Error, message, loc)
+    }
+
+    pub fn report_warning(&mut self, message: impl Into<String>, loc: SourceLoc) -> &mut SemanticDiagnostic {
+        self.report(Severity::Warning, message, loc)
+    }
+
+    pub fn error_count(&self) -> usize {
+        self.diagnostics
+            .iter()
+            .filter(|d| d.severity == Severity::Error)
+            .count()
+    }
+
+    pub fn has_errors(&self) -> bool {
+        self.error_count() > 0
+    }
+
+    /// Every diagnostic collected so far, in the order it was reported.
+    pub fn diagnostics(&self) -> &[SemanticDiagnostic] {
+        &self.diagnostics
+    }
+
+    /// Turns the collected diagnostics into reports that render the
+    /// offending lines of `source`.
+    pub fn into_reports(self, name: &str, source: &str) -> Vec<miette::Report> {
+        self.diagnostics
+            .into_iter()
+            .map(|d| {
+                miette::Report::new(d)
+                    .with_source_code(miette::NamedSource::new(name, source.to_string()))
+            })
+            .collect()
+    }
+}
+
+impl SemanticDiagnostic {
+    pub fn with_help(&mut self, help: impl Into<String>) -> &mut Self {
+        self.help = Some(help.into());
+        self
+    }
+}
@@ -0,0 +1,1278 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+use std::fmt::Write;
+
+use crate::ast::DataType;
+use crate::emit::ebpf_c::maps::{data_type_to_c, declarator, sanitize_ident};
+use crate::emit::util::fmt_err;
+use crate::ir::cfg::{immediate_post_dominators, join_point, natural_loops};
+use crate::sema::SectionValidator;
+use crate::ir::{
+    AtomicOp, BinaryOp, BlockId, Instruction, Opcode, Operand, RedirectHelper, Terminator, UnaryOp, UnitIr, VarId,
+};
+
+/// What the shared body emitter needs to know about the program type it is
+/// rendering into. Everything else (section, signature, prologue) is written
+/// by the per-section emitter before calling [`emit_body`].
+pub struct BodyConfig<'a> {
+    /// Name of the context parameter in the function signature.
+    pub ctx: &'a str,
+}
+
+pub fn emit_body(out: &mut String, unit: &UnitIr, cfg: &BodyConfig) -> Result<(), String> {
+    if reads_packet(unit) {
+        writeln!(out, "    void *__data = (void *)(long){}->data;", cfg.ctx).map_err(fmt_err)?;
+        writeln!(out, "    void *__data_end = (void *)(long){}->data_end;", cfg.ctx).map_err(fmt_err)?;
+    }
+
+    for (id, var) in unit.vars.iter().enumerate() {
+        let slot = local(VarId(id as u32));
+        // A pointer to an array points at its first element, so indexing
+        // reads the same for a buffer and a map value.
+        let decl = match (&var.ty, var.is_ptr) {
+            (DataType::Array(elem, _), true) => format!("{} *{}", data_type_to_c(&(*elem).into()), slot),
+            (ty, true) => format!("{} *{}", data_type_to_c(ty), slot),
+            (ty, false) => declarator(ty, &slot),
+        };
+        // Struct and array slots are map keys; zeroing them up front keeps
+        // padding and unset bytes defined for the verifier.
+        let init = if var.ty.scalar().is_none() && !var.is_ptr { "{}" } else { "0" };
+        match &var.name {
+            Some(name) => writeln!(out, "    {} = {}; /* {} */", decl, init, name),
+            None => writeln!(out, "    {} = {};", decl, init),
+        }
+        .map_err(fmt_err)?;
+    }
+
+    let mut emitter = BodyEmitter {
+        out: String::new(),
+        cfg,
+        unit,
+        ipdom: immediate_post_dominators(unit),
+        loops: natural_loops(unit),
+        emitted: HashSet::new(),
+        labels: HashSet::new(),
+        goto_targets: HashSet::new(),
+    };
+    emitter.emit_region(unit.entry, None, 1)?;
+
+    // Control flow that does not nest (e.g. a jump back into code that was
+    // already emitted) becomes a `goto`; its target needs a label, which is
+    // only known after a first pass.
+    if !emitter.goto_targets.is_empty() {
+        emitter.labels = std::mem::take(&mut emitter.goto_targets);
+        emitter.emitted.clear();
+        emitter.out.clear();
+        emitter.emit_region(unit.entry, None, 1)?;
+    }
+
+    out.push_str(&emitter.out);
+    Ok(())
+}
+
+/// Whether the body bounds-checks or loads packet data, and so needs the
+/// packet pointers read from the context.
+fn reads_packet(unit: &UnitIr) -> bool {
+    unit.blocks
+        .iter()
+        .flat_map(|b| &b.instructions)
+        .any(|inst| matches!(inst.opcode, Opcode::BoundsCheck { .. } | Opcode::LoadPacket { .. }))
+}
+
+struct BodyEmitter<'a, 'b> {
+    out: String,
+    cfg: &'a BodyConfig<'b>,
+    unit: &'a UnitIr,
+    ipdom: HashMap<BlockId, Option<BlockId>>,
+    loops: HashMap<BlockId, BTreeSet<BlockId>>,
+    emitted: HashSet<BlockId>,
+    labels: HashSet<BlockId>,
+    goto_targets: HashSet<BlockId>,
+}
+
+impl BodyEmitter<'_, '_> {
+    /// Emits the straight-line region starting at `id` until control reaches
+    /// `stop`, the merge point of the enclosing branch. Returns whether the
+    /// region falls through to `stop`; `false` means every path in it
+    /// returned or jumped elsewhere.
+    fn emit_region(&mut self, id: BlockId, stop: Option<BlockId>, depth: usize) -> Result<bool, String> {
+        let mut current = id;
+
+        loop {
+            if Some(current) == stop {
+                return Ok(true);
+            }
+            if !self.emitted.insert(current) {
+                writeln!(self.out, "{}goto bb{};", indent(depth), current.0).map_err(fmt_err)?;
+                self.goto_targets.insert(current);
+                return Ok(false);
+            }
+
+            let unit = self.unit;
+            let block = unit
+                .block(current)
+                .ok_or_else(|| format!("Unknown block: {}", current.0))?;
+
+            if self.labels.contains(&current) {
+                writeln!(self.out, "{}bb{}:", indent(depth - 1), current.0).map_err(fmt_err)?;
+            }
+
+            if self.loops.contains_key(&current) {
+                if let Some(exit) = self.emit_loop(current, depth)? {
+                    current = exit;
+                    continue;
+                }
+            }
+
+            let inlined_cond = self.emit_instructions(current, depth)?;
+
+            match &block.terminator {
+                Terminator::Return(value) => {
+                    writeln!(self.out, "{}return {};", indent(depth), format_operand(value))
+                        .map_err(fmt_err)?;
+                    return Ok(false);
+                }
+
+                Terminator::Jump(target) => current = *target,
+
+                Terminator::Branch { condition, true_block, false_block } => {
+                    let cond = inlined_cond.unwrap_or_else(|| format_operand(condition));
+                    let merge = self
+                        .ipdom
+                        .get(&current)
+                        .copied()
+                        .flatten()
+                        .or_else(|| join_point(unit, *true_block, *false_block));
+                    let arm_stop = merge.or(stop);
+
+                    writeln!(self.out, "{}if ({}) {{", indent(depth), cond).map_err(fmt_err)?;
+                    let true_falls = self.emit_region(*true_block, arm_stop, depth + 1)?;
+
+                    // The arms never meet again and the `then` arm cannot fall
+                    // out of the `if`: the `else` arm is simply what follows.
+                    if merge.is_none() && !true_falls {
+                        writeln!(self.out, "{}}}", indent(depth)).map_err(fmt_err)?;
+                        current = *false_block;
+                        continue;
+                    }
+
+                    let false_falls = if self.skips_to(*false_block, arm_stop) {
+                        true
+                    } else {
+                        writeln!(self.out, "{}}} else {{", indent(depth)).map_err(fmt_err)?;
+                        self.emit_region(*false_block, arm_stop, depth + 1)?
+                    };
+                    writeln!(self.out, "{}}}", indent(depth)).map_err(fmt_err)?;
+
+                    match merge {
+                        Some(merge) if true_falls || false_falls => current = merge,
+                        _ => return Ok(true_falls || false_falls),
+                    }
+                }
+            }
+        }
+    }
+
+    /// Emits the loop headed by `header` as `while (1)` with the header's
+    /// test as a `break`, and returns the block the loop exits to. `None`
+    /// means the header is not a two-way test with one side inside the loop,
+    /// and it is left to the generic handling (and a `goto`).
+    fn emit_loop(&mut self, header: BlockId, depth: usize) -> Result<Option<BlockId>, String> {
+        let unit = self.unit;
+        let Some(Terminator::Branch { condition, true_block, false_block }) =
+            unit.block(header).map(|b| &b.terminator)
+        else {
+            return Ok(None);
+        };
+        let Some(blocks) = self.loops.get(&header) else {
+            return Ok(None);
+        };
+        let (body, exit, negate) = match (blocks.contains(true_block), blocks.contains(false_block)) {
+            (true, false) => (*true_block, *false_block, true),
+            (false, true) => (*false_block, *true_block, false),
+            _ => return Ok(None),
+        };
+
+        writeln!(self.out, "{}while (1) {{", indent(depth)).map_err(fmt_err)?;
+        let inlined_cond = self.emit_instructions(header, depth + 1)?;
+        let cond = inlined_cond.unwrap_or_else(|| format_operand(condition));
+        if negate {
+            writeln!(self.out, "{}if (!({})) break;", indent(depth + 1), cond).map_err(fmt_err)?;
+        } else {
+            writeln!(self.out, "{}if ({}) break;", indent(depth + 1), cond).map_err(fmt_err)?;
+        }
+        self.emit_region(body, Some(header), depth + 1)?;
+        writeln!(self.out, "{}}}", indent(depth)).map_err(fmt_err)?;
+        Ok(Some(exit))
+    }
+
+    /// Emits the instructions of `id`. A null check feeding the block's
+    /// branch, `p` or `!p`, is not emitted but returned, to be folded into
+    /// the `if` so the verifier sees the test on the pointer itself.
+    fn emit_instructions(&mut self, id: BlockId, depth: usize) -> Result<Option<String>, String> {
+        let unit = self.unit;
+        let block = unit.block(id).ok_or_else(|| format!("Unknown block: {}", id.0))?;
+        let mut inlined_cond = None;
+        for inst in &block.instructions {
+            if let Terminator::Branch { condition: Operand::Var(v), .. } = &block.terminator {
+                if *v == inst.result {
+                    if let Some(cond) = self.null_test(inst) {
+                        inlined_cond = Some(cond);
+                        continue;
+                    }
+                }
+            }
+            self.emit_instruction(inst, depth)?;
+        }
+        Ok(inlined_cond)
+    }
+
+    /// `inst` written as a test on a map pointer, if it is one.
+    fn null_test(&self, inst: &Instruction) -> Option<String> {
+        let ptr = match inst.operands.first()? {
+            Operand::Var(ptr) if self.unit.var(*ptr).is_ptr => *ptr,
+            _ => return None,
+        };
+        match inst.opcode {
+            Opcode::NullCheck => Some(local(ptr)),
+            Opcode::Unary { op: UnaryOp::Not } => Some(format!("!{}", local(ptr))),
+            _ => None,
+        }
+    }
+
+    /// Whether `id` is `target` or an empty block that only jumps there, in
+    /// which case a branch arm leading to it needs no code.
+    fn skips_to(&self, id: BlockId, target: Option<BlockId>) -> bool {
+        if Some(id) == target {
+            return true;
+        }
+        match self.unit.block(id) {
+            Some(block) => {
+                block.instructions.is_empty()
+                    && matches!(block.terminator, Terminator::Jump(t) if Some(t) == target)
+            }
+            None => false,
+        }
+    }
+
+    fn emit_instruction(&mut self, inst: &Instruction, depth: usize) -> Result<(), String> {
+        let pad = indent(depth);
+        let res = local(inst.result);
+
+        match &inst.opcode {
+            Opcode::Copy => {
+                let value = format_operand(operand(inst, 0)?);
+                writeln!(self.out, "{}{} = {};", pad, res, value).map_err(fmt_err)?;
+            }
+
+            Opcode::Binary { op } => {
+                let left = format_operand(operand(inst, 0)?);
+                let right = format_operand(operand(inst, 1)?);
+                let op_str = match op {
+                    BinaryOp::Add => "+",
+                    BinaryOp::Sub => "-",
+                    BinaryOp::Mul => "*",
+                    BinaryOp::Div => "/",
+                    BinaryOp::Mod => "%",
+                    BinaryOp::Eq => "==",
+                    BinaryOp::Ne => "!=",
+                    BinaryOp::Lt => "<",
+                    BinaryOp::Le => "<=",
+                    BinaryOp::Gt => ">",
+                    BinaryOp::Ge => ">=",
+                    BinaryOp::And => "&&",
+                    BinaryOp::Or => "||",
+                    BinaryOp::BitAnd => "&",
+                    BinaryOp::BitOr => "|",
+                    BinaryOp::BitXor => "^",
+                    BinaryOp::Shl => "<<",
+                    BinaryOp::Shr => ">>",
+                };
+                writeln!(self.out, "{}{} = {} {} {};", pad, res, left, op_str, right).map_err(fmt_err)?;
+            }
+
+            Opcode::Unary { op } => {
+                let value = format_operand(operand(inst, 0)?);
+                let op_str = match op {
+                    UnaryOp::Not => "!",
+                    UnaryOp::BitNot => "~",
+                };
+                writeln!(self.out, "{}{} = {}{};", pad, res, op_str, value).map_err(fmt_err)?;
+            }
+
+            Opcode::Trunc | Opcode::ZeroExtend | Opcode::SignExtend => {
+                // C conversions already truncate or extend according to the
+                // source type's signedness; the cast makes it explicit.
+                let value = format_operand(operand(inst, 0)?);
+                writeln!(self.out, "{}{} = ({}){};", pad, res, data_type_to_c(&inst.result_type), value)
+                    .map_err(fmt_err)?;
+            }
+
+            Opcode::LoadKey => {
+                let ptr = format_operand(operand(inst, 0)?);
+                writeln!(self.out, "{}{} = *{};", pad, res, ptr).map_err(fmt_err)?;
+            }
+
+            Opcode::Store { .. } => {
+                let ptr = format_operand(operand(inst, 0)?);
+                let val = format_operand(operand(inst, 1)?);
+                writeln!(self.out, "{}*{} = {};", pad, ptr, val).map_err(fmt_err)?;
+            }
+
+            Opcode::LoadField { field } => {
+                let ptr = format_operand(operand(inst, 0)?);
+                if inst.result_type.scalar().is_some() {
+                    writeln!(self.out, "{}{} = {}->{};", pad, res, ptr, field).map_err(fmt_err)?;
+                } else {
+                    writeln!(self.out, "{}__builtin_memcpy({}, {}->{}, sizeof({}));", pad, res, ptr, field, res)
+                        .map_err(fmt_err)?;
+                }
+            }
+
+            Opcode::StoreField { field } => {
+                let ptr = format_operand(operand(inst, 0)?);
+                let val = format_operand(operand(inst, 1)?);
+                writeln!(self.out, "{}{}->{} = {};", pad, ptr, field, val).map_err(fmt_err)?;
+            }
+
+            Opcode::SetBytes { field, bytes } => {
+                let literal: String = bytes.iter().map(|b| format!("\\x{:02x}", b)).collect();
+                writeln!(
+                    self.out,
+                    "{}__builtin_memcpy(&{}.{}, \"{}\", {});",
+                    pad, res, field, literal, bytes.len()
+                )
+                .map_err(fmt_err)?;
+            }
+
+            Opcode::SetField { field } => {
+                let value = operand(inst, 0)?;
+                let val = format_operand(value);
+                let is_array = matches!(value, Operand::Var(v) if self.unit.var(*v).ty.scalar().is_none());
+                if is_array {
+                    writeln!(
+                        self.out,
+                        "{}__builtin_memcpy({}.{}, {}, sizeof({}.{}));",
+                        pad, res, field, val, res, field
+                    )
+                    .map_err(fmt_err)?;
+                } else {
+                    writeln!(self.out, "{}{}.{} = {};", pad, res, field, val).map_err(fmt_err)?;
+                }
+            }
+
+            Opcode::LoadIndex { field } => {
+                let element = format_element(inst, field.as_deref())?;
+                writeln!(self.out, "{}{} = {};", pad, res, element).map_err(fmt_err)?;
+            }
+
+            Opcode::StoreIndex { field } => {
+                let element = format_element(inst, field.as_deref())?;
+                let val = format_operand(operand(inst, 2)?);
+                writeln!(self.out, "{}{} = {};", pad, element, val).map_err(fmt_err)?;
+            }
+
+            Opcode::Atomic { op, field, indexed } => {
+                let ptr = format_operand(operand(inst, 0)?);
+                let (place, first_value) = match (field, indexed) {
+                    (None, false) => (ptr, 1),
+                    (Some(field), false) => (format!("&{}->{}", ptr, field), 1),
+                    (None, true) => (format!("&{}[{}]", ptr, format_operand(operand(inst, 1)?)), 2),
+                    (Some(field), true) => {
+                        (format!("&{}->{}[{}]", ptr, field, format_operand(operand(inst, 1)?)), 2)
+                    }
+                };
+                let values: Vec<String> = inst.operands[first_value..].iter().map(format_operand).collect();
+                // The fetch-and-op forms are emitted as statements: with the
+                // result unused they compile to plain atomic adds and friends.
+                let (builtin, fetch) = match op {
+                    AtomicOp::Add => ("__sync_fetch_and_add", false),
+                    AtomicOp::Sub => ("__sync_fetch_and_sub", false),
+                    AtomicOp::And => ("__sync_fetch_and_and", false),
+                    AtomicOp::Or => ("__sync_fetch_and_or", false),
+                    AtomicOp::Xor => ("__sync_fetch_and_xor", false),
+                    AtomicOp::Xchg => ("__sync_lock_test_and_set", true),
+                    AtomicOp::Cmpxchg => ("__sync_val_compare_and_swap", true),
+                };
+                let call = format!("{}({}, {})", builtin, place, values.join(", "));
+                if fetch {
+                    writeln!(self.out, "{}{} = {};", pad, res, call).map_err(fmt_err)?;
+                } else {
+                    writeln!(self.out, "{}{};", pad, call).map_err(fmt_err)?;
+                }
+            }
+
+            Opcode::Zero => {
+                writeln!(self.out, "{}__builtin_memset(&{}, 0, sizeof({}));", pad, res, res).map_err(fmt_err)?;
+            }
+
+            Opcode::CallMap { map_name, .. } => {
+                let key = format_operand(operand(inst, 0)?);
+                writeln!(self.out, "{}{} = bpf_map_lookup_elem({}, &{});", pad, res, map_ref(map_name), key)
+                    .map_err(fmt_err)?;
+            }
+
+            Opcode::LookupInner => {
+                let inner = format_operand(operand(inst, 0)?);
+                let key = format_operand(operand(inst, 1)?);
+                writeln!(
+                    self.out,
+                    "{}{} = {} ? bpf_map_lookup_elem({}, &{}) : 0;",
+                    pad, res, inner, inner, key
+                )
+                .map_err(fmt_err)?;
+            }
+
+            Opcode::LookupOrInit { map_name } => {
+                let key = format_operand(operand(inst, 0)?);
+                let init = format_operand(operand(inst, 1)?);
+                let map = map_ref(map_name);
+                let lookup = format!("bpf_map_lookup_elem({}, &{})", map, key);
+                writeln!(self.out, "{}{} = {};", pad, res, lookup).map_err(fmt_err)?;
+                writeln!(self.out, "{}if (!{}) {{", pad, res).map_err(fmt_err)?;
+                // Another CPU may insert the key first; the lookup after the
+                // failed insert then finds its value.
+                writeln!(self.out, "{}    bpf_map_update_elem({}, &{}, &{}, BPF_NOEXIST);", pad, map, key, init)
+                    .map_err(fmt_err)?;
+                writeln!(self.out, "{}    {} = {};", pad, res, lookup).map_err(fmt_err)?;
+                writeln!(self.out, "{}}}", pad).map_err(fmt_err)?;
+            }
+
+            Opcode::UpdateMap { map_name, flags } => {
+                let key = format_operand(operand(inst, 0)?);
+                let value = format_operand(operand(inst, 1)?);
+                writeln!(
+                    self.out,
+                    "{}{} = bpf_map_update_elem({}, &{}, &{}, {});",
+                    pad,
+                    res,
+                    map_ref(map_name),
+                    key,
+                    value,
+                    map_flags(*flags)
+                )
+                .map_err(fmt_err)?;
+            }
+
+            Opcode::DeleteMap { map_name } => {
+                let key = format_operand(operand(inst, 0)?);
+                writeln!(self.out, "{}{} = bpf_map_delete_elem({}, &{});", pad, res, map_ref(map_name), key)
+                    .map_err(fmt_err)?;
+            }
+
+            Opcode::PushMap { map_name, flags } => {
+                let value = format_operand(operand(inst, 0)?);
+                writeln!(
+                    self.out,
+                    "{}{} = bpf_map_push_elem({}, &{}, {});",
+                    pad,
+                    res,
+                    map_ref(map_name),
+                    value,
+                    map_flags(*flags)
+                )
+                .map_err(fmt_err)?;
+            }
+
+            Opcode::RingbufReserve { map_name } => {
+                writeln!(
+                    self.out,
+                    "{}{} = bpf_ringbuf_reserve({}, sizeof({}), 0);",
+                    pad,
+                    res,
+                    map_ref(map_name),
+                    data_type_to_c(&inst.result_type)
+                )
+                .map_err(fmt_err)?;
+            }
+
+            Opcode::RingbufSubmit | Opcode::RingbufDiscard => {
+                let record = format_operand(operand(inst, 0)?);
+                let helper = match inst.opcode {
+                    Opcode::RingbufSubmit => "bpf_ringbuf_submit",
+                    _ => "bpf_ringbuf_discard",
+                };
+                writeln!(self.out, "{}{}({}, 0);", pad, helper, record).map_err(fmt_err)?;
+            }
+
+            Opcode::RingbufOutput { map_name } => {
+                let value = format_operand(operand(inst, 0)?);
+                writeln!(
+                    self.out,
+                    "{}{} = bpf_ringbuf_output({}, &{}, sizeof({}), 0);",
+                    pad, res, map_ref(map_name), value, value
+                )
+                .map_err(fmt_err)?;
+            }
+
+            Opcode::PerfOutput { map_name } => {
+                let value = format_operand(operand(inst, 0)?);
+                writeln!(
+                    self.out,
+                    "{}{} = bpf_perf_event_output({}, {}, BPF_F_CURRENT_CPU, &{}, sizeof({}));",
+                    pad, res, self.cfg.ctx, map_ref(map_name), value, value
+                )
+                .map_err(fmt_err)?;
+            }
+
+            Opcode::TailCall { map_name } => {
+                let index = format_operand(operand(inst, 0)?);
+                writeln!(self.out, "{}bpf_tail_call({}, {}, {});", pad, self.cfg.ctx, map_ref(map_name), index)
+                    .map_err(fmt_err)?;
+            }
+
+            Opcode::Redirect { map_name, helper, fallback } => {
+                let key = format_operand(operand(inst, 0)?);
+                let ctx = self.cfg.ctx;
+                let map = map_ref(map_name);
+                let verdict = redirect_verdict(*helper, *fallback);
+                let call = match helper {
+                    RedirectHelper::Xdp => format!("bpf_redirect_map({}, {}, {})", map, key, verdict),
+                    RedirectHelper::SkMap => format!("bpf_sk_redirect_map({}, {}, {}, 0)", ctx, map, key),
+                    RedirectHelper::SkHash => format!("bpf_sk_redirect_hash({}, {}, &{}, 0)", ctx, map, key),
+                    RedirectHelper::MsgMap => format!("bpf_msg_redirect_map({}, {}, {}, 0)", ctx, map, key),
+                    RedirectHelper::MsgHash => format!("bpf_msg_redirect_hash({}, {}, &{}, 0)", ctx, map, key),
+                };
+                // The XDP helper returns the fallback itself when the slot is
+                // empty. The socket helpers return SK_PASS once the redirect
+                // is set up and SK_DROP otherwise, so with a SK_PASS fallback
+                // the program passes either way.
+                match helper {
+                    RedirectHelper::Xdp => writeln!(self.out, "{}{} = {};", pad, res, call),
+                    _ if verdict == "SK_DROP" => writeln!(self.out, "{}{} = {};", pad, res, call),
+                    _ => writeln!(self.out, "{}{};\n{}{} = {};", pad, call, pad, res, verdict),
+                }
+                .map_err(fmt_err)?;
+            }
+
+            Opcode::PopMap { map_name } | Opcode::PeekMap { map_name } => {
+                let buf = format_operand(operand(inst, 0)?);
+                let helper = match inst.opcode {
+                    Opcode::PopMap { .. } => "bpf_map_pop_elem",
+                    _ => "bpf_map_peek_elem",
+                };
+                writeln!(
+                    self.out,
+                    "{}{} = {}({}, &{}) == 0 ? (void *)&{} : 0;",
+                    pad, res, helper, map_ref(map_name), buf, buf
+                )
+                .map_err(fmt_err)?;
+            }
+
+            Opcode::BoundsCheck { end } => {
+                let fallback = self
+                    .unit
+                    .packet_fallback
+                    .as_deref()
+                    .ok_or_else(|| format!("{}: packet bounds check without packet access", self.unit.name))?;
+                writeln!(self.out, "{}if (__data + {} > __data_end) return {};", pad, end, fallback)
+                    .map_err(fmt_err)?;
+            }
+
+            Opcode::LoadPacket { offset, .. } => {
+                let c_type = data_type_to_c(&inst.result_type);
+                writeln!(self.out, "{}{} = *({} *)(__data + {});", pad, res, c_type, offset).map_err(fmt_err)?;
+            }
+
+            Opcode::LoadCtx { offset, .. } => {
+                let c_type = data_type_to_c(&inst.result_type);
+                writeln!(
+                    self.out,
+                    "{}{} = *({} *)((char *){} + {});",
+                    pad, res, c_type, self.cfg.ctx, offset
+                )
+                .map_err(fmt_err)?;
+            }
+
+            Opcode::NullCheck => {
+                let ptr = format_operand(operand(inst, 0)?);
+                writeln!(self.out, "{}{} = {} != 0;", pad, res, ptr).map_err(fmt_err)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn operand(inst: &Instruction, idx: usize) -> Result<&Operand, String> {
+    inst.operands
+        .get(idx)
+        .ok_or_else(|| format!("{}: missing operand {}", local(inst.result), idx))
+}
+
+/// The array element an indexing instruction addresses.
+fn format_element(inst: &Instruction, field: Option<&str>) -> Result<String, String> {
+    let base = format_operand(operand(inst, 0)?);
+    let index = format_operand(operand(inst, 1)?);
+    Ok(match field {
+        Some(field) => format!("{}->{}[{}]", base, field, index),
+        None => format!("{}[{}]", base, index),
+    })
+}
+
+/// The name of a `redirect` fallback verdict for `helper`'s program type.
+fn redirect_verdict(helper: RedirectHelper, fallback: i64) -> String {
+    let section = match helper {
+        RedirectHelper::Xdp => "xdp",
+        _ => "sk_msg",
+    };
+    SectionValidator::redirect_fallbacks(section)
+        .unwrap_or_default()
+        .iter()
+        .find(|&&name| SectionValidator::verdict_value(name) == Some(fallback))
+        .map_or_else(|| fallback.to_string(), |name| name.to_string())
+}
+
+/// The `BPF_*` name of update and push flags.
+fn map_flags(flags: i64) -> String {
+    match flags {
+        0 => "BPF_ANY".to_string(),
+        1 => "BPF_NOEXIST".to_string(),
+        2 => "BPF_EXIST".to_string(),
+        _ => flags.to_string(),
+    }
+}
+
+fn format_operand(op: &Operand) -> String {
+    match op {
+        Operand::Var(var) => local(*var),
+        // 9223372036854775808 is not a valid signed literal, so the most
+        // negative value (the mask `1 << 63`) is spelled as a difference.
+        Operand::Immediate(i64::MIN) => "(-9223372036854775807LL - 1)".to_string(),
+        Operand::Immediate(val) => val.to_string(),
+    }
+}
+
+/// C name of a slot. Generated names start with `__`, which source names
+/// cannot, so a map or unit named like a slot does not collide with it.
+fn local(var: VarId) -> String {
+    format!("__v{}", var.0)
+}
+
+/// Address of a map, under the name its declaration was emitted with.
+fn map_ref(name: &str) -> String {
+    format!("&{}", sanitize_ident(name))
+}
+
+fn indent(depth: usize) -> String {
+    "    ".repeat(depth)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::testing::{assert_error, compile_to_c, function};
+
+    #[test]
+    fn every_program_type_renders_its_own_body() {
+        for section in ["xdp", "tc", "kprobe/do_sys_open", "sk_skb/stream_verdict", "lsm/file_open"] {
+            let c = compile_to_c(&format!(
+                "unit prog {{\n    section: \"{}\";\n    license: \"GPL\";\n\n    reg x = 40;\n    x += 2;\n    return x;\n}}\n",
+                section
+            ));
+            let body = function(&c, "prog");
+            assert!(body.contains("__v0 = 40;"), "{}:\n{}", section, body);
+            assert!(body.contains("return __v0;"), "{}:\n{}", section, body);
+        }
+    }
+
+    #[test]
+    fn source_names_do_not_collide_with_generated_ones() {
+        let c = compile_to_c(
+            r#"
+map v3 {
+    type: .array;
+    key: u32;
+    value: u64;
+    max: 4;
+}
+
+map data {
+    type: .hash;
+    key: u32;
+    value: u64;
+    max: 16;
+}
+
+map ctx {
+    type: .devmap;
+    max: 4;
+}
+
+unit data_end {
+    section: "xdp";
+    license: "GPL";
+
+    reg port: u32 = ctx.load_u8(14);
+    heap hits = v3.lookup(port);
+    if guard(hits) {
+        *hits += 1;
+    }
+    heap seen = data.lookup(port);
+    if guard(seen) {
+        *seen += 1;
+    }
+    return redirect(ctx, port, 2);
+}
+"#,
+        );
+        let body = function(&c, "data_end");
+        assert!(body.contains("int data_end(struct xdp_md *__ctx)"), "{}", body);
+        assert!(body.contains("void *__data_end = (void *)(long)__ctx->data_end;"), "{}", body);
+        assert!(body.contains("bpf_map_lookup_elem(&v3, &__v1)"), "{}", body);
+        assert!(body.contains("bpf_map_lookup_elem(&data, &__v1)"), "{}", body);
+        assert!(body.contains("bpf_redirect_map(&ctx, __v1, XDP_PASS)"), "{}", body);
+        assert!(!body.contains(" v3 ") && !body.contains(" data "), "{}", body);
+    }
+
+    #[test]
+    fn generated_names_are_reserved() {
+        let src = r#"
+map __v0 {
+    type: .array;
+    key: u32;
+    value: u64;
+    max: 4;
+}
+
+unit counter {
+    section: "xdp";
+    license: "GPL";
+    return 2;
+}
+"#;
+        assert_error(src, "Map name '__v0' is reserved");
+
+        let src = r#"
+map counter {
+    type: .array;
+    key: u32;
+    value: u64;
+    max: 4;
+}
+
+unit counter {
+    section: "xdp";
+    license: "GPL";
+    return 2;
+}
+"#;
+        assert_error(src, "Unit 'counter' has the same name as a map");
+    }
+
+    #[test]
+    fn logical_operators_short_circuit() {
+        let c = compile_to_c(
+            "map results {\n    type: .array;\n    key: u32;\n    value: u64;\n    max: 4;\n}\n\n\
+             unit prog {\n    section: \"xdp\";\n    license: \"GPL\";\n\n    reg a = ctx.load_u8(0);\n    \
+             heap p = results.lookup(a);\n    if guard(p && *p > 0) {\n        *p = 1;\n    }\n    \
+             if guard(a == 1 || a == 2) {\n        return 1;\n    }\n    return 2;\n}\n",
+        );
+        let body = function(&c, "prog");
+        // The C name of a binding, from the comment on its declaration.
+        let slot = |name: &str| {
+            let decl = body.lines().find(|l| l.ends_with(&format!("/* {} */", name))).expect("binding is declared");
+            decl.split(" = ").next().unwrap().rsplit(['*', ' ']).next().unwrap().to_string()
+        };
+
+        let (p, a) = (slot("p"), slot("a"));
+        let test = body.find(&format!("if ({}) {{", p)).expect("the pointer is tested first");
+        let load = body.find(&format!("= *{};", p)).expect("the value is loaded");
+        assert!(test < load, "{}", body);
+
+        let left = body.find(&format!("= {} == 1;", a)).expect("left operand");
+        let right = body.find(&format!("= {} == 2;", a)).expect("right operand");
+        assert!(body[left..right].contains("} else {"), "{}", body);
+    }
+
+    #[test]
+    fn branches_render_as_nested_ifs() {
+        let c = compile_to_c(
+            r#"
+unit classify {
+    section: "kprobe/do_sys_open";
+    license: "GPL";
+
+    reg y = ctx.load_u8(0);
+    if guard(y == 1) {
+        if guard(y == 2) {
+            return 1;
+        }
+        y = 3;
+    } else if guard(y == 4) {
+        y = 5;
+    } else {
+        return 6;
+    }
+    return y;
+}
+"#,
+        );
+        let body = function(&c, "classify");
+        assert!(!body.contains("goto"), "{}", body);
+        assert_eq!(body.matches("} else {").count(), 2, "{}", body);
+        assert!(body.contains("            return 1;\n        }\n        __v1 = 3;"), "{}", body);
+        assert!(body.trim_end().ends_with("return __v1;\n}"), "{}", body);
+    }
+
+    #[test]
+    fn casts_truncate_and_extend() {
+        let c = compile_to_c(
+            r#"
+unit casts {
+    section: "kprobe/do_sys_open";
+    license: "GPL";
+
+    reg ip: u64 = ctx.load_u32(26);
+    reg wide: i64 = ctx.load_i8(3);
+    reg port = ip as u16;
+    reg small = 300 as u8;
+    return small + (wide as u8) + (port as u8);
+}
+"#,
+        );
+        let body = function(&c, "casts");
+        assert!(body.contains("__v1 = (__u64)__v0;"), "{}", body);
+        assert!(body.contains("__v3 = (__s64)__v2;"), "{}", body);
+        assert!(body.contains("__v4 = (__u16)__v1;"), "{}", body);
+        // constants are folded, wrapping as the cast would
+        assert!(body.contains(" = 44;"), "{}", body);
+    }
+
+    #[test]
+    fn null_tests_fold_into_the_branch() {
+        let c = compile_to_c(
+            r#"
+map results {
+    type: .array;
+    key: u32;
+    value: u64;
+    max: 8;
+}
+
+unit early {
+    section: "xdp";
+    license: "GPL";
+
+    heap p = results.lookup(0);
+    if guard(!p) {
+        return 1;
+    }
+    heap q = results.lookup(1);
+    if guard(q) {
+        *q += *p;
+    }
+    return 2;
+}
+"#,
+        );
+        let body = function(&c, "early");
+        assert!(body.contains("if (!__v1) {"), "{}", body);
+        assert!(body.contains("if (__v4) {"), "{}", body);
+        assert!(!body.contains("= !__v1;"), "{}", body);
+    }
+
+    #[test]
+    fn packet_pointers_are_read_only_when_used() {
+        let src = "unit u {\n    section: \"xdp\";\n    license: \"GPL\";\n    return 2;\n}\n";
+        let c = compile_to_c(src);
+        assert!(!function(&c, "u").contains("__data"), "{}", c);
+
+        let src = "unit u {\n    section: \"tc\";\n    license: \"GPL\";\n    return ctx.load_u8(23);\n}\n";
+        let c = compile_to_c(src);
+        let body = function(&c, "u");
+        assert!(body.contains("void *__data = (void *)(long)__ctx->data;"), "{}", body);
+        assert!(body.contains("if (__data + 24 > __data_end) return TC_ACT_OK;"), "{}", body);
+    }
+
+    #[test]
+    fn loop_inside_an_arm_stays_structured() {
+        let c = compile_to_c(
+            r#"
+unit t {
+    section: "tracepoint/syscalls/sys_enter_execve";
+    license: "GPL";
+
+    reg c = ctx.load_u8(0);
+    reg x = 0;
+    if guard(c == 1) {
+        for i in 0..4 {
+            if guard(x > 10) {
+                return 2;
+            }
+            x += i;
+        }
+    }
+    x = 1;
+    return x;
+}
+"#,
+        );
+        let body = function(&c, "t");
+        assert!(!body.contains("goto"), "{}", body);
+        assert!(body.contains("    if (__v3) {\n        __v4 = 0;\n        while (1) {"), "{}", body);
+        assert_eq!(body.matches("x */").count(), 1, "{}", body);
+    }
+
+    #[test]
+    fn arrays_are_zeroed_and_indexed_in_place() {
+        let c = compile_to_c(
+            r#"
+struct proc {
+    pid: u32,
+    comm: u8[16],
+}
+
+map procs {
+    type: .hash;
+    key: u32;
+    value: proc;
+    max: 16;
+}
+
+unit arrays {
+    section: "kprobe/do_sys_open";
+    license: "GPL";
+
+    reg name: u8[4];
+    name[1] = 7;
+    heap p = procs.lookup(7);
+    if guard(p) {
+        p.comm[0] += name[1];
+    }
+    return 0;
+}
+"#,
+        );
+        let body = function(&c, "arrays");
+        assert!(body.contains("__u8 __v0[4] = {}; /* name */"), "{}", body);
+        assert!(body.contains("__builtin_memset(&__v0, 0, sizeof(__v0));"), "{}", body);
+        assert!(body.contains("__v0[1] = 7;"), "{}", body);
+        assert!(body.contains("->comm[0] = "), "{}", body);
+    }
+
+    #[test]
+    fn top_bit_mask_is_a_valid_c_literal() {
+        let src = "unit u {\n    section: \"kprobe/do_sys_open\";\n    license: \"GPL\";\n    reg m: u64 = 1 << 63;\n    reg x = ctx.load_u64(0) & m;\n    return x >> 63;\n}\n";
+        let c = compile_to_c(src);
+        let body = function(&c, "u");
+        assert!(body.contains("__v0 = (-9223372036854775807LL - 1);"), "{}", body);
+        assert!(!body.contains("-9223372036854775808"), "{}", body);
+    }
+
+    #[test]
+    fn compound_assignments_read_modify_write() {
+        let c = compile_to_c(
+            r#"
+struct stats { packets: u64, bytes: u64 }
+
+map per_flow {
+    type: .hash;
+    key: u32;
+    value: stats;
+    max: 16;
+}
+
+unit compound {
+    section: "kprobe/do_sys_open";
+    license: "GPL";
+
+    reg x: u32 = ctx.load_u32(0);
+    x %= 100;
+    heap s = per_flow.lookup(x);
+    if guard(s) {
+        s.bytes -= 10;
+    }
+    return 0;
+}
+"#,
+        );
+        let body = function(&c, "compound");
+        assert!(body.contains("__v1 = __v1 % 100;"), "{}", body);
+        assert!(body.contains("__v4 = __v2->bytes;\n        __v5 = __v4 - 10;\n        __v2->bytes = __v5;"), "{}", body);
+    }
+
+    #[test]
+    fn atomics_use_the_sync_builtins() {
+        let c = compile_to_c(
+            r#"
+map counters {
+    type: .hash;
+    key: u32;
+    value: u64;
+    max: 16;
+}
+
+unit atomics {
+    section: "kprobe/do_sys_open";
+    license: "GPL";
+
+    heap p = counters.lookup(0);
+    if guard(p) {
+        atomic *p += 1;
+        atomic *p &= ~1;
+        reg old: u64 = atomic xchg(*p, 0);
+        reg prev = atomic cmpxchg(*p, old, 5);
+    }
+    return 0;
+}
+"#,
+        );
+        let body = function(&c, "atomics");
+        assert!(body.contains("__sync_fetch_and_add(__v1, 1);"), "{}", body);
+        assert!(body.contains("__sync_fetch_and_and(__v1, -2);"), "{}", body);
+        assert!(body.contains(" = __sync_lock_test_and_set(__v1, 0);"), "{}", body);
+        assert!(body.contains(" = __sync_val_compare_and_swap(__v1, "), "{}", body);
+    }
+
+    #[test]
+    fn map_flags_keep_their_names() {
+        let c = compile_to_c(
+            r#"
+map counters {
+    type: .hash;
+    key: u32;
+    value: u64;
+    max: 16;
+}
+
+unit flags {
+    section: "kprobe/do_sys_open";
+    license: "GPL";
+
+    imm MODE = 2;
+    counters.update(1, 2, BPF_NOEXIST);
+    counters.update(1, 2, MODE);
+    return 0;
+}
+"#,
+        );
+        let body = function(&c, "flags");
+        assert!(body.contains(", BPF_NOEXIST);"), "{}", body);
+        assert!(body.contains(", BPF_EXIST);"), "{}", body);
+    }
+
+    #[test]
+    fn ring_buffer_records_use_the_ringbuf_helpers() {
+        let c = compile_to_c(
+            r#"
+struct event {
+    pid: u32,
+    len: u16
+}
+
+map events {
+    type: .ringbuf;
+    max: 4096;
+}
+
+unit rb {
+    section: "kprobe/do_sys_open";
+    license: "GPL";
+
+    heap e = events.reserve(event);
+    if guard(e) {
+        e.len = 64;
+        events.submit(e);
+    }
+    events.output(7);
+    return 0;
+}
+"#,
+        );
+        let body = function(&c, "rb");
+        assert!(body.contains(" = bpf_ringbuf_reserve(&events, sizeof(struct event), 0);"), "{}", body);
+        assert!(body.contains("bpf_ringbuf_submit(__v"), "{}", body);
+        assert!(body.contains(" = bpf_ringbuf_output(&events, &"), "{}", body);
+    }
+
+    #[test]
+    fn perf_events_go_to_the_current_cpu() {
+        let c = compile_to_c(
+            r#"
+struct sample {
+    src: u32,
+    len: u16
+}
+
+map perf {
+    type: .perf_event_array;
+}
+
+unit pe {
+    section: "xdp";
+    license: "GPL";
+
+    perf.emit(ctx, sample { src: 1, len: 64 });
+    return 2;
+}
+"#,
+        );
+        let body = function(&c, "pe");
+        assert!(body.contains("__v1 = bpf_perf_event_output(__ctx, &perf, BPF_F_CURRENT_CPU, &__v0, sizeof(__v0));"), "{}", body);
+        assert!(c.contains("__uint(type, BPF_MAP_TYPE_PERF_EVENT_ARRAY);"), "{}", c);
+    }
+
+    #[test]
+    fn tail_calls_pass_the_context() {
+        let c = compile_to_c(
+            r#"
+map jumps {
+    type: .prog_array;
+    max: 4;
+}
+
+unit entry {
+    section: "xdp";
+    license: "GPL";
+
+    reg proto = ctx.load_u8(0);
+    tail_call(jumps, proto & 3);
+    return 2;
+}
+"#,
+        );
+        let body = function(&c, "entry");
+        assert!(body.contains("bpf_tail_call(__ctx, &jumps, __v"), "{}", body);
+    }
+
+    #[test]
+    fn cidr_keys_are_built_in_network_order() {
+        let c = compile_to_c(
+            r#"
+struct prefix_v6 {
+    prefixlen: u32,
+    addr: u8[16]
+}
+
+map deny6 {
+    type: .lpm_trie;
+    key: prefix_v6;
+    value: u32;
+    max: 16;
+}
+
+unit filter {
+    section: "xdp";
+    license: "GPL";
+
+    deny6.delete(2001:db8::/32);
+    return 2;
+}
+"#,
+        );
+        let body = function(&c, "filter");
+        let key = "    __v0.prefixlen = 32;\n    __builtin_memcpy(&__v0.addr, \"\\x20\\x01\\x0d\\xb8";
+        assert!(body.contains(key), "{}", body);
+        assert!(body.contains("bpf_map_delete_elem(&deny6, &__v0);"), "{}", body);
+        assert!(c.contains("__uint(map_flags, BPF_F_NO_PREALLOC);"), "{}", c);
+    }
+
+    #[test]
+    fn inner_lookup_checks_the_outer_map_first() {
+        let c = compile_to_c(
+            r#"
+map counts {
+    type: .hash;
+    key: u32;
+    value: u64;
+    max: 16;
+}
+
+map tenants {
+    type: .hash_of_maps;
+    key: u32;
+    max: 8;
+    inner: counts;
+}
+
+unit policy {
+    section: "kprobe/do_sys_open";
+    license: "GPL";
+
+    heap hits = tenants.lookup(1).lookup(2);
+    if guard(hits) {
+        *hits += 1;
+    }
+    return 0;
+}
+"#,
+        );
+        let body = function(&c, "policy");
+        assert!(body.contains(" = bpf_map_lookup_elem(&tenants, &__v0);"), "{}", body);
+        assert!(body.contains("__v3 = __v1 ? bpf_map_lookup_elem(__v1, &__v2) : 0;"), "{}", body);
+    }
+
+    #[test]
+    fn socket_redirects_follow_the_helper_verdict() {
+        let c = compile_to_c(
+            r#"
+map socks {
+    type: .sockmap;
+    max: 8;
+}
+
+map conns {
+    type: .sockhash;
+    key: u64;
+    max: 8;
+}
+
+unit skb {
+    section: "sk_skb/stream_verdict";
+    license: "GPL";
+
+    reg k = ctx.load_u32(0);
+    if guard(k == 0) {
+        return redirect(socks, k, SK_DROP);
+    }
+    return redirect(conns, k, SK_PASS);
+}
+
+unit msg {
+    section: "sk_msg";
+    license: "GPL";
+
+    reg k: u64 = 7;
+    if guard(k == 7) {
+        return redirect(conns, k, 0);
+    }
+    return redirect(socks, 3, SK_PASS);
+}
+"#,
+        );
+        let skb = function(&c, "skb");
+        assert!(skb.contains("        __v3 = bpf_sk_redirect_map(__skb, &socks, __v1, 0);\n        return __v3;"), "{}", skb);
+        assert!(skb.contains("    bpf_sk_redirect_hash(__skb, &conns, &__v4, 0);\n    __v5 = SK_PASS;\n"), "{}", skb);
+
+        let msg = function(&c, "msg");
+        assert!(msg.contains(" = bpf_msg_redirect_hash(__msg, &conns, &__v0, 0);\n"), "{}", msg);
+        assert!(msg.contains("    bpf_msg_redirect_map(__msg, &socks, 3, 0);\n    __v"), "{}", msg);
+        assert!(msg.contains(" = SK_PASS;\n"), "{}", msg);
+    }
+
+    #[test]
+    fn xdp_redirect_passes_the_fallback_by_name() {
+        let c = compile_to_c(
+            r#"
+map ports {
+    type: .devmap;
+    max: 8;
+}
+
+unit lb {
+    section: "xdp";
+    license: "GPL";
+
+    reg p = ctx.load_u8(14);
+    if guard(p == 1) {
+        return redirect(ports, p, XDP_PASS);
+    }
+    return redirect(ports, 0, 1);
+}
+"#,
+        );
+        let body = function(&c, "lb");
+        assert!(body.contains(" = bpf_redirect_map(&ports, __v"), "{}", body);
+        assert!(body.contains(", XDP_PASS);"), "{}", body);
+        assert!(body.contains(" = bpf_redirect_map(&ports, 0, XDP_DROP);"), "{}", body);
+    }
+}
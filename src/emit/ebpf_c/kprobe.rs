@@ -1,25 +1,16 @@
 use std::fmt::Write;
+use crate::emit::ebpf_c::body::{emit_body, BodyConfig};
+use crate::emit::util::fmt_err;
 use crate::ir::UnitIr;
 
 pub fn emit_kprobe(out: &mut String, unit: &UnitIr, sec: &str) -> Result<(), String> {
-    emit_license(out, &unit.license)?;
         
-    writeln!(out, "SEC(\"{}\")", sec).map_err(err)?;
-    writeln!(out, "int {}(struct pt_regs *ctx) {{", unit.name).map_err(err)?;
-    writeln!(out, "    (void)ctx;").map_err(err)?;
-    writeln!(out, "    return 0;").map_err(err)?;
-    writeln!(out, "}}").map_err(err)?;
-    writeln!(out).map_err(err)?;
+    writeln!(out, "SEC(\"{}\")", sec).map_err(fmt_err)?;
+    writeln!(out, "int {}(struct pt_regs *__ctx) {{", unit.name).map_err(fmt_err)?;
+    writeln!(out, "    (void)__ctx;").map_err(fmt_err)?;
+    emit_body(out, unit, &BodyConfig { ctx: "__ctx" })?;
+    writeln!(out, "}}").map_err(fmt_err)?;
+    writeln!(out).map_err(fmt_err)?;
 
     Ok(())
 }
-
-fn emit_license(out: &mut String, lic: &str) -> Result<(), String> {
-    writeln!(out, "char LICENSE[] SEC(\"license\") = \"{}\";", lic).map_err(err)?;
-    writeln!(out).map_err(err)?;
-    Ok(())
-}
-
-fn err(e: std::fmt::Error) -> String {
-    e.to_string()
-}
@@ -1,33 +1,137 @@
+use std::collections::HashSet;
 use std::fmt::Write;
 
-use crate::ast::{MapDecl, MapType, Type};
+use crate::ast::structs::field_layout;
+use crate::ast::{DataType, MapDecl, MapFlag, MapType, Pinning, StructDecl, Type};
 use crate::emit::util::fmt_err;
 
+/// Emits the declared structs with every padding byte spelled out, so the
+/// layout is the same whichever compiler reads the definition and a zeroed
+/// key has no bytes the program never writes.
+pub fn emit_structs(out: &mut String, structs: &[StructDecl]) -> Result<(), String> {
+    for s in structs {
+        let (offsets, size) = s.layout();
+
+        writeln!(out, "struct {} {{", s.name).map_err(fmt_err)?;
+        let mut end = 0;
+        for (field, offset) in s.fields.iter().zip(offsets) {
+            if offset > end {
+                writeln!(out, "    __u8 __pad{}[{}];", end, offset - end).map_err(fmt_err)?;
+            }
+            writeln!(out, "    {};", declarator(&field.ty, &field.name)).map_err(fmt_err)?;
+            end = offset + field_layout(&field.ty).0;
+        }
+        if size > end {
+            writeln!(out, "    __u8 __pad{}[{}];", end, size - end).map_err(fmt_err)?;
+        }
+        writeln!(out, "}};").map_err(fmt_err)?;
+        writeln!(out).map_err(fmt_err)?;
+    }
+
+    Ok(())
+}
+
 pub fn emit_maps(out: &mut String, maps: &[MapDecl]) -> Result<(), String> {
+    // An inner map prototype gets a named struct type, which the map of
+    // maps refers to in its `__array(values, ...)`.
+    let prototypes: HashSet<&str> = maps.iter().filter_map(|m| m.inner.as_deref()).collect();
+    for m in maps.iter().filter(|m| prototypes.contains(m.name.as_str())) {
+        writeln!(out, "struct {} {{", map_struct_name(&m.name)).map_err(fmt_err)?;
+        emit_map_fields(out, m)?;
+        writeln!(out, "}};").map_err(fmt_err)?;
+        writeln!(out).map_err(fmt_err)?;
+    }
+
     for m in maps {
-        let bpf_map_type = map_type_to_c(m.map_type);
-        let key_ty = type_to_c(m.key_type);
-        let val_ty = type_to_c(m.value_type);
-        
         let name = sanitize_ident(&m.name);
 
+        if prototypes.contains(m.name.as_str()) {
+            writeln!(out, "struct {} {} SEC(\".maps\");", map_struct_name(&m.name), name).map_err(fmt_err)?;
+            writeln!(out).map_err(fmt_err)?;
+            continue;
+        }
+
         writeln!(out, "struct {{").map_err(fmt_err)?;
-        writeln!(out, "    __uint(type, {});", bpf_map_type).map_err(fmt_err)?;
-        writeln!(out, "    __uint(max_entries, {});", m.max_entries).map_err(fmt_err)?;
-        
-        if m.map_type != MapType::Ringbuf {
-            writeln!(out, "    __type(key, {});", key_ty).map_err(fmt_err)?;
-            writeln!(out, "    __type(value, {});", val_ty).map_err(fmt_err)?;
-        } else {
+        emit_map_fields(out, m)?;
+        if m.programs.is_empty() {
+            writeln!(out, "}} {} SEC(\".maps\");", name).map_err(fmt_err)?;
+            writeln!(out).map_err(fmt_err)?;
+            continue;
         }
 
-        writeln!(out, "}} {} SEC(\".maps\");", name).map_err(fmt_err)?;
+        // libbpf fills the slots from the initializer at load time.
+        writeln!(out, "}} {} SEC(\".maps\") = {{", name).map_err(fmt_err)?;
+        writeln!(out, "    .values = {{").map_err(fmt_err)?;
+        for slot in &m.programs {
+            writeln!(out, "        [{}] = (void *)&{},", slot.index, slot.unit).map_err(fmt_err)?;
+        }
+        writeln!(out, "    }},").map_err(fmt_err)?;
+        writeln!(out, "}};").map_err(fmt_err)?;
         writeln!(out).map_err(fmt_err)?;
     }
 
     Ok(())
 }
 
+/// The body of a map definition, from its type to its key and values.
+fn emit_map_fields(out: &mut String, m: &MapDecl) -> Result<(), String> {
+    writeln!(out, "    __uint(type, {});", map_type_to_c(m.map_type)).map_err(fmt_err)?;
+    writeln!(out, "    __uint(max_entries, {});", m.max_entries).map_err(fmt_err)?;
+    let flags = map_flags(m);
+    if !flags.is_empty() {
+        writeln!(out, "    __uint(map_flags, {});", flags.join(" | ")).map_err(fmt_err)?;
+    }
+    if let Some(node) = m.numa_node {
+        writeln!(out, "    __uint(numa_node, {});", node).map_err(fmt_err)?;
+    }
+    if m.pinning == Pinning::ByName {
+        writeln!(out, "    __uint(pinning, LIBBPF_PIN_BY_NAME);").map_err(fmt_err)?;
+    }
+
+    if let Some(key_type) = &m.key_type {
+        writeln!(out, "    __type(key, {});", data_type_to_c(key_type)).map_err(fmt_err)?;
+    }
+    if !m.programs.is_empty() {
+        writeln!(out, "    __array(values, int (void *));").map_err(fmt_err)?;
+    } else if let Some(inner) = &m.inner {
+        writeln!(out, "    __array(values, struct {});", map_struct_name(inner)).map_err(fmt_err)?;
+    } else if let Some(value_type) = &m.value_type {
+        writeln!(out, "    __type(value, {});", data_type_to_c(value_type)).map_err(fmt_err)?;
+    }
+    Ok(())
+}
+
+/// Name of the struct type declaring an inner map prototype.
+fn map_struct_name(name: &str) -> String {
+    format!("__map_{}", sanitize_ident(name))
+}
+
+/// The `BPF_F_*` flags the map is created with: those declared, plus the
+/// ones its type or attributes require.
+fn map_flags(m: &MapDecl) -> Vec<&'static str> {
+    let mut flags: Vec<&'static str> = m.flags.iter().map(|flag| map_flag_to_c(*flag)).collect();
+    // The kernel refuses to create a trie whose nodes are preallocated.
+    if m.map_type == MapType::LpmTrie && !m.flags.contains(&MapFlag::NoPrealloc) {
+        flags.push("BPF_F_NO_PREALLOC");
+    }
+    if m.numa_node.is_some() {
+        flags.push("BPF_F_NUMA_NODE");
+    }
+    flags
+}
+
+fn map_flag_to_c(flag: MapFlag) -> &'static str {
+    match flag {
+        MapFlag::NoPrealloc => "BPF_F_NO_PREALLOC",
+        MapFlag::NoCommonLru => "BPF_F_NO_COMMON_LRU",
+        MapFlag::Rdonly => "BPF_F_RDONLY",
+        MapFlag::Wronly => "BPF_F_WRONLY",
+        MapFlag::RdonlyProg => "BPF_F_RDONLY_PROG",
+        MapFlag::WronlyProg => "BPF_F_WRONLY_PROG",
+        MapFlag::Mmapable => "BPF_F_MMAPABLE",
+    }
+}
+
 fn map_type_to_c(t: MapType) -> &'static str {
     match t {
         MapType::Hash => "BPF_MAP_TYPE_HASH",
@@ -36,24 +140,56 @@ fn map_type_to_c(t: MapType) -> &'static str {
         MapType::LruHash => "BPF_MAP_TYPE_LRU_HASH",
         MapType::ProgArray => "BPF_MAP_TYPE_PROG_ARRAY",
         MapType::PerfEventArray => "BPF_MAP_TYPE_PERF_EVENT_ARRAY",
+        MapType::Queue => "BPF_MAP_TYPE_QUEUE",
+        MapType::Stack => "BPF_MAP_TYPE_STACK",
+        MapType::PercpuHash => "BPF_MAP_TYPE_PERCPU_HASH",
+        MapType::PercpuArray => "BPF_MAP_TYPE_PERCPU_ARRAY",
+        MapType::LruPercpuHash => "BPF_MAP_TYPE_LRU_PERCPU_HASH",
+        MapType::LpmTrie => "BPF_MAP_TYPE_LPM_TRIE",
+        MapType::ArrayOfMaps => "BPF_MAP_TYPE_ARRAY_OF_MAPS",
+        MapType::HashOfMaps => "BPF_MAP_TYPE_HASH_OF_MAPS",
+        MapType::Devmap => "BPF_MAP_TYPE_DEVMAP",
+        MapType::DevmapHash => "BPF_MAP_TYPE_DEVMAP_HASH",
+        MapType::Cpumap => "BPF_MAP_TYPE_CPUMAP",
+        MapType::Xskmap => "BPF_MAP_TYPE_XSKMAP",
+        MapType::Sockmap => "BPF_MAP_TYPE_SOCKMAP",
+        MapType::Sockhash => "BPF_MAP_TYPE_SOCKHASH",
+    }
+}
+
+pub fn data_type_to_c(ty: &DataType) -> String {
+    match ty {
+        DataType::Scalar(ty) => type_to_c(*ty).to_string(),
+        DataType::Struct(name) => format!("struct {}", name),
+        DataType::Array(elem, len) => format!("{}[{}]", type_to_c(*elem), len),
+    }
+}
+
+/// Declaration of `name` as a `ty`; array lengths go after the name.
+pub fn declarator(ty: &DataType, name: &str) -> String {
+    match ty {
+        DataType::Array(elem, len) => format!("{} {}[{}]", type_to_c(*elem), name, len),
+        _ => format!("{} {}", data_type_to_c(ty), name),
     }
 }
 
 fn type_to_c(t: Type) -> &'static str {
     match t {
+        Type::U8 => "__u8",
+        Type::U16 => "__u16",
         Type::U32 => "__u32",
         Type::U64 => "__u64",
+        Type::I8 => "__s8",
+        Type::I16 => "__s16",
         Type::I32 => "__s32",
         Type::I64 => "__s64",
     }
 }
 
-fn sanitize_ident(name: &str) -> String {
+pub fn sanitize_ident(name: &str) -> String {
     let mut out = String::with_capacity(name.len());
     for (i, ch) in name.chars().enumerate() {
-        if (i == 0 && (ch.is_ascii_alphabetic() || ch == '_'))
-            || (i != 0 && (ch.is_ascii_alphanumeric() || ch == '_'))
-        {
+        if ch == '_' || (i == 0 && ch.is_ascii_alphabetic()) || (i != 0 && ch.is_ascii_alphanumeric()) {
             out.push(ch);
         } else if ch.is_ascii_alphanumeric() {
             if i == 0 {
@@ -70,3 +206,152 @@ fn sanitize_ident(name: &str) -> String {
         out
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::testing::compile_to_c;
+
+    #[test]
+    fn struct_padding_is_spelled_out() {
+        let c = compile_to_c(
+            r#"
+struct flow_key {
+    proto: u8,
+    saddr: u32,
+    sport: u16,
+}
+
+map flows {
+    type: .hash;
+    key: flow_key;
+    value: u64;
+    max: 16;
+}
+
+unit u {
+    section: "xdp";
+    license: "GPL";
+    return 2;
+}
+"#,
+        );
+        let expected = "struct flow_key {\n    __u8 proto;\n    __u8 __pad1[3];\n    __u32 saddr;\n    __u16 sport;\n    __u8 __pad10[2];\n};\n";
+        assert!(c.contains(expected), "{}", c);
+        assert!(c.contains("__type(key, struct flow_key);"), "{}", c);
+    }
+
+    #[test]
+    fn ring_buffer_is_sized_in_bytes_without_key_or_value() {
+        let c = compile_to_c(
+            r#"
+map events {
+    type: .ringbuf;
+    max: 8192;
+}
+
+unit u {
+    section: "xdp";
+    license: "GPL";
+    events.output(1);
+    return 2;
+}
+"#,
+        );
+        let start = c.find("__uint(type, BPF_MAP_TYPE_RINGBUF);").expect("ring buffer map");
+        let def = &c[start..start + c[start..].find("} events").expect("map name")];
+        assert!(def.contains("__uint(max_entries, 8192);"), "{}", def);
+        assert!(!def.contains("__type("), "{}", def);
+    }
+
+    #[test]
+    fn prog_array_is_filled_from_its_programs() {
+        let c = compile_to_c(
+            r#"
+map jumps {
+    type: .prog_array;
+    max: 4;
+    programs: [2: parse];
+}
+
+unit entry {
+    section: "xdp";
+    license: "GPL";
+    tail_call(jumps, 2);
+    return 2;
+}
+
+unit parse {
+    section: "xdp";
+    license: "GPL";
+    return 1;
+}
+"#,
+        );
+        assert!(c.contains("int parse(struct xdp_md *__ctx);\n"), "{}", c);
+        assert!(c.contains("    __array(values, int (void *));\n} jumps SEC(\".maps\") = {\n    .values = {\n        [2] = (void *)&parse,\n"), "{}", c);
+    }
+
+    #[test]
+    fn attributes_become_map_fields() {
+        let c = compile_to_c(
+            r#"
+map counts {
+    type: .hash;
+    key: u32;
+    value: u64;
+    max: 1024;
+    flags: [no_prealloc, rdonly_prog];
+    pinning: by_name;
+    numa_node: 1;
+}
+
+map plain {
+    type: .hash;
+    key: u32;
+    value: u64;
+    max: 1024;
+}
+
+unit u {
+    section: "xdp";
+    license: "GPL";
+    return 2;
+}
+"#,
+        );
+        let counts = "    __uint(max_entries, 1024);\n    __uint(map_flags, BPF_F_NO_PREALLOC | BPF_F_RDONLY_PROG | BPF_F_NUMA_NODE);\n    __uint(numa_node, 1);\n    __uint(pinning, LIBBPF_PIN_BY_NAME);\n";
+        assert!(c.contains(counts), "{}", c);
+        let plain = &c[c.find("} counts").expect("counts")..];
+        assert!(!plain.contains("map_flags") && !plain.contains("pinning"), "{}", plain);
+    }
+
+    #[test]
+    fn map_of_maps_refers_to_its_prototype() {
+        let c = compile_to_c(
+            r#"
+map counts {
+    type: .hash;
+    key: u32;
+    value: u64;
+    max: 16;
+}
+
+map slots {
+    type: .array_of_maps;
+    key: u32;
+    max: 8;
+    inner: counts;
+}
+
+unit u {
+    section: "xdp";
+    license: "GPL";
+    return 2;
+}
+"#,
+        );
+        assert!(c.contains("struct __map_counts {\n    __uint(type, BPF_MAP_TYPE_HASH);\n"), "{}", c);
+        assert!(c.contains("struct __map_counts counts SEC(\".maps\");"), "{}", c);
+        assert!(c.contains("    __type(key, __u32);\n    __array(values, struct __map_counts);\n} slots SEC(\".maps\");"), "{}", c);
+    }
+}
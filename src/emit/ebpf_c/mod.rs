@@ -1,7 +1,9 @@
 pub mod program;
+pub mod body;
 pub mod maps;
 pub mod xdp;
 pub mod write;
+pub mod userspace;
 pub mod helpers;
 pub mod tc;
 pub mod sk;
@@ -1,24 +1,46 @@
 use std::fmt::Write;
 use std::path::Path;
 
-use super::{helpers, maps, write, xdp};
+use super::{helpers, maps, userspace, write, xdp};
 use crate::{
     emit::ebpf_c::{cgroup, fentry, kprobe, lsm, raw_tracepoint, sk, tc, tracepoint},
     ir::ProgramIr,
 };
 
 pub fn emit_program(program: &ProgramIr, output: &Path) -> Result<(), String> {
+    let c = emit_c(program)?;
+    write::compile_to_object(&c, output)?;
+
+    // Userspace reads per-CPU maps through the helpers in `<output>.maps.h`,
+    // written only once there is an object for it to go with.
+    let stem = output.file_stem().and_then(|s| s.to_str()).unwrap_or("solnix");
+    if let Some(h) = userspace::emit_percpu_header(program, stem)? {
+        std::fs::write(output.with_extension("maps.h"), h).map_err(|e| e.to_string())?;
+    }
+    Ok(())
+}
+
+/// Renders the whole program as one C translation unit.
+pub fn emit_c(program: &ProgramIr) -> Result<String, String> {
     let mut c = String::new();
     
     emit_prelude(&mut c, program)?;
     
     helpers::emit_helpers(&mut c)?;
+    maps::emit_structs(&mut c, &program.structs)?;
+    emit_prototypes(&mut c, program)?;
     maps::emit_maps(&mut c, &program.maps)?;
+
+    // Sema has checked that every unit declares the same license.
+    if let Some(unit) = program.units.first() {
+        writeln!(c, "char LICENSE[] SEC(\"license\") = \"{}\";", unit.license).map_err(err)?;
+        writeln!(c).map_err(err)?;
+    }
     
     for unit in &program.units {
         let sec0 = unit
             .sections
-            .get(0)
+            .first()
             .map(|s| s.as_str())
             .unwrap_or("unknown");
 
@@ -54,11 +76,55 @@ pub fn emit_program(program: &ProgramIr, output: &Path) -> Result<(), String> {
             s => return Err(format!("Unsupported section: {}", s)),
         }
     }
-    
-    write::compile_to_object(&c, output)?;
+
+    Ok(c)
+}
+
+/// Declares the units a `prog_array` lists in its `programs`, so the map
+/// initializer can take their address before their definitions.
+fn emit_prototypes(out: &mut String, program: &ProgramIr) -> Result<(), String> {
+    let listed: Vec<&str> = program
+        .maps
+        .iter()
+        .flat_map(|m| m.programs.iter().map(|slot| slot.unit.as_str()))
+        .collect();
+    if listed.is_empty() {
+        return Ok(());
+    }
+
+    for unit in &program.units {
+        if !listed.contains(&unit.name.as_str()) {
+            continue;
+        }
+        let sec0 = unit.sections.first().map(|s| s.as_str()).unwrap_or("unknown");
+        let param = context_param(sec0).ok_or_else(|| format!("Unsupported section: {}", sec0))?;
+        writeln!(out, "int {}({});", unit.name, param).map_err(err)?;
+    }
+    writeln!(out).map_err(err)?;
     Ok(())
 }
 
+/// The context parameter each section's emitter gives its function.
+fn context_param(section: &str) -> Option<&'static str> {
+    let param = match section {
+        "xdp" => "struct xdp_md *__ctx",
+        "tc" | "classifier" | "tcx" | "tcx/egress" | "tc/egress" | "tcx/ingress" | "tc/ingress" => {
+            "struct __sk_buff *__ctx"
+        }
+        "sk_skb/stream_parser" | "sk_skb/stream_verdict" => "struct __sk_buff *__skb",
+        "sk_msg" => "struct sk_msg_md *__msg",
+        "cgroup/skb/ingress" | "cgroup/skb/egress" | "cgroup/sock" => "struct __sk_buff *__skb",
+        "cgroup/sock_addr" => "struct bpf_sock_addr *__ctx",
+        s if s.starts_with("kprobe/") || s.starts_with("kretprobe/") => "struct pt_regs *__ctx",
+        s if s.starts_with("raw_tracepoint/") => "struct bpf_raw_tracepoint_args *__ctx",
+        s if s.starts_with("tracepoint/") || s.starts_with("fentry/") || s.starts_with("fexit/") || s.starts_with("lsm/") => {
+            "void *__ctx"
+        }
+        _ => return None,
+    };
+    Some(param)
+}
+
 fn emit_prelude(out: &mut String, program: &ProgramIr) -> Result<(), String> {
     writeln!(out, "#include \"vmlinux.h\"").map_err(err)?;
     writeln!(out, "#include <bpf/bpf_helpers.h>").map_err(err)?;
@@ -1,40 +1,26 @@
 use std::fmt::Write;
+use crate::emit::ebpf_c::body::{emit_body, BodyConfig};
+use crate::emit::util::fmt_err;
 use crate::ir::UnitIr;
 
 pub fn emit_sk_skb(out: &mut String, unit: &UnitIr, section: &str) -> Result<(), String> {
-    emit_license(out, &unit.license)?;
+    writeln!(out, "SEC(\"{}\")", section).map_err(fmt_err)?;
+    writeln!(out, "int {}(struct __sk_buff *__skb) {{", unit.name).map_err(fmt_err)?;
 
-    writeln!(out, "SEC(\"{}\")", section).map_err(err)?;
-    writeln!(out, "int {}(struct __sk_buff *skb) {{", unit.name).map_err(err)?;
+    emit_body(out, unit, &BodyConfig { ctx: "__skb" })?;
 
-    writeln!(out, "    void *data = (void *)(long)skb->data;").map_err(err)?;
-    writeln!(out, "    void *data_end = (void *)(long)skb->data_end;").map_err(err)?;
-    writeln!(out, "    if (data >= data_end) return SK_DROP;").map_err(err)?;
-
-    writeln!(out, "    return SK_PASS;").map_err(err)?;
-    writeln!(out, "}}").map_err(err)?;
-    writeln!(out).map_err(err)?;
+    writeln!(out, "}}").map_err(fmt_err)?;
+    writeln!(out).map_err(fmt_err)?;
     Ok(())
 }
 
 pub fn emit_sk_msg(out: &mut String, unit: &UnitIr) -> Result<(), String> {
-    emit_license(out, &unit.license)?;
-
-    writeln!(out, "SEC(\"sk_msg\")").map_err(err)?;
-    writeln!(out, "int {}(struct sk_msg_md *msg) {{", unit.name).map_err(err)?;
+    writeln!(out, "SEC(\"sk_msg\")").map_err(fmt_err)?;
+    writeln!(out, "int {}(struct sk_msg_md *__msg) {{", unit.name).map_err(fmt_err)?;
 
-    writeln!(out, "    return SK_PASS;").map_err(err)?;
-    writeln!(out, "}}").map_err(err)?;
-    writeln!(out).map_err(err)?;
-    Ok(())
-}
+    emit_body(out, unit, &BodyConfig { ctx: "__msg" })?;
 
-fn emit_license(out: &mut String, lic: &str) -> Result<(), String> {
-    writeln!(out, "char LICENSE[] SEC(\"license\") = \"{}\";", lic).map_err(err)?;
-    writeln!(out).map_err(err)?;
+    writeln!(out, "}}").map_err(fmt_err)?;
+    writeln!(out).map_err(fmt_err)?;
     Ok(())
 }
-
-fn err(e: std::fmt::Error) -> String {
-    e.to_string()
-}
@@ -0,0 +1,186 @@
+use std::fmt::Write;
+
+use super::maps::{data_type_to_c, declarator, emit_structs, sanitize_ident};
+use crate::ast::{DataType, MapDecl, StructDecl};
+use crate::emit::util::fmt_err;
+use crate::ir::ProgramIr;
+
+/// Renders the userspace header for the per-CPU maps of `program`, or
+/// `None` if it has none.
+///
+/// A lookup from userspace copies one value per possible CPU, each padded
+/// to 8 bytes. For each per-CPU map `m`, the header declares
+/// `struct m_percpu` holding the values as an array of `nr_cpus`, with
+/// `m_percpu_lookup` to fill it from the map's fd, `m_percpu_free` to
+/// release it and `m_percpu_sum` to add up the CPUs' values: field by field
+/// for a struct and element by element for an array, which the sum is
+/// written into rather than returned.
+pub fn emit_percpu_header(program: &ProgramIr, guard: &str) -> Result<Option<String>, String> {
+    let maps: Vec<&MapDecl> = program.maps.iter().filter(|m| m.map_type.is_percpu()).collect();
+    if maps.is_empty() {
+        return Ok(None);
+    }
+
+    let mut h = String::new();
+    let guard = format!("__{}_MAPS_H", sanitize_ident(guard).to_ascii_uppercase());
+    writeln!(h, "#ifndef {}", guard).map_err(fmt_err)?;
+    writeln!(h, "#define {}\n", guard).map_err(fmt_err)?;
+    writeln!(h, "#include <errno.h>").map_err(fmt_err)?;
+    writeln!(h, "#include <stdlib.h>").map_err(fmt_err)?;
+    writeln!(h, "#include <string.h>").map_err(fmt_err)?;
+    writeln!(h, "#include <linux/types.h>").map_err(fmt_err)?;
+    writeln!(h, "#include <bpf/bpf.h>").map_err(fmt_err)?;
+    writeln!(h, "#include <bpf/libbpf.h>\n").map_err(fmt_err)?;
+
+    emit_structs(&mut h, &program.structs)?;
+    for m in maps {
+        if let Some(value_type) = &m.value_type {
+            emit_percpu_map(&mut h, &sanitize_ident(&m.name), value_type, &program.structs)?;
+        }
+    }
+
+    writeln!(h, "#endif /* {} */", guard).map_err(fmt_err)?;
+    Ok(Some(h))
+}
+
+fn emit_percpu_map(out: &mut String, name: &str, value_type: &DataType, structs: &[StructDecl]) -> Result<(), String> {
+    let values = match value_type {
+        DataType::Array(..) => declarator(value_type, "(*values)"),
+        _ => declarator(value_type, "*values"),
+    };
+    writeln!(out, "struct {}_percpu {{", name).map_err(fmt_err)?;
+    writeln!(out, "    int nr_cpus;").map_err(fmt_err)?;
+    writeln!(out, "    {};", values).map_err(fmt_err)?;
+    writeln!(out, "}};\n").map_err(fmt_err)?;
+
+    writeln!(
+        out,
+        "static inline int {name}_percpu_lookup(int map_fd, const void *key, struct {name}_percpu *v)\n{{"
+    )
+    .map_err(fmt_err)?;
+    writeln!(out, "    size_t stride = (sizeof(*v->values) + 7) & ~(size_t)7;").map_err(fmt_err)?;
+    writeln!(out, "    int nr_cpus = libbpf_num_possible_cpus();").map_err(fmt_err)?;
+    writeln!(out, "    char *buf;\n").map_err(fmt_err)?;
+    writeln!(out, "    if (nr_cpus < 0)\n        return nr_cpus;").map_err(fmt_err)?;
+    writeln!(out, "    buf = calloc(nr_cpus, stride);").map_err(fmt_err)?;
+    writeln!(out, "    v->values = calloc(nr_cpus, sizeof(*v->values));").map_err(fmt_err)?;
+    writeln!(out, "    v->nr_cpus = 0;").map_err(fmt_err)?;
+    writeln!(out, "    if (!buf || !v->values || bpf_map_lookup_elem(map_fd, key, buf)) {{").map_err(fmt_err)?;
+    writeln!(out, "        int err = buf && v->values ? -errno : -ENOMEM;\n").map_err(fmt_err)?;
+    writeln!(out, "        free(buf);").map_err(fmt_err)?;
+    writeln!(out, "        free(v->values);").map_err(fmt_err)?;
+    writeln!(out, "        v->values = NULL;").map_err(fmt_err)?;
+    writeln!(out, "        return err;").map_err(fmt_err)?;
+    writeln!(out, "    }}").map_err(fmt_err)?;
+    writeln!(out, "    for (int cpu = 0; cpu < nr_cpus; cpu++)").map_err(fmt_err)?;
+    writeln!(out, "        memcpy(&v->values[cpu], buf + cpu * stride, sizeof(*v->values));").map_err(fmt_err)?;
+    writeln!(out, "    v->nr_cpus = nr_cpus;").map_err(fmt_err)?;
+    writeln!(out, "    free(buf);").map_err(fmt_err)?;
+    writeln!(out, "    return 0;\n}}\n").map_err(fmt_err)?;
+
+    writeln!(out, "static inline void {name}_percpu_free(struct {name}_percpu *v)\n{{").map_err(fmt_err)?;
+    writeln!(out, "    free(v->values);").map_err(fmt_err)?;
+    writeln!(out, "    v->values = NULL;").map_err(fmt_err)?;
+    writeln!(out, "    v->nr_cpus = 0;\n}}\n").map_err(fmt_err)?;
+
+    // What each CPU adds to the sum, as `sum... += v->values[cpu]...;`.
+    let mut terms = Vec::new();
+    match value_type {
+        DataType::Scalar(_) => terms.push("sum += v->values[cpu];".to_string()),
+        DataType::Array(_, len) => terms.push(format!("for (int i = 0; i < {len}; i++)\n            sum[i] += v->values[cpu][i];")),
+        DataType::Struct(s) => {
+            let decl = structs.iter().find(|d| &d.name == s).ok_or_else(|| format!("Unknown struct: {}", s))?;
+            for field in &decl.fields {
+                let f = &field.name;
+                terms.push(match field.ty {
+                    DataType::Array(_, len) => {
+                        format!("for (int i = 0; i < {len}; i++)\n            sum.{f}[i] += v->values[cpu].{f}[i];")
+                    }
+                    _ => format!("sum.{f} += v->values[cpu].{f};"),
+                });
+            }
+        }
+    }
+
+    match value_type {
+        DataType::Array(..) => {
+            writeln!(
+                out,
+                "static inline void {name}_percpu_sum(const struct {name}_percpu *v, {})\n{{",
+                declarator(value_type, "sum")
+            )
+            .map_err(fmt_err)?;
+            writeln!(out, "    memset(sum, 0, sizeof(*v->values));").map_err(fmt_err)?;
+        }
+        _ => {
+            let ty = data_type_to_c(value_type);
+            writeln!(out, "static inline {ty} {name}_percpu_sum(const struct {name}_percpu *v)\n{{").map_err(fmt_err)?;
+            writeln!(out, "    {ty} sum;\n").map_err(fmt_err)?;
+            writeln!(out, "    memset(&sum, 0, sizeof(sum));").map_err(fmt_err)?;
+        }
+    }
+    writeln!(out, "    for (int cpu = 0; cpu < v->nr_cpus; cpu++) {{").map_err(fmt_err)?;
+    for term in terms {
+        writeln!(out, "        {}", term).map_err(fmt_err)?;
+    }
+    writeln!(out, "    }}").map_err(fmt_err)?;
+    if !matches!(value_type, DataType::Array(..)) {
+        writeln!(out, "    return sum;").map_err(fmt_err)?;
+    }
+    writeln!(out, "}}\n").map_err(fmt_err)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::emit_percpu_header;
+    use crate::compiler::testing::lower;
+
+    const UNIT: &str = "unit u {\n    section: \"xdp\";\n    license: \"GPL\";\n    return 2;\n}\n";
+
+    #[test]
+    fn percpu_values_are_read_into_a_vector_and_summed() {
+        let src = format!(
+            r#"
+struct stats {{
+    packets: u64,
+    bytes: u32[2]
+}}
+
+map flows {{
+    type: .lru_percpu_hash;
+    key: u32;
+    value: stats;
+    max: 64;
+}}
+
+map hist {{
+    type: .percpu_array;
+    key: u32;
+    value: u32[3];
+    max: 4;
+}}
+
+{}"#,
+            UNIT
+        );
+        let h = emit_percpu_header(&lower(&src), "out").unwrap().expect("per-CPU maps have a header");
+        assert!(h.starts_with("#ifndef __OUT_MAPS_H\n"), "{}", h);
+        assert!(h.contains("struct flows_percpu {\n    int nr_cpus;\n    struct stats *values;\n};"), "{}", h);
+        assert!(h.contains("size_t stride = (sizeof(*v->values) + 7) & ~(size_t)7;"), "{}", h);
+        assert!(h.contains("int nr_cpus = libbpf_num_possible_cpus();"), "{}", h);
+        assert!(h.contains("static inline struct stats flows_percpu_sum(const struct flows_percpu *v)"), "{}", h);
+        assert!(h.contains("        sum.packets += v->values[cpu].packets;\n"), "{}", h);
+        assert!(h.contains("            sum.bytes[i] += v->values[cpu].bytes[i];\n"), "{}", h);
+
+        assert!(h.contains("    __u32 (*values)[3];\n"), "{}", h);
+        assert!(h.contains("static inline void hist_percpu_sum(const struct hist_percpu *v, __u32 sum[3])"), "{}", h);
+        assert!(h.contains("static inline void hist_percpu_free(struct hist_percpu *v)"), "{}", h);
+    }
+
+    #[test]
+    fn shared_maps_need_no_header() {
+        let src = format!("map counts {{\n    type: .hash;\n    key: u32;\n    value: u64;\n    max: 8;\n}}\n\n{}", UNIT);
+        assert!(emit_percpu_header(&lower(&src), "out").unwrap().is_none());
+    }
+}
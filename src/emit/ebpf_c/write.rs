@@ -9,6 +9,8 @@ pub fn compile_to_object(code: &str, out: &Path) -> Result<(), String> {
             "-O2",
             "-g",
             "-target", "bpf",
+            // v3 has the atomic fetch-and-op, xchg and cmpxchg instructions.
+            "-mcpu=v3",
             "-D__TARGET_ARCH_x86",
             "-I.",
             "-c",
@@ -0,0 +1,239 @@
+use std::collections::HashMap;
+
+use super::unit::BasicBlock;
+use super::{BlockId, Instruction, Opcode, UnitIr};
+use crate::ast::Type;
+
+/// Inserts the `data_end` checks the verifier demands before packet loads.
+///
+/// Two dataflow facts drive placement, both measured as "the first N bytes
+/// of the packet":
+///
+/// - *available*: bytes already proven present on every path reaching a
+///   point (forward, minimum over predecessors). A load inside that range
+///   needs no check.
+/// - *anticipated*: bytes every path from a point will load before it has
+///   a visible effect (backward, minimum over successors). When a check is
+///   needed it covers this whole range, so one check serves all of the
+///   loads that follow instead of one check each.
+///
+/// A short packet therefore still takes the fallback before any map write
+/// it would not have reached, and never on a path that does not load that
+/// far.
+pub fn insert_bounds_checks(unit: &mut UnitIr) {
+    let anticipated_out = anticipated(unit);
+    let available_in = available(unit, &anticipated_out);
+
+    let mut inserted = Vec::new();
+    for (idx, block) in unit.blocks.iter().enumerate() {
+        let avail = available_in.get(&block.id).copied().unwrap_or(0);
+        let ant_out = anticipated_out.get(&block.id).copied().unwrap_or(0);
+        let (_, checks) = scan_block(block, avail, ant_out);
+        inserted.push((idx, checks));
+    }
+
+    for (idx, checks) in inserted.into_iter().rev() {
+        for (position, end) in checks.into_iter().rev() {
+            let loc = unit.blocks[idx].instructions[position].loc;
+            let result = unit.alloc_var(Type::U32);
+            unit.blocks[idx].instructions.insert(
+                position,
+                Instruction {
+                    result,
+                    opcode: Opcode::BoundsCheck { end },
+                    operands: Vec::new(),
+                    result_type: Type::U32.into(),
+                    loc,
+                },
+            );
+        }
+    }
+}
+
+/// Packet end offset a load reads up to.
+fn packet_end(opcode: &Opcode) -> Option<i32> {
+    match opcode {
+        Opcode::LoadPacket { offset, size } => Some(offset + i32::from(*size)),
+        _ => None,
+    }
+}
+
+/// Whether a short packet must not skip this instruction, i.e. a check may
+/// not be hoisted above it.
+fn has_side_effects(opcode: &Opcode) -> bool {
+    matches!(
+        opcode,
+        Opcode::Store { .. }
+            | Opcode::StoreField { .. }
+            | Opcode::StoreIndex { .. }
+            | Opcode::Atomic { .. }
+            | Opcode::LookupOrInit { .. }
+            | Opcode::UpdateMap { .. }
+            | Opcode::DeleteMap { .. }
+            | Opcode::PushMap { .. }
+            | Opcode::PopMap { .. }
+            | Opcode::RingbufSubmit
+            | Opcode::RingbufDiscard
+            | Opcode::RingbufOutput { .. }
+            | Opcode::PerfOutput { .. }
+            | Opcode::TailCall { .. }
+            | Opcode::Redirect { .. }
+    )
+}
+
+/// Bytes anticipated at the start of `block` given those anticipated at
+/// its end, and for each instruction the bytes anticipated just before it.
+fn anticipated_in_block(block: &BasicBlock, ant_out: i32) -> (i32, Vec<i32>) {
+    let mut ant = ant_out;
+    let mut before = vec![0; block.instructions.len()];
+    for (i, inst) in block.instructions.iter().enumerate().rev() {
+        if has_side_effects(&inst.opcode) {
+            ant = 0;
+        }
+        if let Some(end) = packet_end(&inst.opcode) {
+            ant = ant.max(end);
+        }
+        before[i] = ant;
+    }
+    (ant, before)
+}
+
+fn anticipated(unit: &UnitIr) -> HashMap<BlockId, i32> {
+    let mut ant_in: HashMap<BlockId, i32> = HashMap::new();
+    let mut ant_out: HashMap<BlockId, i32> = HashMap::new();
+
+    // Start from "everything" and shrink; blocks not yet visited do not
+    // constrain the minimum.
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in unit.blocks.iter().rev() {
+            let out = block
+                .terminator
+                .successors()
+                .iter()
+                .map(|succ| ant_in.get(succ).copied().unwrap_or(i32::MAX))
+                .min()
+                .unwrap_or(0);
+            let (entry, _) = anticipated_in_block(block, out);
+
+            if ant_in.get(&block.id) != Some(&entry) || ant_out.get(&block.id) != Some(&out) {
+                ant_in.insert(block.id, entry);
+                ant_out.insert(block.id, out);
+                changed = true;
+            }
+        }
+    }
+    ant_out
+}
+
+/// Walks `block` with `avail` bytes proven on entry. Returns the bytes
+/// proven on exit and the checks to insert, as (instruction index, end).
+fn scan_block(block: &BasicBlock, mut avail: i32, ant_out: i32) -> (i32, Vec<(usize, i32)>) {
+    let (_, ant_before) = anticipated_in_block(block, ant_out);
+    let mut checks = Vec::new();
+
+    for (i, inst) in block.instructions.iter().enumerate() {
+        if let Some(end) = packet_end(&inst.opcode) {
+            if avail < end {
+                let covered = end.max(ant_before[i]);
+                checks.push((i, covered));
+                avail = covered;
+            }
+        }
+    }
+    (avail, checks)
+}
+
+fn available(unit: &UnitIr, anticipated_out: &HashMap<BlockId, i32>) -> HashMap<BlockId, i32> {
+    let mut avail_in: HashMap<BlockId, i32> = HashMap::new();
+    avail_in.insert(unit.entry, 0);
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in &unit.blocks {
+            let Some(&entry) = avail_in.get(&block.id) else {
+                continue;
+            };
+            let ant_out = anticipated_out.get(&block.id).copied().unwrap_or(0);
+            let (exit, _) = scan_block(block, entry, ant_out);
+
+            for succ in block.terminator.successors() {
+                let merged = match avail_in.get(&succ) {
+                    Some(&existing) => existing.min(exit),
+                    None => exit,
+                };
+                if avail_in.get(&succ) != Some(&merged) {
+                    avail_in.insert(succ, merged);
+                    changed = true;
+                }
+            }
+        }
+    }
+    avail_in
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::testing::lower;
+
+    /// `end` of every bounds check in `body`, in block order.
+    fn checks(section: &str, body: &str) -> Vec<i32> {
+        let src = format!(
+            "map counts {{\n    type: .hash;\n    key: u32;\n    value: u64;\n    max: 64;\n}}\n\nunit u {{\n    section: \"{}\";\n    license: \"GPL\";\n\n{}\n}}\n",
+            section, body
+        );
+        let unit = lower(&src).units.remove(0);
+        let mut blocks: Vec<&BasicBlock> = unit.blocks.iter().collect();
+        blocks.sort_by_key(|b| b.id);
+        blocks
+            .iter()
+            .flat_map(|b| &b.instructions)
+            .filter_map(|inst| match inst.opcode {
+                Opcode::BoundsCheck { end } => Some(end),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn loads_share_one_check() {
+        let body = "    reg proto = ctx.load_u8(23);\n    reg src = ctx.load_u32(26);\n    return proto + (src as u8);";
+        assert_eq!(checks("xdp", body), [30]);
+    }
+
+    #[test]
+    fn check_covers_only_what_every_path_loads() {
+        let body = "    reg proto = ctx.load_u8(23);\n    if guard(proto == 6) {\n        reg dport = ctx.load_u16(36);\n        return dport;\n    } else {\n        reg x = ctx.load_u8(14);\n    }\n    return 2;";
+        assert_eq!(checks("xdp", body), [24, 38]);
+    }
+
+    #[test]
+    fn check_is_not_hoisted_above_a_map_write() {
+        let body = "    reg src = ctx.load_u32(26);\n    heap c = counts.lookup(src);\n    if guard(c) {\n        *c += 1;\n    }\n    reg flags = ctx.load_u8(47);\n    return flags;";
+        assert_eq!(checks("xdp", body), [30, 48]);
+    }
+
+    #[test]
+    fn context_reads_need_no_check() {
+        let body = "    reg id = ctx.load_u32(8);\n    return id;";
+        assert!(checks("tracepoint/syscalls/sys_enter_execve", body).is_empty());
+    }
+
+    #[test]
+    fn check_is_not_hoisted_above_an_element_write() {
+        let body = "    reg buf: u8[4];\n    buf[0] = ctx.load_u8(14);\n    heap c = counts.lookup(1);\n    if guard(c) {\n        *c += 1;\n    }\n    buf[1] = ctx.load_u8(20);\n    return buf[1];";
+        assert_eq!(checks("xdp", body), [15, 21]);
+    }
+
+    #[test]
+    fn check_is_not_hoisted_above_an_atomic_update() {
+        let body = "    heap c = counts.lookup(1);\n    if guard(c) {\n        atomic *c += 1;\n        reg old = atomic xchg(*c, 0);\n    }\n    return ctx.load_u8(14);";
+        assert_eq!(checks("xdp", body), [15]);
+
+        let body = "    heap c = counts.lookup(1);\n    if guard(c) {\n        reg a = ctx.load_u8(14);\n        atomic *c += 1;\n        reg b = ctx.load_u8(20);\n    }\n    return 2;";
+        assert_eq!(checks("xdp", body), [15, 21]);
+    }
+}
@@ -0,0 +1,221 @@
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use super::{BlockId, UnitIr};
+
+/// Immediate post-dominator of every block. `None` means the only common
+/// successor of all paths out of the block is the function exit, i.e. the
+/// paths never merge again before returning.
+pub fn immediate_post_dominators(unit: &UnitIr) -> HashMap<BlockId, Option<BlockId>> {
+    // `None` stands for "every block", the starting point of the
+    // intersection; the virtual exit node is simply absent from the sets.
+    let mut pdom: HashMap<BlockId, Option<BTreeSet<BlockId>>> =
+        unit.blocks.iter().map(|b| (b.id, None)).collect();
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in unit.blocks.iter().rev() {
+            let mut set: Option<BTreeSet<BlockId>> = None;
+            let succs = block.terminator.successors();
+            if succs.is_empty() {
+                set = Some(BTreeSet::new());
+            }
+            for succ in succs {
+                let Some(Some(succ_set)) = pdom.get(&succ) else {
+                    continue;
+                };
+                set = Some(match set {
+                    Some(acc) => acc.intersection(succ_set).copied().collect(),
+                    None => succ_set.clone(),
+                });
+            }
+
+            let Some(mut set) = set else { continue };
+            set.insert(block.id);
+            if pdom.get(&block.id) != Some(&Some(set.clone())) {
+                pdom.insert(block.id, Some(set));
+                changed = true;
+            }
+        }
+    }
+
+    pdom.iter()
+        .map(|(&id, set)| {
+            let strict: BTreeSet<BlockId> = set
+                .iter()
+                .flatten()
+                .copied()
+                .filter(|&b| b != id)
+                .collect();
+            // The closest strict post-dominator is the one every other strict
+            // post-dominator also post-dominates.
+            let ipdom = strict.iter().copied().find(|candidate| {
+                pdom.get(candidate)
+                    .and_then(|s| s.as_ref())
+                    .is_some_and(|s| s.len() == strict.len())
+            });
+            (id, ipdom)
+        })
+        .collect()
+}
+
+/// The nearest block reachable from both `a` and `b`: the one every other
+/// common successor is reachable from. Used to find where two branch arms
+/// rejoin when one of them may also return early, so there is no
+/// post-dominator.
+///
+/// Reachability does not follow back edges, otherwise every block of a loop
+/// body would reach every other one. Entering a loop through its header is
+/// an ordinary forward edge, so an arm containing a loop still reaches the
+/// code after it.
+pub fn join_point(unit: &UnitIr, a: BlockId, b: BlockId) -> Option<BlockId> {
+    let loops = natural_loops(unit);
+    let from_a = forward_reachable(unit, a, &loops);
+    let from_b = forward_reachable(unit, b, &loops);
+    let common: BTreeSet<BlockId> = from_a.intersection(&from_b).copied().collect();
+
+    common.iter().copied().find(|&candidate| {
+        let from_candidate = forward_reachable(unit, candidate, &loops);
+        common.iter().all(|other| from_candidate.contains(other))
+    })
+}
+
+/// Every loop in the unit, keyed by its header (the target of a back
+/// edge), with the blocks of its body including the header itself.
+pub fn natural_loops(unit: &UnitIr) -> HashMap<BlockId, BTreeSet<BlockId>> {
+    let mut back_edges: Vec<(BlockId, BlockId)> = Vec::new();
+    let mut visited = HashSet::new();
+    let mut on_stack = HashSet::new();
+    // Iterative DFS; each frame is a block and the successors still to visit.
+    let mut stack = vec![(unit.entry, successors(unit, unit.entry))];
+    visited.insert(unit.entry);
+    on_stack.insert(unit.entry);
+
+    while let Some((id, pending)) = stack.last_mut() {
+        let id = *id;
+        match pending.pop() {
+            Some(succ) if on_stack.contains(&succ) => back_edges.push((id, succ)),
+            Some(succ) => {
+                if visited.insert(succ) {
+                    on_stack.insert(succ);
+                    stack.push((succ, successors(unit, succ)));
+                }
+            }
+            None => {
+                on_stack.remove(&id);
+                stack.pop();
+            }
+        }
+    }
+
+    let mut predecessors: HashMap<BlockId, Vec<BlockId>> = HashMap::new();
+    for block in &unit.blocks {
+        for succ in block.terminator.successors() {
+            predecessors.entry(succ).or_default().push(block.id);
+        }
+    }
+
+    // The body is everything that reaches a back edge without going
+    // through the header.
+    let mut loops: HashMap<BlockId, BTreeSet<BlockId>> = HashMap::new();
+    for (source, header) in back_edges {
+        let body = loops.entry(header).or_insert_with(|| BTreeSet::from([header]));
+        let mut work = vec![source];
+        while let Some(id) = work.pop() {
+            if body.insert(id) {
+                work.extend(predecessors.get(&id).into_iter().flatten().copied());
+            }
+        }
+    }
+    loops
+}
+
+fn successors(unit: &UnitIr, id: BlockId) -> Vec<BlockId> {
+    unit.block(id).map(|b| b.terminator.successors()).unwrap_or_default()
+}
+
+/// Blocks reachable from `start` without taking a back edge, i.e. an edge
+/// from inside a loop to its own header.
+fn forward_reachable(unit: &UnitIr, start: BlockId, loops: &HashMap<BlockId, BTreeSet<BlockId>>) -> BTreeSet<BlockId> {
+    let mut seen = BTreeSet::new();
+    let mut stack = vec![start];
+    while let Some(id) = stack.pop() {
+        if !seen.insert(id) {
+            continue;
+        }
+        let is_back_edge = |succ: &BlockId| loops.get(succ).is_some_and(|body| body.contains(&id));
+        stack.extend(successors(unit, id).into_iter().filter(|succ| !is_back_edge(succ)));
+    }
+    seen
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compiler::testing::lower;
+    use crate::ir::{Operand, Terminator};
+
+    fn unit(body: &str) -> UnitIr {
+        let src = format!("unit u {{\n    section: \"kprobe/do_sys_open\";\n    license: \"GPL\";\n\n    reg y = ctx.load_u8(0);\n{}\n}}\n", body);
+        lower(&src).units.remove(0)
+    }
+
+    fn branch(unit: &UnitIr, id: BlockId) -> (BlockId, BlockId) {
+        match unit.block(id).map(|b| &b.terminator) {
+            Some(Terminator::Branch { true_block, false_block, .. }) => (*true_block, *false_block),
+            other => panic!("expected a branch, found {:?}", other),
+        }
+    }
+
+    #[test]
+    fn arms_that_fall_through_rejoin_at_the_post_dominator() {
+        let unit = unit("    if guard(y == 1) {\n        y = 2;\n    } else {\n        y = 3;\n    }\n    return y;");
+        let (then_arm, else_arm) = branch(&unit, unit.entry);
+        let merge = immediate_post_dominators(&unit)[&unit.entry].expect("arms rejoin");
+        assert_eq!(join_point(&unit, then_arm, else_arm), Some(merge));
+        assert!(matches!(unit.block(merge).unwrap().terminator, Terminator::Return(_)));
+    }
+
+    #[test]
+    fn arms_that_both_return_never_rejoin() {
+        let unit = unit("    if guard(y == 1) {\n        return 1;\n    } else {\n        return 2;\n    }");
+        let (then_arm, else_arm) = branch(&unit, unit.entry);
+        assert_eq!(immediate_post_dominators(&unit)[&unit.entry], None);
+        assert_eq!(join_point(&unit, then_arm, else_arm), None);
+    }
+
+    #[test]
+    fn arm_that_may_return_early_still_rejoins() {
+        let unit = unit("    if guard(y == 1) {\n        if guard(y == 2) {\n            return 1;\n        }\n        y = 3;\n    }\n    return y;");
+        let (then_arm, else_arm) = branch(&unit, unit.entry);
+        assert_eq!(immediate_post_dominators(&unit)[&unit.entry], None);
+        let join = join_point(&unit, then_arm, else_arm).expect("the fall-through paths rejoin");
+        assert!(matches!(unit.block(join).unwrap().terminator, Terminator::Return(Operand::Var(_))));
+    }
+
+    #[test]
+    fn arm_containing_a_loop_rejoins_after_it() {
+        let unit = unit("    reg x = 0;\n    if guard(y == 1) {\n        for i in 0..4 {\n            if guard(x > 10) {\n                return 2;\n            }\n            x += i;\n        }\n    }\n    x = 1;\n    return x;");
+        let loops = natural_loops(&unit);
+        assert_eq!(loops.len(), 1);
+        let (header, body) = loops.iter().next().unwrap();
+
+        let (then_arm, else_arm) = branch(&unit, unit.entry);
+        let join = join_point(&unit, then_arm, else_arm).expect("the arms rejoin after the loop");
+        assert_ne!(join, *header);
+        assert!(!body.contains(&join));
+        assert!(matches!(unit.block(join).unwrap().terminator, Terminator::Return(Operand::Var(_))));
+    }
+
+    #[test]
+    fn back_edges_are_not_followed() {
+        let unit = unit("    reg x = 0;\n    for i in 0..4 {\n        x += i;\n    }\n    return x;");
+        let loops = natural_loops(&unit);
+        let (&header, body) = loops.iter().next().expect("one loop");
+        let (inside, exit) = branch(&unit, header);
+        assert!(body.contains(&inside) && !body.contains(&exit));
+        // Around the loop the body only reaches the header again through
+        // the back edge, so the two sides of the test never rejoin.
+        assert_eq!(join_point(&unit, inside, exit), None);
+    }
+}
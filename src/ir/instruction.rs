@@ -1,4 +1,5 @@
-use crate::ast::Type;
+use crate::ast::DataType;
+use crate::parser::SourceLoc;
 
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
@@ -6,26 +7,115 @@ pub struct Instruction {
     pub result: VarId,
     pub opcode: Opcode,
     pub operands: Vec<Operand>,
-    pub result_type: Type,
+    pub result_type: DataType,
+    /// Statement the instruction was lowered from.
+    pub loc: SourceLoc,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct VarId(pub u32);
 
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub enum Opcode {
+    Copy,
     LoadKey,
     Store { size: u8 },
+    /// Read a field of the struct the pointer operand refers to.
+    LoadField { field: String },
+    /// Write the second operand to a field of the struct the first operand
+    /// points to.
+    StoreField { field: String },
+    /// Set a field of the struct held in the result slot itself.
+    SetField { field: String },
+    /// Copy constant bytes into a field of the struct held in the result
+    /// slot, such as the network-order address of a CIDR literal.
+    SetBytes { field: String, bytes: Vec<u8> },
+    /// Read element `operands[1]` of an array: the slot `operands[0]`, the
+    /// array it points to, or its `field` when it points to a struct.
+    LoadIndex { field: Option<String> },
+    /// Write `operands[2]` to an array element, addressed as for `LoadIndex`.
+    StoreIndex { field: Option<String> },
+    /// Clear every byte of the result slot.
+    Zero,
+    /// Atomic read-modify-write of a map value. The first operand points to
+    /// it; with `field` the place is that field, and when `indexed` the next
+    /// operand is an index into the array there. The remaining operands are
+    /// the values `op` takes, and the result is the value it replaced.
+    Atomic { op: AtomicOp, field: Option<String>, indexed: bool },
     
     LoadCtx { offset: i32, size: u8 },
     LoadPacket { offset: i32, size: u8 },
+    /// Returns the unit's packet fallback verdict unless the first `end`
+    /// bytes of packet data are present.
+    BoundsCheck { end: i32 },
     
     NullCheck,
 
     Binary { op: BinaryOp },
+    Unary { op: UnaryOp },
 
-    CallMap { map_name: String },
+    /// Narrow the operand to `result_type`, dropping the high bits.
+    Trunc,
+    /// Widen an unsigned operand to `result_type`.
+    ZeroExtend,
+    /// Widen a signed operand to `result_type`, replicating the sign bit.
+    SignExtend,
+
+    /// Look up `operands[0]`; the result is null when the key is missing,
+    /// unless `in_bounds` marks a constant index within an array.
+    CallMap { map_name: String, in_bounds: bool },
+    /// Look up `operands[1]` in the inner map `operands[0]` found by a
+    /// lookup in a map of maps; null when that lookup found nothing.
+    LookupInner,
+    /// Look up `operands[0]`, inserting `operands[1]` under it first if it
+    /// is missing; the result points to the value, or is null if the insert
+    /// failed.
+    LookupOrInit { map_name: String },
+    /// Write value `operands[1]` under key `operands[0]`. The result is the
+    /// helper's return code, 0 on success.
+    UpdateMap { map_name: String, flags: i64 },
+    /// Remove key `operands[0]`; the result is the helper's return code.
+    DeleteMap { map_name: String },
+    /// Append `operands[0]` to a queue or stack; the result is the helper's
+    /// return code.
+    PushMap { map_name: String, flags: i64 },
+    /// Remove the next element into the buffer slot `operands[0]`; the
+    /// result points to that buffer, or is null when the map is empty.
+    PopMap { map_name: String },
+    /// As `PopMap`, but leaves the element in the map.
+    PeekMap { map_name: String },
+    /// Reserve a record of `result_type` in a ring buffer; the result
+    /// points to it, or is null when the buffer is full.
+    RingbufReserve { map_name: String },
+    /// Publish the reserved record `operands[0]` to the ring buffer's reader.
+    RingbufSubmit,
+    /// Release the reserved record `operands[0]` without publishing it.
+    RingbufDiscard,
+    /// Copy `operands[0]` into a ring buffer as one record; the result is
+    /// the helper's return code.
+    RingbufOutput { map_name: String },
+    /// Send `operands[0]` to the perf event buffer of the current CPU; the
+    /// result is the helper's return code.
+    PerfOutput { map_name: String },
+    /// Jump to the program in slot `operands[0]` of a prog_array; falls
+    /// through when the slot is empty.
+    TailCall { map_name: String },
+    /// Redirect to the target under key `operands[0]`; the result is the
+    /// verdict to return, `fallback` when the slot is empty.
+    Redirect { map_name: String, helper: RedirectHelper, fallback: i64 },
+}
+
+/// The kernel helper a `redirect` goes through, fixed by the program type
+/// and the map: the socket helpers take a sockmap key by value and a
+/// sockhash key by address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RedirectHelper {
+    Xdp,
+    SkMap,
+    SkHash,
+    MsgMap,
+    MsgHash,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -34,7 +124,37 @@ pub enum BinaryOp {
     Sub,
     Mul,
     Div,
-    Mod
+    Mod,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    And,
+    Or,
+    BitAnd,
+    BitOr,
+    BitXor,
+    Shl,
+    Shr,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AtomicOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+    Xchg,
+    Cmpxchg,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Not,
+    BitNot,
 }
 
 #[allow(dead_code)]
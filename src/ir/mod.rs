@@ -1,3 +1,5 @@
+pub mod bounds;
+pub mod cfg;
 pub mod instruction;
 pub mod program;
 pub mod unit;
@@ -8,7 +10,10 @@ pub use instruction::{
     VarId,
     Opcode,
     BinaryOp,
+    UnaryOp,
+    AtomicOp,
     Operand,
+    RedirectHelper,
 };
 
 pub use program::{
@@ -16,6 +21,8 @@ pub use program::{
 };
 
 pub use unit::{
+    BlockId,
+    Terminator,
     UnitIr,
 };
 
@@ -1,8 +1,9 @@
 use super::{UnitIr, LoweringError};
-use crate::ast::{MapDecl, Program};
+use crate::ast::{MapDecl, Program, StructDecl};
 
 #[derive(Debug, Clone)]
 pub struct ProgramIr {
+    pub structs: Vec<StructDecl>,
     pub maps: Vec<MapDecl>,
     pub units: Vec<UnitIr>,
 }
@@ -11,10 +12,11 @@ pub fn lower_program(program: &Program) -> Result<ProgramIr, LoweringError> {
     let mut units = Vec::new();
 
     for unit in &program.units {
-        units.push(UnitIr::lower(unit)?);
+        units.push(UnitIr::lower(unit, &program.maps, &program.structs)?);
     }
 
     Ok(ProgramIr {
+        structs: program.structs.clone(),
         maps: program.maps.clone(),
         units,
     })
@@ -1,25 +1,55 @@
+use std::collections::HashMap;
+
 use super::{Instruction, VarId};
-use crate::ast::{Expr, ExprKind, Stmt, StmtKind, Unit};
-use crate::ir::{BinaryOp, LoweringError, Opcode, Operand};
+use crate::ast::{
+    BinOp, BinaryExpr, Cidr, DataType, Expr, ExprKind, FieldAccess, ForLoop, IfGuard, IndexExpr, MapDecl, MapType,
+    MethodCall, Redirect, Stmt, StmtKind, StructDecl, StructLiteral, Type, Unit, VarType,
+};
+use crate::ir::bounds::insert_bounds_checks;
+use crate::ir::{AtomicOp, BinaryOp, LoweringError, Opcode, Operand, RedirectHelper, UnaryOp};
+use crate::parser::SourceLoc;
+use crate::sema::SectionValidator;
+use crate::sema::types::{
+    atomic_op, const_eval, constant_type, ctx_load, fallback_value, flags_value, fold_binary, fold_unary, is_boolean_op, is_shift_op, loop_counter_type,
+};
 
 #[derive(Debug, Clone)]
 pub struct UnitIr {
     pub name: String,
     pub sections: Vec<String>,
     pub license: String,
+    /// Verdict returned by a failed packet bounds check. `None` when the
+    /// program type has no direct packet access and `ctx.load_*` reads the
+    /// context struct instead.
+    pub packet_fallback: Option<String>,
+    pub entry: BlockId,
     pub blocks: Vec<BasicBlock>,
-    pub next_var_id: u32,
+    pub vars: Vec<VarInfo>,
+}
+
+/// A value slot. Temporaries are written exactly once; slots that back a
+/// source-level binding are written by its declaration and by every later
+/// assignment, which is what keeps reassignment inside a branch visible
+/// after the merge.
+#[derive(Debug, Clone)]
+pub struct VarInfo {
+    pub ty: DataType,
+    /// The slot holds a pointer to a `ty` (a map value) rather than a `ty`.
+    pub is_ptr: bool,
+    /// Name of the `reg`/`imm`/`heap` binding this slot backs, if any.
+    pub name: Option<String>,
 }
 
 #[allow(dead_code)]
 #[derive(Debug, Clone)]
 pub struct BasicBlock {
     pub id: BlockId,
+    pub preds: Vec<BlockId>,
     pub instructions: Vec<Instruction>,
     pub terminator: Terminator,
 }
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
 pub struct BlockId(pub u32);
 
 #[allow(dead_code)]
@@ -34,178 +64,408 @@ pub enum Terminator {
     },
 }
 
-struct LowerCtx {
-    vars: std::collections::HashMap<String, VarId>,
-    // Track which variables are map pointers (from CallMap operations)
-    map_ptr_vars: std::collections::HashSet<VarId>,
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Return(_) => Vec::new(),
+            Terminator::Jump(target) => vec![*target],
+            Terminator::Branch { true_block, false_block, .. } => vec![*true_block, *false_block],
+        }
+    }
+}
+
+/// What a source-level name refers to while lowering.
+#[derive(Debug, Clone, Copy)]
+enum Binding {
+    /// A `reg` or `heap` binding backed by a slot.
+    Slot(VarId),
+    /// An `imm` binding, which is a compile-time constant and never
+    /// occupies a slot.
+    Const(i64),
+}
+
+struct LowerCtx<'a> {
+    maps: &'a [MapDecl],
+    structs: &'a [StructDecl],
+    /// Lexical scopes, innermost last. Each `if`/`else` arm opens one.
+    scopes: Vec<HashMap<String, Binding>>,
+    /// Block new instructions are appended to. It never has a real
+    /// terminator yet: returning moves lowering on to a fresh block.
+    current: BlockId,
+    /// Statement being lowered; every instruction it produces points back
+    /// at it for diagnostics.
+    loc: SourceLoc,
+}
+
+impl<'a> LowerCtx<'a> {
+    fn lookup(&self, name: &str) -> Result<Binding, LoweringError> {
+        self.scopes
+            .iter()
+            .rev()
+            .find_map(|scope| scope.get(name).copied())
+            .ok_or_else(|| LoweringError::UnitLowering(format!("Undefined variable: {name}")))
+    }
+
+    fn constant(&self, name: &str) -> Option<i64> {
+        match self.lookup(name) {
+            Ok(Binding::Const(value)) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn declare(&mut self, name: &str, binding: Binding) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), binding);
+        }
+    }
+
+    fn map(&self, name: &str) -> Result<&MapDecl, LoweringError> {
+        self.maps
+            .iter()
+            .find(|m| m.name == name)
+            .ok_or_else(|| LoweringError::UnitLowering(format!("Unknown map: {name}")))
+    }
+
+    fn struct_decl(&self, name: &str) -> Result<&'a StructDecl, LoweringError> {
+        self.structs
+            .iter()
+            .find(|s| s.name == name)
+            .ok_or_else(|| LoweringError::UnitLowering(format!("Unknown struct: {name}")))
+    }
 }
 
 impl UnitIr {
-    pub fn lower(unit: &Unit) -> Result<Self, LoweringError> {
+    pub fn lower(unit: &Unit, maps: &[MapDecl], structs: &[StructDecl]) -> Result<Self, LoweringError> {
         let mut ir = Self {
             name: unit.name.clone(),
             sections: unit.sections.clone(),
             license: unit.license.clone().unwrap_or_else(|| "GPL".to_string()),
+            packet_fallback: packet_fallback(unit),
+            entry: BlockId(0),
             blocks: Vec::new(),
-            next_var_id: 0,
+            vars: Vec::new(),
         };
 
+        let entry = ir.new_block();
         let mut ctx = LowerCtx {
-            vars: std::collections::HashMap::new(),
-            map_ptr_vars: std::collections::HashSet::new(),
-        };
-
-        let mut current_block = BasicBlock {
-            id: BlockId(0),
-            instructions: Vec::new(),
-            terminator: Terminator::Return(Operand::Immediate(0)),
+            maps,
+            structs,
+            scopes: vec![HashMap::new()],
+            current: entry,
+            loc: unit.loc,
         };
 
         for stmt in &unit.body {
-            lower_statement(stmt, &mut ctx, &mut ir, &mut current_block)?;
+            lower_statement(stmt, &mut ctx, &mut ir)?;
         }
 
-        ir.blocks.push(current_block);
+        // Falling off the end of the unit returns 0, which is the
+        // placeholder terminator every open block already carries.
+        ir.remove_unreachable();
+        ir.compute_preds();
+        if ir.packet_fallback.is_some() {
+            insert_bounds_checks(&mut ir);
+        }
         Ok(ir)
     }
 
-    fn alloc_var(&mut self, _var_type: crate::ast::Type) -> VarId {
-        let id = VarId(self.next_var_id);
-        self.next_var_id += 1;
+    pub fn block(&self, id: BlockId) -> Option<&BasicBlock> {
+        self.blocks.iter().find(|b| b.id == id)
+    }
+
+    pub fn var(&self, id: VarId) -> &VarInfo {
+        &self.vars[id.0 as usize]
+    }
+
+    pub(super) fn alloc_var(&mut self, ty: impl Into<DataType>) -> VarId {
+        self.alloc_slot(ty, false, None)
+    }
+
+    fn alloc_slot(&mut self, ty: impl Into<DataType>, is_ptr: bool, name: Option<&str>) -> VarId {
+        let id = VarId(self.vars.len() as u32);
+        self.vars.push(VarInfo {
+            ty: ty.into(),
+            is_ptr,
+            name: name.map(str::to_string),
+        });
         id
     }
+
+    /// Scalar type of the value `op` holds; constants have none of their
+    /// own, and neither do struct slots.
+    fn operand_type(&self, op: &Operand) -> Option<Type> {
+        match op {
+            Operand::Var(v) => self.var(*v).ty.scalar(),
+            Operand::Immediate(_) => None,
+        }
+    }
+
+    fn new_block(&mut self) -> BlockId {
+        let id = BlockId(self.blocks.len() as u32);
+        self.blocks.push(BasicBlock {
+            id,
+            preds: Vec::new(),
+            instructions: Vec::new(),
+            terminator: Terminator::Return(Operand::Immediate(0)),
+        });
+        id
+    }
+
+    /// Only valid while lowering, when block ids are still vector indices.
+    fn block_mut(&mut self, id: BlockId) -> &mut BasicBlock {
+        &mut self.blocks[id.0 as usize]
+    }
+
+    fn remove_unreachable(&mut self) {
+        let mut reachable = vec![false; self.blocks.len()];
+        let mut stack = vec![self.entry];
+        while let Some(id) = stack.pop() {
+            let idx = id.0 as usize;
+            if reachable[idx] {
+                continue;
+            }
+            reachable[idx] = true;
+            stack.extend(self.blocks[idx].terminator.successors());
+        }
+
+        self.blocks.retain(|b| reachable[b.id.0 as usize]);
+    }
+
+    fn compute_preds(&mut self) {
+        let mut preds: HashMap<BlockId, Vec<BlockId>> = HashMap::new();
+        for block in &self.blocks {
+            for succ in block.terminator.successors() {
+                preds.entry(succ).or_default().push(block.id);
+            }
+        }
+        for block in &mut self.blocks {
+            block.preds = preds.remove(&block.id).unwrap_or_default();
+        }
+    }
+}
+
+fn packet_fallback(unit: &Unit) -> Option<String> {
+    let verdicts = unit.sections.first().and_then(|s| SectionValidator::packet_verdicts(s))?;
+    Some(unit.fallback.clone().unwrap_or_else(|| verdicts[0].to_string()))
 }
 
-fn lower_statement(
-    stmt: &Stmt,
-    ctx: &mut LowerCtx,
+/// Appends an instruction producing a fresh temporary to the current block.
+fn emit(
+    ctx: &LowerCtx<'_>,
     ir: &mut UnitIr,
-    block: &mut BasicBlock,
-) -> Result<(), LoweringError> {
+    opcode: Opcode,
+    operands: Vec<Operand>,
+    result_type: impl Into<DataType>,
+) -> VarId {
+    let result_type = result_type.into();
+    let result = ir.alloc_var(result_type.clone());
+    emit_into(ctx, ir, result, opcode, operands, result_type);
+    result
+}
+
+/// Appends an instruction writing `result`, which may be an existing slot.
+fn emit_into(
+    ctx: &LowerCtx<'_>,
+    ir: &mut UnitIr,
+    result: VarId,
+    opcode: Opcode,
+    operands: Vec<Operand>,
+    result_type: impl Into<DataType>,
+) {
+    ir.block_mut(ctx.current).instructions.push(Instruction {
+        result,
+        opcode,
+        operands,
+        result_type: result_type.into(),
+        loc: ctx.loc,
+    });
+}
+
+/// Ends the current block with `terminator` and continues lowering in a
+/// fresh block. Code that follows a `return` lands in a block nothing jumps
+/// to and is dropped once lowering is done.
+fn terminate(ctx: &mut LowerCtx<'_>, ir: &mut UnitIr, terminator: Terminator) {
+    ir.block_mut(ctx.current).terminator = terminator;
+    ctx.current = ir.new_block();
+}
+
+fn lower_block(stmts: &[Stmt], ctx: &mut LowerCtx<'_>, ir: &mut UnitIr) -> Result<(), LoweringError> {
+    ctx.scopes.push(HashMap::new());
+    for stmt in stmts {
+        lower_statement(stmt, ctx, ir)?;
+    }
+    ctx.scopes.pop();
+    Ok(())
+}
+
+fn lower_statement(stmt: &Stmt, ctx: &mut LowerCtx<'_>, ir: &mut UnitIr) -> Result<(), LoweringError> {
+    ctx.loc = stmt.loc;
     match &stmt.kind {
         StmtKind::VarDecl(var_decl) => {
-            let ty: crate::ast::Type = vartype_to_type(&var_decl.var_type)?;
+            if var_decl.var_type == VarType::Imm {
+                let value = var_decl
+                    .value
+                    .as_deref()
+                    .and_then(|value| const_eval(value, &|name| ctx.constant(name)))
+                    .ok_or_else(|| LoweringError::UnitLowering(format!("imm {} is not a constant", var_decl.name)))?;
+                ctx.declare(&var_decl.name, Binding::Const(value));
+                return Ok(());
+            }
 
-            let var_id = ir.alloc_var(ty.clone());
-            let value = lower_expr(&var_decl.value, ctx, ir, block)?;
+            // A buffer is declared without a value and starts out zeroed,
+            // every time control reaches the declaration.
+            if let Some(ty @ DataType::Array(..)) = &var_decl.ty {
+                let slot = ir.alloc_slot(ty.clone(), false, Some(&var_decl.name));
+                emit_into(ctx, ir, slot, Opcode::Zero, vec![], ty.clone());
+                ctx.declare(&var_decl.name, Binding::Slot(slot));
+                return Ok(());
+            }
 
-            ctx.vars.insert(var_decl.name.clone(), var_id);
+            let value_expr = var_decl
+                .value
+                .as_deref()
+                .ok_or_else(|| LoweringError::UnitLowering(format!("reg {} has no value", var_decl.name)))?;
+            let value = lower_expr(value_expr, ctx, ir)?;
+            let ty = match (var_decl.ty.as_ref().and_then(DataType::scalar), &value) {
+                (Some(ty), _) => ty,
+                (None, Operand::Var(_)) => ir.operand_type(&value).ok_or(LoweringError::InvalidOperand)?,
+                (None, Operand::Immediate(n)) => constant_type(*n),
+            };
 
-            block.instructions.push(Instruction {
-                result: var_id,
-                opcode: Opcode::Binary { op: BinaryOp::Add },
-                operands: vec![value, Operand::Immediate(0)],
-                result_type: ty,
-            });
+            let slot = ir.alloc_slot(ty, false, Some(&var_decl.name));
+            let (opcode, value) = match value {
+                Operand::Var(v) => {
+                    let from = ir.operand_type(&Operand::Var(v)).ok_or(LoweringError::InvalidOperand)?;
+                    (conversion(from, ty), Operand::Var(v))
+                }
+                Operand::Immediate(n) => (Opcode::Copy, Operand::Immediate(ty.wrap(n))),
+            };
+            emit_into(ctx, ir, slot, opcode, vec![value], ty);
+            ctx.declare(&var_decl.name, Binding::Slot(slot));
         }
 
         StmtKind::Return(expr) => {
-            let ret_value = lower_expr(expr, ctx, ir, block)?;
-            block.terminator = Terminator::Return(ret_value);
+            let ret_value = lower_expr(expr, ctx, ir)?;
+            terminate(ctx, ir, Terminator::Return(ret_value));
         }
 
         StmtKind::HeapVarDecl(heap_decl) => {
-            let key = lower_expr(&heap_decl.lookup.key_expr, ctx, ir, block)?;
-            let result = ir.alloc_var(crate::ast::Type::U64);
+            let slot = lower_map_call(&heap_decl.call, Some(&heap_decl.name), ctx, ir)?;
+            ctx.declare(&heap_decl.name, Binding::Slot(slot));
+        }
 
-            block.instructions.push(Instruction {
-                result,
-                opcode: Opcode::CallMap { map_name: heap_decl.lookup.map_name.clone() },
-                operands: vec![key],
-                result_type: crate::ast::Type::U64,
-            });
+        StmtKind::Expr(expr) => {
+            lower_expr(expr, ctx, ir)?;
+        }
 
-            ctx.vars.insert(heap_decl.name.clone(), result);
-            // Mark this variable as coming from a map lookup
-            ctx.map_ptr_vars.insert(result);
+        StmtKind::Assignment(assign) if assign.atomic => {
+            let op = match atomic_op(assign.op) {
+                Some(BinOp::Add) => AtomicOp::Add,
+                Some(BinOp::Sub) => AtomicOp::Sub,
+                Some(BinOp::BitAnd) => AtomicOp::And,
+                Some(BinOp::BitOr) => AtomicOp::Or,
+                Some(BinOp::BitXor) => AtomicOp::Xor,
+                _ => return Err(LoweringError::UnitLowering("Invalid atomic operator".to_string())),
+            };
+            let place = lower_atomic_place(&assign.target, ctx, ir)?;
+            let value = lower_expr(&assign.value, ctx, ir)?;
+            emit_atomic(ctx, ir, op, place, vec![value]);
         }
 
         StmtKind::Assignment(assign) => {
-            let value = lower_expr(&assign.value, ctx, ir, block)?;
-            
+            let value = lower_expr(&assign.value, ctx, ir)?;
+
             match &assign.target.kind {
                 ExprKind::Dereference(ptr_expr) => {
-                    let ptr = lower_expr(ptr_expr, ctx, ir, block)?;
-                    
-                    // If the pointer is a map pointer, we need to insert a null check
-                    let needs_null_check = if let Operand::Var(ptr_var) = ptr {
-                        ctx.map_ptr_vars.contains(&ptr_var)
-                    } else {
-                        false
+                    let ptr = lower_expr(ptr_expr, ctx, ir)?;
+                    let pointee = ir.operand_type(&ptr).ok_or(LoweringError::InvalidOperand)?;
+
+                    // A compound assignment loads the current value, applies
+                    // the operator, then stores
+                    let final_value = match assign.op.binary_op() {
+                        Some(op) => {
+                            let current = emit(ctx, ir, Opcode::LoadKey, vec![ptr.clone()], pointee);
+                            let result = emit(
+                                ctx,
+                                ir,
+                                Opcode::Binary { op: binary_op(op) },
+                                vec![Operand::Var(current), value],
+                                pointee,
+                            );
+                            Operand::Var(result)
+                        }
+                        None => value,
                     };
-                    
-                    if needs_null_check {
-                        // Emit a NullCheck instruction
-                        let null_check_result = ir.alloc_var(crate::ast::Type::U64);
-                        block.instructions.push(Instruction {
-                            result: null_check_result,
-                            opcode: Opcode::NullCheck,
-                            operands: vec![ptr.clone()],
-                            result_type: crate::ast::Type::U64,
-                        });
-                    }
-                    
-                    // For +=, we need to load the current value, add, then store
-                    let final_value = if assign.op == crate::ast::AssignmentOp::AddAssign {
-                        // Load current value
-                        let load_result = ir.alloc_var(crate::ast::Type::U64);
-                        block.instructions.push(Instruction {
-                            result: load_result,
-                            opcode: Opcode::LoadKey,
-                            operands: vec![ptr.clone()],
-                            result_type: crate::ast::Type::U64,
-                        });
-                        
-                        // Add the new value to it
-                        let add_result = ir.alloc_var(crate::ast::Type::U64);
-                        block.instructions.push(Instruction {
-                            result: add_result,
-                            opcode: Opcode::Binary { op: BinaryOp::Add },
-                            operands: vec![Operand::Var(load_result), value],
-                            result_type: crate::ast::Type::U64,
-                        });
-                        
-                        Operand::Var(add_result)
-                    } else {
-                        value
+
+                    emit(ctx, ir, Opcode::Store { size: pointee.size() }, vec![ptr, final_value], pointee);
+                }
+                ExprKind::Field(access) => {
+                    let (ptr, field_ty) = lower_field_base(access, ctx, ir)?;
+                    let field_ty = field_ty.scalar().ok_or(LoweringError::InvalidOperand)?;
+                    let field = access.field.clone();
+
+                    let final_value = match assign.op.binary_op() {
+                        Some(op) => {
+                            let current =
+                                emit(ctx, ir, Opcode::LoadField { field: field.clone() }, vec![ptr.clone()], field_ty);
+                            let result = emit(
+                                ctx,
+                                ir,
+                                Opcode::Binary { op: binary_op(op) },
+                                vec![Operand::Var(current), value],
+                                field_ty,
+                            );
+                            Operand::Var(result)
+                        }
+                        None => value,
+                    };
+
+                    emit(ctx, ir, Opcode::StoreField { field }, vec![ptr, final_value], field_ty);
+                }
+                ExprKind::Index(index_expr) => {
+                    let (base, field, elem) = lower_index_base(index_expr, ctx, ir)?;
+                    let index = lower_expr(&index_expr.index, ctx, ir)?;
+                    let value = convert(ctx, ir, value, elem);
+
+                    let final_value = match assign.op.binary_op() {
+                        Some(op) => {
+                            let current = emit(
+                                ctx,
+                                ir,
+                                Opcode::LoadIndex { field: field.clone() },
+                                vec![base.clone(), index.clone()],
+                                elem,
+                            );
+                            let result = emit(
+                                ctx,
+                                ir,
+                                Opcode::Binary { op: binary_op(op) },
+                                vec![Operand::Var(current), value],
+                                elem,
+                            );
+                            Operand::Var(result)
+                        }
+                        None => value,
                     };
-                    
-                    let _result = ir.alloc_var(crate::ast::Type::U64);
-                    
-                    block.instructions.push(Instruction {
-                        result: _result,
-                        opcode: Opcode::Store { size: 8 },
-                        operands: vec![ptr, final_value],
-                        result_type: crate::ast::Type::U64,
-                    });
+
+                    emit(ctx, ir, Opcode::StoreIndex { field }, vec![base, index, final_value], elem);
                 }
                 ExprKind::Variable(var_name) => {
-                    if ctx.vars.contains_key(var_name) {
-                        let var_id = ctx.vars.get(var_name).copied().unwrap();
-                        
-                        let final_value = if assign.op == crate::ast::AssignmentOp::AddAssign {
-                            let add_result = ir.alloc_var(crate::ast::Type::U64);
-                            block.instructions.push(Instruction {
-                                result: add_result,
-                                opcode: Opcode::Binary { op: BinaryOp::Add },
-                                operands: vec![Operand::Var(var_id), value],
-                                result_type: crate::ast::Type::U64,
-                            });
-                            add_result
-                        } else {
-                            let result = ir.alloc_var(crate::ast::Type::U64);
-                            block.instructions.push(Instruction {
-                                result,
-                                opcode: Opcode::Binary { op: BinaryOp::Add },
-                                operands: vec![value, Operand::Immediate(0)],
-                                result_type: crate::ast::Type::U64,
-                            });
-                            result
-                        };
-                        
-                        ctx.vars.insert(var_name.clone(), final_value);
-                    } else {
-                        return Err(LoweringError::UnitLowering(format!("Undefined variable: {var_name}")));
+                    let Binding::Slot(slot) = ctx.lookup(var_name)? else {
+                        return Err(LoweringError::UnitLowering(format!("Cannot assign to imm {var_name}")));
+                    };
+                    let ty = ir.var(slot).ty.clone();
+
+                    match assign.op.binary_op() {
+                        Some(op) => emit_into(
+                            ctx,
+                            ir,
+                            slot,
+                            Opcode::Binary { op: binary_op(op) },
+                            vec![Operand::Var(slot), value],
+                            ty,
+                        ),
+                        None => emit_into(ctx, ir, slot, Opcode::Copy, vec![value], ty),
                     }
                 }
                 _ => {
@@ -214,189 +474,764 @@ fn lower_statement(
             }
         }
 
-        StmtKind::IfGuard(if_guard) => {
-            let guard_expr = &if_guard.condition;
-            let guard_var = match &guard_expr.kind {
-                ExprKind::Variable(name) => {
-                    ctx.vars.get(name).copied().ok_or_else(|| {
-                        LoweringError::UnitLowering(format!("Undefined variable in guard: {name}"))
-                    })?
-                }
-                _ => {
-                    return Err(LoweringError::UnitLowering("Guard must be a variable".to_string()));
-                }
-            };
-            
-            let null_check_result = ir.alloc_var(crate::ast::Type::U64);
-            block.instructions.push(Instruction {
-                result: null_check_result,
-                opcode: Opcode::NullCheck,
-                operands: vec![Operand::Var(guard_var)],
-                result_type: crate::ast::Type::U64,
-            });
-            
-            let true_block_id = BlockId(ir.blocks.len() as u32);
-            let false_block_id = BlockId(ir.blocks.len() as u32 + 1);
-            let merge_block_id = BlockId(ir.blocks.len() as u32 + 2);
-
-            block.terminator = Terminator::Branch {
-                condition: Operand::Var(null_check_result),
-                true_block: true_block_id,
-                false_block: false_block_id,
-            };
-            let mut true_block = BasicBlock {
-                id: true_block_id,
-                instructions: Vec::new(),
-                terminator: Terminator::Jump(merge_block_id),
-            };
-            
-            for stmt in &if_guard.body {
-                lower_statement(stmt, ctx, ir, &mut true_block)?;
-            }
-            
-            
-            let false_block = BasicBlock {
-                id: false_block_id,
-                instructions: Vec::new(),
-                terminator: Terminator::Jump(merge_block_id),
-            };
-            
-            let merge_block = BasicBlock {
-                id: merge_block_id,
-                instructions: Vec::new(),
-                terminator: Terminator::Return(Operand::Immediate(0)),
-            };
-            
-            ir.blocks.push(true_block);
-            ir.blocks.push(false_block);
-            ir.blocks.push(merge_block.clone());
-            
-            *block = merge_block;
-        }
+        StmtKind::IfGuard(if_guard) => lower_if_guard(if_guard, ctx, ir)?,
+
+        StmtKind::For(for_loop) => lower_for(for_loop, stmt.loc, ctx, ir)?,
 
+        StmtKind::TailCall(call) => {
+            let index = lower_expr(&call.index, ctx, ir)?;
+            let index = convert(ctx, ir, index, Type::U32);
+            emit(ctx, ir, Opcode::TailCall { map_name: call.map_name.clone() }, vec![index], Type::I64);
+        }
     }
     Ok(())
 }
 
-fn lower_expr(
-    expr: &Expr,
-    ctx: &mut LowerCtx,
+fn lower_if_guard(if_guard: &IfGuard, ctx: &mut LowerCtx<'_>, ir: &mut UnitIr) -> Result<(), LoweringError> {
+    let true_block = ir.new_block();
+    let false_block = ir.new_block();
+    let merge_block = ir.new_block();
+
+    lower_branch(&if_guard.condition, true_block, false_block, ctx, ir)?;
+
+    ctx.current = true_block;
+    lower_block(&if_guard.body, ctx, ir)?;
+    ir.block_mut(ctx.current).terminator = Terminator::Jump(merge_block);
+
+    ctx.current = false_block;
+    lower_block(&if_guard.else_body, ctx, ir)?;
+    ir.block_mut(ctx.current).terminator = Terminator::Jump(merge_block);
+
+    ctx.current = merge_block;
+    Ok(())
+}
+
+/// Lowers `condition` as a branch to `true_block` or `false_block`.
+///
+/// `&&` and `||` short-circuit: the right operand gets a block of its own,
+/// reached only when the left one does not decide the result, so a pointer
+/// tested on the left is known non-null on the right.
+fn lower_branch(
+    condition: &Expr,
+    true_block: BlockId,
+    false_block: BlockId,
+    ctx: &mut LowerCtx<'_>,
     ir: &mut UnitIr,
-    block: &mut BasicBlock,
-) -> Result<Operand, LoweringError> {
+) -> Result<(), LoweringError> {
+    if let Some(bin) = short_circuit(condition, ctx) {
+        let right_block = ir.new_block();
+        match bin.op {
+            BinOp::And => lower_branch(&bin.left, right_block, false_block, ctx, ir)?,
+            _ => lower_branch(&bin.left, true_block, right_block, ctx, ir)?,
+        }
+        ctx.current = right_block;
+        return lower_branch(&bin.right, true_block, false_block, ctx, ir);
+    }
+
+    // Guarding a `heap` pointer means "the lookup succeeded"; any other
+    // expression is an ordinary boolean condition.
+    let condition = match lower_expr(condition, ctx, ir)? {
+        Operand::Var(ptr) if ir.var(ptr).is_ptr => {
+            Operand::Var(emit(ctx, ir, Opcode::NullCheck, vec![Operand::Var(ptr)], Type::U32))
+        }
+        other => other,
+    };
+    ir.block_mut(ctx.current).terminator = Terminator::Branch {
+        condition,
+        true_block,
+        false_block,
+    };
+    Ok(())
+}
+
+/// Returns the `&&` or `||` that `expr` is, unless it folds to a constant.
+fn short_circuit<'e>(expr: &'e Expr, ctx: &LowerCtx<'_>) -> Option<&'e BinaryExpr> {
     match &expr.kind {
-        ExprKind::Variable(name) => {
-            let v = ctx.vars.get(name).copied().ok_or_else(|| {
-                LoweringError::UnitLowering(format!("Undefined variable: {name}"))
-            })?;
-            Ok(Operand::Var(v))
+        ExprKind::Binary(bin) if matches!(bin.op, BinOp::And | BinOp::Or) => {
+            const_eval(expr, &|name| ctx.constant(name)).is_none().then_some(bin)
         }
+        _ => None,
+    }
+}
+
+/// Lowers a counted loop to a header that tests the counter, the body, and
+/// a latch that increments it and jumps back to the header.
+fn lower_for(for_loop: &ForLoop, loc: SourceLoc, ctx: &mut LowerCtx<'_>, ir: &mut UnitIr) -> Result<(), LoweringError> {
+    let bound = |expr: &Expr| {
+        const_eval(expr, &|name| ctx.constant(name))
+            .ok_or_else(|| LoweringError::UnitLowering("Loop bound is not a constant".to_string()))
+    };
+    let start = bound(&for_loop.start)?;
+    let end = bound(&for_loop.end)?;
+    let ty = loop_counter_type(start, end);
+
+    ctx.scopes.push(HashMap::new());
+    let counter = ir.alloc_slot(ty, false, Some(&for_loop.var));
+    emit_into(ctx, ir, counter, Opcode::Copy, vec![Operand::Immediate(start)], ty);
+    ctx.declare(&for_loop.var, Binding::Slot(counter));
+
+    let header = ir.new_block();
+    let body = ir.new_block();
+    let exit = ir.new_block();
+    ir.block_mut(ctx.current).terminator = Terminator::Jump(header);
+
+    ctx.current = header;
+    let condition = emit(
+        ctx,
+        ir,
+        Opcode::Binary { op: BinaryOp::Lt },
+        vec![Operand::Var(counter), Operand::Immediate(end)],
+        Type::U32,
+    );
+    ir.block_mut(ctx.current).terminator = Terminator::Branch {
+        condition: Operand::Var(condition),
+        true_block: body,
+        false_block: exit,
+    };
+
+    ctx.current = body;
+    lower_block(&for_loop.body, ctx, ir)?;
+    ctx.loc = loc;
+    emit_into(
+        ctx,
+        ir,
+        counter,
+        Opcode::Binary { op: BinaryOp::Add },
+        vec![Operand::Var(counter), Operand::Immediate(1)],
+        ty,
+    );
+    ir.block_mut(ctx.current).terminator = Terminator::Jump(header);
+    ctx.scopes.pop();
+
+    ctx.current = exit;
+    Ok(())
+}
+
+fn lower_expr(expr: &Expr, ctx: &mut LowerCtx<'_>, ir: &mut UnitIr) -> Result<Operand, LoweringError> {
+    match &expr.kind {
+        ExprKind::Variable(name) => match ctx.lookup(name)? {
+            Binding::Slot(v) => Ok(Operand::Var(v)),
+            Binding::Const(n) => Ok(Operand::Immediate(n)),
+        },
 
         ExprKind::Number(n) => Ok(Operand::Immediate(*n)),
 
         ExprKind::MethodCall(call) => {
             if call.receiver == "ctx" {
-                let offset_expr = lower_expr(&call.arg, ctx, ir, block)?;
-                let offset = match offset_expr {
+                let offset = match lower_expr(&call.args[0], ctx, ir)? {
                     Operand::Immediate(n) => n as i32,
                     _ => return Err(LoweringError::UnitLowering("Context load offset must be immediate".to_string())),
                 };
-                
-                let (size, result_type) = match call.method.as_str() {
-                    "load_u8" => (1, crate::ast::Type::U32),
-                    "load_u16" => (2, crate::ast::Type::U32),
-                    "load_u32" => (4, crate::ast::Type::U32),
-                    "load_u64" => (8, crate::ast::Type::U64),
-                    "load_i8" => (1, crate::ast::Type::I32),
-                    "load_i16" => (2, crate::ast::Type::I32),
-                    "load_i32" => (4, crate::ast::Type::I32),
-                    "load_i64" => (8, crate::ast::Type::I64),
-                    _ => return Err(LoweringError::UnitLowering(format!("Unknown context method: {}", call.method))),
-                };
-                
-                let result = ir.alloc_var(result_type);
-                
-                // Check if this is a packet data load (offset >= 0) or context field load
-                let is_packet = offset >= 0;
+
+                let (size, result_type) = ctx_load(&call.method).ok_or_else(|| {
+                    LoweringError::UnitLowering(format!("Unknown context method: {}", call.method))
+                })?;
+
+                // Packet data is only reachable directly in some program
+                // types; everywhere else, and for negative offsets, the load
+                // reads a context field.
+                let is_packet = offset >= 0 && ir.packet_fallback.is_some();
                 let opcode = if is_packet {
                     Opcode::LoadPacket { offset, size }
                 } else {
                     Opcode::LoadCtx { offset, size }
                 };
-                
-                block.instructions.push(Instruction {
-                    result,
-                    opcode,
-                    operands: vec![],
-                    result_type,
-                });
-                
-                Ok(Operand::Var(result))
-            } else if call.method == "lookup" {
-                let key = lower_expr(&call.arg, ctx, ir, block)?;
-                let result = ir.alloc_var(crate::ast::Type::U64);
-
-                block.instructions.push(Instruction {
-                    result,
-                    opcode: Opcode::CallMap { map_name: call.receiver.clone() },
-                    operands: vec![key],
-                    result_type: crate::ast::Type::U64,
-                });
 
+                let result = emit(ctx, ir, opcode, vec![], result_type);
                 Ok(Operand::Var(result))
             } else {
-                Err(LoweringError::UnitLowering(format!("Unknown method: {}.{}", call.receiver, call.method)))
+                let result = lower_map_call(call, None, ctx, ir)?;
+                Ok(Operand::Var(result))
             }
         }
-        
+
         ExprKind::Dereference(ptr_expr) => {
-            let ptr = lower_expr(ptr_expr, ctx, ir, block)?;
-            let result = ir.alloc_var(crate::ast::Type::U64);
-            
-            block.instructions.push(Instruction {
-                result,
-                opcode: Opcode::LoadKey,
-                operands: vec![ptr],
-                result_type: crate::ast::Type::U64,
-            });
-            
+            let ptr = lower_expr(ptr_expr, ctx, ir)?;
+            let pointee = ir.operand_type(&ptr).ok_or(LoweringError::InvalidOperand)?;
+            let result = emit(ctx, ir, Opcode::LoadKey, vec![ptr], pointee);
+            Ok(Operand::Var(result))
+        }
+
+        ExprKind::Binary(_) if short_circuit(expr, ctx).is_some() => {
+            let result = ir.alloc_slot(Type::U32, false, None);
+            let true_block = ir.new_block();
+            let false_block = ir.new_block();
+            let merge_block = ir.new_block();
+            lower_branch(expr, true_block, false_block, ctx, ir)?;
+
+            for (block, value) in [(true_block, 1), (false_block, 0)] {
+                ctx.current = block;
+                emit_into(ctx, ir, result, Opcode::Copy, vec![Operand::Immediate(value)], Type::U32);
+                ir.block_mut(ctx.current).terminator = Terminator::Jump(merge_block);
+            }
+            ctx.current = merge_block;
             Ok(Operand::Var(result))
         }
 
         ExprKind::Binary(bin) => {
-            let left = lower_expr(&bin.left, ctx, ir, block)?;
-            let right = lower_expr(&bin.right, ctx, ir, block)?;
+            let left = lower_expr(&bin.left, ctx, ir)?;
+            let right = lower_expr(&bin.right, ctx, ir)?;
+
+            if let (Operand::Immediate(l), Operand::Immediate(r)) = (&left, &right) {
+                if let Some(n) = fold_binary(bin.op, *l, *r) {
+                    return Ok(Operand::Immediate(n));
+                }
+            }
+
+            let op = binary_op(bin.op);
+
+            // Operands were unified by the type checker: a constant takes the
+            // other side's type and two typed operands widen to the larger.
+            // A shift keeps the type of the value being shifted.
+            let result_type = if is_boolean_op(bin.op) {
+                Type::U32
+            } else if let (true, Some(t)) = (is_shift_op(bin.op), ir.operand_type(&left)) {
+                t
+            } else {
+                match (ir.operand_type(&left), ir.operand_type(&right)) {
+                    (Some(l), Some(r)) => l.common(r).unwrap_or(l),
+                    (Some(t), None) | (None, Some(t)) => t,
+                    (None, None) => Type::U64,
+                }
+            };
+
+            let result = emit(ctx, ir, Opcode::Binary { op }, vec![left, right], result_type);
+            Ok(Operand::Var(result))
+        }
+
+        ExprKind::Unary(unary) => {
+            let operand = lower_expr(&unary.operand, ctx, ir)?;
 
-            let result = ir.alloc_var(crate::ast::Type::U64);
+            if let Operand::Immediate(n) = operand {
+                return Ok(Operand::Immediate(fold_unary(unary.op, n)));
+            }
 
-            let op = match bin.op {
-                crate::ast::BinOp::Add => BinaryOp::Add,
-                crate::ast::BinOp::Sub => BinaryOp::Sub,
-                crate::ast::BinOp::Mul => BinaryOp::Mul,
-                crate::ast::BinOp::Div => BinaryOp::Div,
-                crate::ast::BinOp::Mod => BinaryOp::Mod,
+            let (op, result_type) = match unary.op {
+                crate::ast::UnaryOp::Not => (UnaryOp::Not, Type::U32),
+                crate::ast::UnaryOp::BitNot => {
+                    (UnaryOp::BitNot, ir.operand_type(&operand).ok_or(LoweringError::InvalidOperand)?)
+                }
             };
 
-            block.instructions.push(Instruction {
-                result,
-                opcode: Opcode::Binary { op },
-                operands: vec![left, right],
-                result_type: crate::ast::Type::U64,
-            });
+            let result = emit(ctx, ir, Opcode::Unary { op }, vec![operand], result_type);
+            Ok(Operand::Var(result))
+        }
 
+        ExprKind::Cast(cast) => {
+            // Unlike a bare constant, a cast constant has a fixed type, so it
+            // gets a typed slot of its own.
+            match lower_expr(&cast.expr, ctx, ir)? {
+                Operand::Immediate(n) => {
+                    let value = Operand::Immediate(cast.ty.wrap(n));
+                    Ok(Operand::Var(emit(ctx, ir, Opcode::Copy, vec![value], cast.ty)))
+                }
+                value => Ok(convert(ctx, ir, value, cast.ty)),
+            }
+        }
+
+        ExprKind::Field(access) => {
+            let (ptr, field_ty) = lower_field_base(access, ctx, ir)?;
+            let field = access.field.clone();
+            let result = emit(ctx, ir, Opcode::LoadField { field }, vec![ptr], field_ty);
+            Ok(Operand::Var(result))
+        }
+
+        ExprKind::Index(index_expr) => {
+            let (base, field, elem) = lower_index_base(index_expr, ctx, ir)?;
+            let index = lower_expr(&index_expr.index, ctx, ir)?;
+            let result = emit(ctx, ir, Opcode::LoadIndex { field }, vec![base, index], elem);
             Ok(Operand::Var(result))
         }
 
+        ExprKind::StructLiteral(literal) => Ok(Operand::Var(lower_struct_literal(literal, ctx, ir)?)),
+
+        ExprKind::Cidr(_) => Err(LoweringError::UnitLowering("CIDR literal outside an lpm_trie key".to_string())),
+
+        ExprKind::Redirect(redirect) => Ok(Operand::Var(lower_redirect(redirect, ctx, ir)?)),
+
+        ExprKind::Atomic(atomic) => {
+            let op = match atomic.op {
+                crate::ast::AtomicOp::Xchg => AtomicOp::Xchg,
+                crate::ast::AtomicOp::Cmpxchg => AtomicOp::Cmpxchg,
+            };
+            let place = lower_atomic_place(&atomic.place, ctx, ir)?;
+            let args = atomic
+                .args
+                .iter()
+                .map(|arg| lower_expr(arg, ctx, ir))
+                .collect::<Result<Vec<_>, _>>()?;
+            Ok(Operand::Var(emit_atomic(ctx, ir, op, place, args)))
+        }
+    }
+}
+
+/// Lowers `redirect(map, key, fallback)` to the helper for this program
+/// type. The fallback was checked to be a constant verdict.
+fn lower_redirect(redirect: &Redirect, ctx: &mut LowerCtx<'_>, ir: &mut UnitIr) -> Result<VarId, LoweringError> {
+    let map = ctx.map(&redirect.map_name)?;
+    let map_type = map.map_type;
+    let key_type = map
+        .key_type
+        .clone()
+        .ok_or_else(|| LoweringError::UnitLowering(format!("Map {} has no key type", redirect.map_name)))?;
+
+    let program_type = ir.sections.first().and_then(|s| SectionValidator::program_type(s));
+    let helper = match (program_type, map_type) {
+        (Some("sk_skb"), MapType::Sockhash) => RedirectHelper::SkHash,
+        (Some("sk_skb"), _) => RedirectHelper::SkMap,
+        (Some("sk_msg"), MapType::Sockhash) => RedirectHelper::MsgHash,
+        (Some("sk_msg"), _) => RedirectHelper::MsgMap,
+        _ => RedirectHelper::Xdp,
+    };
+
+    let key = match helper {
+        RedirectHelper::SkHash | RedirectHelper::MsgHash => lower_map_operand(&redirect.key, &key_type, ctx, ir)?,
+        _ => {
+            let key = lower_expr(&redirect.key, ctx, ir)?;
+            convert(ctx, ir, key, Type::U32)
+        }
+    };
+    let section = ir.sections.first().map_or("", |s| s.as_str());
+    let fallback = fallback_value(&redirect.fallback, section, &|name| ctx.constant(name))
+        .ok_or_else(|| LoweringError::UnitLowering("Redirect fallback must be a constant".to_string()))?;
+
+    let opcode = Opcode::Redirect { map_name: redirect.map_name.clone(), helper, fallback };
+    Ok(emit(ctx, ir, opcode, vec![key], Type::I64))
+}
+
+/// Lowers the pointer of `ptr.field` and returns it with the field's type.
+fn lower_field_base(
+    access: &FieldAccess,
+    ctx: &mut LowerCtx<'_>,
+    ir: &mut UnitIr,
+) -> Result<(Operand, DataType), LoweringError> {
+    let ptr = lower_expr(&access.base, ctx, ir)?;
+    let Operand::Var(v) = ptr else {
+        return Err(LoweringError::InvalidOperand);
+    };
+    let DataType::Struct(name) = &ir.var(v).ty else {
+        return Err(LoweringError::InvalidOperand);
+    };
+    let field = ctx
+        .struct_decl(name)?
+        .field(&access.field)
+        .ok_or_else(|| LoweringError::UnitLowering(format!("Unknown field: {}.{}", name, access.field)))?;
+    Ok((ptr, field.ty.clone()))
+}
+
+/// Lowers the array of `base[index]`: the array slot or pointer, the
+/// field holding the array when the base is `ptr.field`, and the element
+/// type.
+fn lower_index_base(
+    index: &IndexExpr,
+    ctx: &mut LowerCtx<'_>,
+    ir: &mut UnitIr,
+) -> Result<(Operand, Option<String>, Type), LoweringError> {
+    let (base, field, ty) = match &index.base.kind {
+        ExprKind::Field(access) => {
+            let (ptr, field_ty) = lower_field_base(access, ctx, ir)?;
+            (ptr, Some(access.field.clone()), field_ty)
+        }
+        _ => match lower_expr(&index.base, ctx, ir)? {
+            Operand::Var(v) => (Operand::Var(v), None, ir.var(v).ty.clone()),
+            Operand::Immediate(_) => return Err(LoweringError::InvalidOperand),
+        },
+    };
+    match ty {
+        DataType::Array(elem, _) => Ok((base, field, elem)),
         _ => Err(LoweringError::InvalidOperand),
     }
 }
 
+/// A map-value word an atomic instruction operates on, addressed as by
+/// [`Opcode::Atomic`].
+struct AtomicPlace {
+    operands: Vec<Operand>,
+    field: Option<String>,
+    indexed: bool,
+    ty: Type,
+}
+
+fn lower_atomic_place(place: &Expr, ctx: &mut LowerCtx<'_>, ir: &mut UnitIr) -> Result<AtomicPlace, LoweringError> {
+    match &place.kind {
+        ExprKind::Dereference(ptr_expr) => {
+            let ptr = lower_expr(ptr_expr, ctx, ir)?;
+            let ty = ir.operand_type(&ptr).ok_or(LoweringError::InvalidOperand)?;
+            Ok(AtomicPlace { operands: vec![ptr], field: None, indexed: false, ty })
+        }
+        ExprKind::Field(access) => {
+            let (ptr, field_ty) = lower_field_base(access, ctx, ir)?;
+            let ty = field_ty.scalar().ok_or(LoweringError::InvalidOperand)?;
+            Ok(AtomicPlace { operands: vec![ptr], field: Some(access.field.clone()), indexed: false, ty })
+        }
+        ExprKind::Index(index_expr) => {
+            let (base, field, ty) = lower_index_base(index_expr, ctx, ir)?;
+            let index = lower_expr(&index_expr.index, ctx, ir)?;
+            Ok(AtomicPlace { operands: vec![base, index], field, indexed: true, ty })
+        }
+        _ => Err(LoweringError::UnitLowering("Invalid atomic target".to_string())),
+    }
+}
+
+/// Emits `op` on `place` with `args` converted to its type, returning the
+/// slot that receives the previous value.
+fn emit_atomic(ctx: &LowerCtx<'_>, ir: &mut UnitIr, op: AtomicOp, place: AtomicPlace, args: Vec<Operand>) -> VarId {
+    let mut operands = place.operands;
+    for arg in args {
+        operands.push(convert(ctx, ir, arg, place.ty));
+    }
+    let opcode = Opcode::Atomic {
+        op,
+        field: place.field,
+        indexed: place.indexed,
+    };
+    emit(ctx, ir, opcode, operands, place.ty)
+}
 
-fn vartype_to_type(vt: &crate::ast::VarType) -> Result<crate::ast::Type, LoweringError> {
-    use crate::ast::{Type, VarType};
+/// Builds a struct in a fresh slot, one field at a time. The slot starts
+/// zeroed and every field is set, so no byte of it is left undefined.
+fn lower_struct_literal(literal: &StructLiteral, ctx: &mut LowerCtx<'_>, ir: &mut UnitIr) -> Result<VarId, LoweringError> {
+    let decl = ctx.struct_decl(&literal.name)?;
+    let ty = DataType::Struct(literal.name.clone());
+    let slot = ir.alloc_var(ty.clone());
 
-    match vt {
-        VarType::Reg => Ok(Type::U64),
-        VarType::Imm => Ok(Type::U64),
+    for init in &literal.fields {
+        let field_ty = &decl
+            .field(&init.name)
+            .ok_or_else(|| LoweringError::UnitLowering(format!("Unknown field: {}.{}", literal.name, init.name)))?
+            .ty;
+        let value = lower_expr(&init.value, ctx, ir)?;
+        let value = match field_ty.scalar() {
+            Some(field_ty) => convert(ctx, ir, value, field_ty),
+            None => value,
+        };
+        emit_into(ctx, ir, slot, Opcode::SetField { field: init.name.clone() }, vec![value], ty.clone());
+    }
+    Ok(slot)
+}
+
+fn binary_op(op: BinOp) -> BinaryOp {
+    match op {
+        BinOp::Add => BinaryOp::Add,
+        BinOp::Sub => BinaryOp::Sub,
+        BinOp::Mul => BinaryOp::Mul,
+        BinOp::Div => BinaryOp::Div,
+        BinOp::Mod => BinaryOp::Mod,
+        BinOp::Eq => BinaryOp::Eq,
+        BinOp::Ne => BinaryOp::Ne,
+        BinOp::Lt => BinaryOp::Lt,
+        BinOp::Le => BinaryOp::Le,
+        BinOp::Gt => BinaryOp::Gt,
+        BinOp::Ge => BinaryOp::Ge,
+        BinOp::And => BinaryOp::And,
+        BinOp::Or => BinaryOp::Or,
+        BinOp::BitAnd => BinaryOp::BitAnd,
+        BinOp::BitOr => BinaryOp::BitOr,
+        BinOp::BitXor => BinaryOp::BitXor,
+        BinOp::Shl => BinaryOp::Shl,
+        BinOp::Shr => BinaryOp::Shr,
     }
-}
\ No newline at end of file
+}
+
+/// The opcode that turns a `from` value into a `to` value. Conversions
+/// between types of the same width only reinterpret the bits.
+fn conversion(from: Type, to: Type) -> Opcode {
+    if from.size() > to.size() {
+        Opcode::Trunc
+    } else if from.size() == to.size() {
+        Opcode::Copy
+    } else if from.is_signed() {
+        Opcode::SignExtend
+    } else {
+        Opcode::ZeroExtend
+    }
+}
+
+/// `value` as a `ty`, converting into a fresh temporary if it is not one
+/// already.
+fn convert(ctx: &LowerCtx<'_>, ir: &mut UnitIr, value: Operand, ty: Type) -> Operand {
+    match value {
+        Operand::Immediate(n) => Operand::Immediate(ty.wrap(n)),
+        Operand::Var(v) => match ir.var(v).ty.scalar() {
+            Some(from) if from != ty => {
+                let opcode = conversion(from, ty);
+                Operand::Var(emit(ctx, ir, opcode, vec![Operand::Var(v)], ty))
+            }
+            _ => Operand::Var(v),
+        },
+    }
+}
+
+/// Lowers a map method call. `lookup`, `lookup_or_init`, `pop`, `peek` and
+/// `reserve` produce a pointer slot, named `name` when it backs a `heap`
+/// binding; the others produce the helper's return code.
+fn lower_map_call(
+    call: &MethodCall,
+    name: Option<&str>,
+    ctx: &mut LowerCtx<'_>,
+    ir: &mut UnitIr,
+) -> Result<VarId, LoweringError> {
+    let (map_type, max_entries, key_type, value_type, inner) = {
+        let map = ctx.map(&call.receiver)?;
+        (map.map_type, map.max_entries, map.key_type.clone(), map.value_type.clone(), map.inner.clone())
+    };
+    let map_name = call.receiver.clone();
+    let arg = |i: usize| {
+        call.args
+            .get(i)
+            .ok_or_else(|| LoweringError::UnitLowering(format!("Missing argument to {}.{}", map_name, call.method)))
+    };
+    let key = |ctx: &mut LowerCtx<'_>, ir: &mut UnitIr| match &key_type {
+        Some(key_type) => lower_map_operand(arg(0)?, key_type, ctx, ir),
+        None => Err(LoweringError::UnitLowering(format!("Map {} has no key", map_name))),
+    };
+    let value_type = || {
+        value_type
+            .clone()
+            .ok_or_else(|| LoweringError::UnitLowering(format!("Map {} has no value type", map_name)))
+    };
+
+    let (opcode, operands, pointee) = match call.method.as_str() {
+        "lookup" => match (&inner, &call.inner_key) {
+            (Some(inner), Some(inner_key)) => {
+                // The outer lookup yields the inner map itself, which the
+                // program only passes back to the helper.
+                let key = key(ctx, ir)?;
+                let inner_map = ir.alloc_slot(Type::U32, true, None);
+                let opcode = Opcode::CallMap { map_name: map_name.clone(), in_bounds: false };
+                emit_into(ctx, ir, inner_map, opcode, vec![key], Type::U32);
+
+                let (inner_key_type, inner_value_type) = {
+                    let prototype = ctx.map(inner)?;
+                    (prototype.key_type.clone(), prototype.value_type.clone())
+                };
+                let missing = || LoweringError::UnitLowering(format!("Inner map {} has no key or value", inner));
+                let inner_key = lower_map_operand(inner_key, &inner_key_type.ok_or_else(missing)?, ctx, ir)?;
+                (Opcode::LookupInner, vec![Operand::Var(inner_map), inner_key], inner_value_type.ok_or_else(missing)?)
+            }
+            _ => {
+                // Every index below `max` of an array holds a value, so a
+                // lookup of a constant one cannot fail.
+                let in_bounds = matches!(map_type, MapType::Array | MapType::PercpuArray)
+                    && const_eval(arg(0)?, &|name| ctx.constant(name))
+                        .is_some_and(|index| (0..i64::from(max_entries)).contains(&index));
+                (Opcode::CallMap { map_name: map_name.clone(), in_bounds }, vec![key(ctx, ir)?], value_type()?)
+            }
+        },
+        "lookup_or_init" => {
+            let key = key(ctx, ir)?;
+            let init = lower_map_operand(arg(1)?, &value_type()?, ctx, ir)?;
+            (Opcode::LookupOrInit { map_name: map_name.clone() }, vec![key, init], value_type()?)
+        }
+        "pop" | "peek" => {
+            // The helper copies the element out into a buffer on the stack.
+            let buf = ir.alloc_slot(value_type()?, false, None);
+            let opcode = if call.method == "pop" {
+                Opcode::PopMap { map_name: map_name.clone() }
+            } else {
+                Opcode::PeekMap { map_name: map_name.clone() }
+            };
+            (opcode, vec![Operand::Var(buf)], value_type()?)
+        }
+        "reserve" => {
+            let record_type = call
+                .type_arg
+                .clone()
+                .ok_or_else(|| LoweringError::UnitLowering(format!("Missing record type for {}.reserve", map_name)))?;
+            (Opcode::RingbufReserve { map_name: map_name.clone() }, Vec::new(), record_type)
+        }
+        "update" => {
+            let key = key(ctx, ir)?;
+            let value = lower_map_operand(arg(1)?, &value_type()?, ctx, ir)?;
+            let flags = lower_flags(call.args.get(2), ctx)?;
+            let opcode = Opcode::UpdateMap { map_name: map_name.clone(), flags };
+            return Ok(emit(ctx, ir, opcode, vec![key, value], Type::I64));
+        }
+        "delete" => {
+            let key = key(ctx, ir)?;
+            let opcode = Opcode::DeleteMap { map_name: map_name.clone() };
+            return Ok(emit(ctx, ir, opcode, vec![key], Type::I64));
+        }
+        "push" => {
+            let value = lower_map_operand(arg(0)?, &value_type()?, ctx, ir)?;
+            let flags = lower_flags(call.args.get(1), ctx)?;
+            let opcode = Opcode::PushMap { map_name: map_name.clone(), flags };
+            return Ok(emit(ctx, ir, opcode, vec![value], Type::I64));
+        }
+        "submit" | "discard" => {
+            let record = lower_expr(arg(0)?, ctx, ir)?;
+            let opcode = if call.method == "submit" {
+                Opcode::RingbufSubmit
+            } else {
+                Opcode::RingbufDiscard
+            };
+            return Ok(emit(ctx, ir, opcode, vec![record], Type::I64));
+        }
+        "output" => {
+            let value = lower_event_value(arg(0)?, ctx, ir)?;
+            let opcode = Opcode::RingbufOutput { map_name: map_name.clone() };
+            return Ok(emit(ctx, ir, opcode, vec![value], Type::I64));
+        }
+        "emit" => {
+            let value = lower_event_value(arg(1)?, ctx, ir)?;
+            let opcode = Opcode::PerfOutput { map_name: map_name.clone() };
+            return Ok(emit(ctx, ir, opcode, vec![value], Type::I64));
+        }
+        _ => {
+            return Err(LoweringError::UnitLowering(format!("Unknown method: {}.{}", map_name, call.method)));
+        }
+    };
+
+    let slot = ir.alloc_slot(pointee.clone(), true, name);
+    emit_into(ctx, ir, slot, opcode, operands, pointee);
+    Ok(slot)
+}
+
+/// Lowers a key or value passed to a map helper. The helper takes it by
+/// address, so unless it already sits in a slot of exactly the map's type it
+/// is first copied into one. A struct or array is always in a slot of its
+/// own.
+fn lower_map_operand(
+    expr: &Expr,
+    ty: &DataType,
+    ctx: &mut LowerCtx<'_>,
+    ir: &mut UnitIr,
+) -> Result<Operand, LoweringError> {
+    if let (ExprKind::Cidr(cidr), DataType::Struct(name)) = (&expr.kind, ty) {
+        return Ok(Operand::Var(lower_cidr_key(cidr, name, ctx, ir)?));
+    }
+    let operand = match (lower_expr(expr, ctx, ir)?, ty.scalar()) {
+        (Operand::Immediate(n), Some(ty)) => Operand::Var(emit(ctx, ir, Opcode::Copy, vec![Operand::Immediate(ty.wrap(n))], ty)),
+        (operand, Some(ty)) => convert(ctx, ir, operand, ty),
+        (operand, None) => operand,
+    };
+    Ok(operand)
+}
+
+/// Builds the `lpm_trie` key of a CIDR literal in a fresh slot: its prefix
+/// length, then its address bytes in network order.
+fn lower_cidr_key(cidr: &Cidr, name: &str, ctx: &mut LowerCtx<'_>, ir: &mut UnitIr) -> Result<VarId, LoweringError> {
+    let data = ctx
+        .struct_decl(name)?
+        .lpm_data()
+        .ok_or_else(|| LoweringError::UnitLowering(format!("{} is not an lpm_trie key", name)))?
+        .name
+        .clone();
+    let ty = DataType::Struct(name.to_string());
+    let slot = ir.alloc_var(ty.clone());
+
+    let prefix_len = Operand::Immediate(i64::from(cidr.prefix_len));
+    emit_into(ctx, ir, slot, Opcode::SetField { field: "prefixlen".to_string() }, vec![prefix_len], ty.clone());
+    emit_into(ctx, ir, slot, Opcode::SetBytes { field: data, bytes: cidr.octets() }, Vec::new(), ty);
+    Ok(slot)
+}
+
+/// Lowers a value sent to userspace by `output` or `emit`. The helper copies
+/// the bytes of the value, so it needs an address; a constant gets a slot of
+/// its natural type.
+fn lower_event_value(expr: &Expr, ctx: &mut LowerCtx<'_>, ir: &mut UnitIr) -> Result<Operand, LoweringError> {
+    let value = match lower_expr(expr, ctx, ir)? {
+        Operand::Immediate(n) => Operand::Var(emit(ctx, ir, Opcode::Copy, vec![Operand::Immediate(n)], constant_type(n))),
+        value => value,
+    };
+    Ok(value)
+}
+
+/// Flags of `update` and `push`, checked to be a constant; `BPF_ANY` when
+/// omitted.
+fn lower_flags(flags: Option<&Expr>, ctx: &LowerCtx<'_>) -> Result<i64, LoweringError> {
+    match flags {
+        Some(flags) => flags_value(flags, &|name| ctx.constant(name))
+            .ok_or_else(|| LoweringError::UnitLowering("Map flags must be immediate".to_string())),
+        None => Ok(0),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::parser::parse;
+
+    fn lower(body: &str) -> Result<UnitIr, LoweringError> {
+        let src = format!("unit u {{\n    section: \"kprobe/do_sys_open\";\n    license: \"GPL\";\n{}\n}}\n", body);
+        let program = parse(&src).unwrap_or_else(|e| panic!("{}: {}", e.message, src));
+        UnitIr::lower(&program.units[0], &program.maps, &program.structs)
+    }
+
+    fn slot(ir: &UnitIr, name: &str) -> VarId {
+        let id = ir.vars.iter().position(|v| v.name.as_deref() == Some(name)).expect("binding has a slot");
+        VarId(id as u32)
+    }
+
+    fn writes(block: &BasicBlock, var: VarId) -> bool {
+        block.instructions.iter().any(|i| i.result == var)
+    }
+
+    #[test]
+    fn reassignment_in_an_arm_is_seen_after_the_merge() {
+        let ir = lower(
+            "    reg x = 1;\n    reg y = ctx.load_u8(0);\n    if guard(y == 3) {\n        x = 2;\n    }\n    return x;",
+        )
+        .unwrap();
+        let x = slot(&ir, "x");
+
+        let Terminator::Branch { true_block, .. } = ir.block(ir.entry).unwrap().terminator else {
+            panic!("entry should branch on the guard");
+        };
+        let arm = ir.block(true_block).unwrap();
+        assert!(writes(arm, x));
+
+        let Terminator::Jump(merge) = arm.terminator else {
+            panic!("the arm should jump to the merge");
+        };
+        let merge = ir.block(merge).unwrap();
+        assert_eq!(merge.preds.len(), 2);
+        assert!(matches!(merge.terminator, Terminator::Return(Operand::Var(v)) if v == x));
+    }
+
+    #[test]
+    fn code_after_return_is_dropped() {
+        let ir = lower("    return 1;\n    reg x = 2;\n    return x;").unwrap();
+        assert_eq!(ir.blocks.len(), 1);
+        assert!(matches!(ir.blocks[0].terminator, Terminator::Return(Operand::Immediate(1))));
+    }
+
+    #[test]
+    fn binding_is_scoped_to_its_arm() {
+        let err = lower("    reg y = ctx.load_u8(0);\n    if guard(y) {\n        reg x = 1;\n    }\n    return x;").unwrap_err();
+        assert!(err.to_string().contains("Undefined variable: x"), "{}", err);
+    }
+
+    #[test]
+    fn compound_element_update_converts_the_value() {
+        let ir = lower("    reg buf: u64[2];\n    reg k: u8 = ctx.load_u8(0);\n    buf[0] += k;\n    return 0;").unwrap();
+        let add = ir
+            .blocks
+            .iter()
+            .flat_map(|b| &b.instructions)
+            .find(|i| matches!(i.opcode, Opcode::Binary { op: BinaryOp::Add }))
+            .expect("the update adds");
+        let Operand::Var(value) = add.operands[1] else {
+            panic!("expected a slot, found {:?}", add.operands[1]);
+        };
+        assert_eq!(ir.var(value).ty.scalar(), Some(Type::U64));
+    }
+
+    #[test]
+    fn logical_operators_test_the_right_side_in_its_own_block() {
+        let ir = lower("    reg y = ctx.load_u8(0);\n    if guard(y > 1 && y < 9) {\n        return 1;\n    }\n    return 0;")
+            .unwrap();
+        let Terminator::Branch { true_block: right, false_block, .. } = ir.block(ir.entry).unwrap().terminator else {
+            panic!("entry should branch on the left operand");
+        };
+        let right = ir.block(right).unwrap();
+        assert!(matches!(right.instructions[..], [Instruction { opcode: Opcode::Binary { op: BinaryOp::Lt }, .. }]));
+        assert!(matches!(right.terminator, Terminator::Branch { false_block: f, .. } if f == false_block));
+
+        let ir = lower("    reg y = ctx.load_u8(0);\n    reg z = y == 1 || y == 2;\n    return z;").unwrap();
+        let Terminator::Branch { true_block, false_block: right, .. } = ir.block(ir.entry).unwrap().terminator else {
+            panic!("entry should branch on the left operand");
+        };
+        assert!(matches!(ir.block(right).unwrap().terminator, Terminator::Branch { true_block: t, .. } if t == true_block));
+    }
+}
@@ -38,6 +38,7 @@ pub enum LexError {
     },
 }
 
+#[derive(Clone)]
 pub struct Lexer<'src> {
     src: &'src str,
     bytes: &'src [u8],
@@ -162,9 +163,21 @@ impl<'src> Lexer<'src> {
             "max" => crate::parser::TokenKind::KeywordMax,
             "if" => crate::parser::TokenKind::KeywordIf,
             "guard" => crate::parser::TokenKind::KeywordGuard,
+            "else" => crate::parser::TokenKind::KeywordElse,
             "heap" => crate::parser::TokenKind::KeywordHeap,
+            "as" => crate::parser::TokenKind::KeywordAs,
+            "for" => crate::parser::TokenKind::KeywordFor,
+            "in" => crate::parser::TokenKind::KeywordIn,
+            "struct" => crate::parser::TokenKind::KeywordStruct,
+            "atomic" => crate::parser::TokenKind::KeywordAtomic,
+            "tail_call" => crate::parser::TokenKind::KeywordTailCall,
+            "redirect" => crate::parser::TokenKind::KeywordRedirect,
+            "u8" => crate::parser::TokenKind::TypeU8,
+            "u16" => crate::parser::TokenKind::TypeU16,
             "u32" => crate::parser::TokenKind::TypeU32,
             "u64" => crate::parser::TokenKind::TypeU64,
+            "i8" => crate::parser::TokenKind::TypeI8,
+            "i16" => crate::parser::TokenKind::TypeI16,
             "i32" => crate::parser::TokenKind::TypeI32,
             "i64" => crate::parser::TokenKind::TypeI64,
             _ => crate::parser::TokenKind::Identifier,
@@ -240,6 +253,41 @@ impl<'src> Lexer<'src> {
         ))
     }
 
+    /// Lexes `10.0.0.0/8` or `2001:db8::/32` as one token. Only the shape is
+    /// checked here: four dotted decimal groups, or hex digits with at least
+    /// two colons, then `/` and a prefix length. The parser validates the
+    /// address itself.
+    fn read_cidr(&mut self) -> Option<crate::parser::Token> {
+        let rest = &self.bytes[self.index..];
+        let addr_len = rest
+            .iter()
+            .take_while(|b| b.is_ascii_hexdigit() || **b == b':' || **b == b'.')
+            .count();
+        let addr = &self.src[self.index..self.index + addr_len];
+
+        let is_v4 = addr.split('.').count() == 4
+            && addr.split('.').all(|group| !group.is_empty() && group.bytes().all(|b| b.is_ascii_digit()));
+        let is_v6 = addr.matches(':').count() >= 2;
+        if !(is_v4 || is_v6) || rest.get(addr_len) != Some(&b'/') {
+            return None;
+        }
+        let prefix_len = rest[addr_len + 1..].iter().take_while(|b| b.is_ascii_digit()).count();
+        if prefix_len == 0 {
+            return None;
+        }
+
+        let start = self.index;
+        let loc = self.current_loc();
+        for _ in 0..addr_len + 1 + prefix_len {
+            self.advance();
+        }
+        Some(crate::parser::Token::new(
+            TokenKind::CidrLiteral,
+            &self.src[start..self.index],
+            loc,
+        ))
+    }
+
     fn read_string(&mut self) -> Result<crate::parser::Token, LexError> {
         let start = self.index;
         let loc = self.current_loc();
@@ -306,9 +354,7 @@ impl<'src> Lexer<'src> {
             }
 
             let before = self.index;
-            if let Err(e) = self.skip_comment() {
-                return Err(e);
-            }
+            self.skip_comment()?;
 
             // If we consumed a comment, loop again to skip whitespace/comments after it
             if self.index == before {
@@ -319,6 +365,13 @@ impl<'src> Lexer<'src> {
         let loc = self.current_loc();
         let ch = self.peek();
 
+        // CIDR literals, which can start like a number, an identifier or `::`
+        if ch.is_ascii_hexdigit() || ch == ':' {
+            if let Some(token) = self.read_cidr() {
+                return Ok(token);
+            }
+        }
+
         // identifiers / keywords
         if ch.is_alphabetic() || ch == '_' {
             return Ok(self.read_identifier());
@@ -346,6 +399,14 @@ impl<'src> Lexer<'src> {
                 self.advance();
                 Ok(crate::parser::Token::new(TokenKind::RBrace, "}", loc))
             }
+            '[' => {
+                self.advance();
+                Ok(crate::parser::Token::new(TokenKind::LBracket, "[", loc))
+            }
+            ']' => {
+                self.advance();
+                Ok(crate::parser::Token::new(TokenKind::RBracket, "]", loc))
+            }
             '(' => {
                 self.advance();
                 Ok(crate::parser::Token::new(TokenKind::LParen, "(", loc))
@@ -358,6 +419,15 @@ impl<'src> Lexer<'src> {
                 self.advance();
                 Ok(crate::parser::Token::new(TokenKind::Colon, ":", loc))
             }
+            ',' => {
+                self.advance();
+                Ok(crate::parser::Token::new(TokenKind::Comma, ",", loc))
+            }
+            '.' if self.peek_next() == '.' => {
+                self.advance();
+                self.advance();
+                Ok(crate::parser::Token::new(TokenKind::DotDot, "..", loc))
+            }
             '.' => {
                 self.advance();
                 Ok(crate::parser::Token::new(TokenKind::Dot, ".", loc))
@@ -368,7 +438,100 @@ impl<'src> Lexer<'src> {
             }
             '=' => {
                 self.advance();
-                Ok(crate::parser::Token::new(TokenKind::Equals, "=", loc))
+                if self.peek() == '=' {
+                    self.advance();
+                    Ok(crate::parser::Token::new(TokenKind::EqualsEquals, "==", loc))
+                } else {
+                    Ok(crate::parser::Token::new(TokenKind::Equals, "=", loc))
+                }
+            }
+
+            // Comparison / logical operators
+            '!' => {
+                self.advance();
+                if self.peek() == '=' {
+                    self.advance();
+                    Ok(crate::parser::Token::new(TokenKind::BangEquals, "!=", loc))
+                } else {
+                    Ok(crate::parser::Token::new(TokenKind::Bang, "!", loc))
+                }
+            }
+            '<' => {
+                self.advance();
+                if self.peek() == '<' {
+                    self.advance();
+                    if self.peek() == '=' {
+                        self.advance();
+                        Ok(crate::parser::Token::new(TokenKind::ShlEquals, "<<=", loc))
+                    } else {
+                        Ok(crate::parser::Token::new(TokenKind::Shl, "<<", loc))
+                    }
+                } else if self.peek() == '=' {
+                    self.advance();
+                    Ok(crate::parser::Token::new(TokenKind::LessEquals, "<=", loc))
+                } else {
+                    Ok(crate::parser::Token::new(TokenKind::Less, "<", loc))
+                }
+            }
+            '>' => {
+                self.advance();
+                if self.peek() == '>' {
+                    self.advance();
+                    if self.peek() == '=' {
+                        self.advance();
+                        Ok(crate::parser::Token::new(TokenKind::ShrEquals, ">>=", loc))
+                    } else {
+                        Ok(crate::parser::Token::new(TokenKind::Shr, ">>", loc))
+                    }
+                } else if self.peek() == '=' {
+                    self.advance();
+                    Ok(crate::parser::Token::new(TokenKind::GreaterEquals, ">=", loc))
+                } else {
+                    Ok(crate::parser::Token::new(TokenKind::Greater, ">", loc))
+                }
+            }
+            '&' if self.peek_next() == '&' => {
+                self.advance();
+                self.advance();
+                Ok(crate::parser::Token::new(TokenKind::AndAnd, "&&", loc))
+            }
+            '|' if self.peek_next() == '|' => {
+                self.advance();
+                self.advance();
+                Ok(crate::parser::Token::new(TokenKind::OrOr, "||", loc))
+            }
+
+            // Bitwise operators + compound assigns
+            '&' => {
+                self.advance();
+                if self.peek() == '=' {
+                    self.advance();
+                    Ok(crate::parser::Token::new(TokenKind::AmpEquals, "&=", loc))
+                } else {
+                    Ok(crate::parser::Token::new(TokenKind::Amp, "&", loc))
+                }
+            }
+            '|' => {
+                self.advance();
+                if self.peek() == '=' {
+                    self.advance();
+                    Ok(crate::parser::Token::new(TokenKind::PipeEquals, "|=", loc))
+                } else {
+                    Ok(crate::parser::Token::new(TokenKind::Pipe, "|", loc))
+                }
+            }
+            '^' => {
+                self.advance();
+                if self.peek() == '=' {
+                    self.advance();
+                    Ok(crate::parser::Token::new(TokenKind::CaretEquals, "^=", loc))
+                } else {
+                    Ok(crate::parser::Token::new(TokenKind::Caret, "^", loc))
+                }
+            }
+            '~' => {
+                self.advance();
+                Ok(crate::parser::Token::new(TokenKind::Tilde, "~", loc))
             }
 
             // Operators + compound assigns
@@ -428,3 +591,90 @@ impl<'src> Lexer<'src> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn kinds(src: &str) -> Vec<TokenKind> {
+        let mut lexer = Lexer::new(src);
+        let mut kinds = Vec::new();
+        loop {
+            let token = lexer.next_token().expect("lexes");
+            if token.kind == TokenKind::Eof {
+                return kinds;
+            }
+            kinds.push(token.kind);
+        }
+    }
+
+    #[test]
+    fn comparison_and_logical_operators() {
+        assert_eq!(
+            kinds("== != ! < <= > >= && ||"),
+            vec![
+                TokenKind::EqualsEquals,
+                TokenKind::BangEquals,
+                TokenKind::Bang,
+                TokenKind::Less,
+                TokenKind::LessEquals,
+                TokenKind::Greater,
+                TokenKind::GreaterEquals,
+                TokenKind::AndAnd,
+                TokenKind::OrOr,
+            ]
+        );
+    }
+
+    #[test]
+    fn unknown_character_is_rejected() {
+        let mut lexer = Lexer::new("a @ b");
+        lexer.next_token().expect("identifier");
+        assert!(matches!(lexer.next_token(), Err(LexError::InvalidCharacter { .. })));
+    }
+
+    #[test]
+    fn bitwise_and_shift_operators() {
+        assert_eq!(
+            kinds("& | ^ ~ << >> &= |= ^= <<= >>= < <"),
+            vec![
+                TokenKind::Amp,
+                TokenKind::Pipe,
+                TokenKind::Caret,
+                TokenKind::Tilde,
+                TokenKind::Shl,
+                TokenKind::Shr,
+                TokenKind::AmpEquals,
+                TokenKind::PipeEquals,
+                TokenKind::CaretEquals,
+                TokenKind::ShlEquals,
+                TokenKind::ShrEquals,
+                TokenKind::Less,
+                TokenKind::Less,
+            ]
+        );
+    }
+
+    #[test]
+    fn cidr_literals_are_one_token() {
+        assert_eq!(
+            kinds("10.0.0.0/8 2001:db8::/32 ::1/128"),
+            vec![TokenKind::CidrLiteral, TokenKind::CidrLiteral, TokenKind::CidrLiteral]
+        );
+    }
+
+    #[test]
+    fn division_is_not_a_cidr_literal() {
+        assert_eq!(
+            kinds("10/2 a/8"),
+            vec![
+                TokenKind::Number,
+                TokenKind::Slash,
+                TokenKind::Number,
+                TokenKind::Identifier,
+                TokenKind::Slash,
+                TokenKind::Number,
+            ]
+        );
+    }
+}
@@ -6,6 +6,7 @@ mod ast;
 mod lexer;
 mod ir;
 mod emit;
+mod sema;
 
 use compiler::compile;
 use std::path::PathBuf;
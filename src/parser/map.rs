@@ -1,5 +1,5 @@
 use super::{Parser, ParseError};
-use crate::{ast::{MapDecl, MapType, Type}, parser::TokenKind};
+use crate::{ast::{DataType, MapDecl, MapFlag, MapType, Pinning, ProgramSlot, Type}, parser::TokenKind};
 
 pub fn parse_map(parser: &mut Parser) -> Result<MapDecl, ParseError> {
     let map_loc = parser.current_loc();
@@ -8,9 +8,14 @@ pub fn parse_map(parser: &mut Parser) -> Result<MapDecl, ParseError> {
     expect_token(parser, TokenKind::LBrace)?;
 
     let mut map_type: Option<MapType> = None;
-    let mut key_type: Option<Type> = None;
-    let mut value_type: Option<Type> = None;
+    let mut key_type: Option<DataType> = None;
+    let mut value_type: Option<DataType> = None;
     let mut max_entries: Option<u32> = None;
+    let mut programs = Vec::new();
+    let mut flags = Vec::new();
+    let mut pinning = Pinning::None;
+    let mut numa_node: Option<u32> = None;
+    let mut inner: Option<String> = None;
 
     while !parser.check(TokenKind::RBrace) {
         if parser.r#match(TokenKind::KeywordType) {
@@ -24,10 +29,27 @@ pub fn parse_map(parser: &mut Parser) -> Result<MapDecl, ParseError> {
                 "ringbuf" => MapType::Ringbuf,
                 "lru_hash" => MapType::LruHash,
                 "prog_array" => MapType::ProgArray,
+                "perf_event_array" => MapType::PerfEventArray,
+                "queue" => MapType::Queue,
+                "stack" => MapType::Stack,
+                "percpu_hash" => MapType::PercpuHash,
+                "percpu_array" => MapType::PercpuArray,
+                "lru_percpu_hash" => MapType::LruPercpuHash,
+                "lpm_trie" => MapType::LpmTrie,
+                "array_of_maps" => MapType::ArrayOfMaps,
+                "hash_of_maps" => MapType::HashOfMaps,
+                "devmap" => MapType::Devmap,
+                "devmap_hash" => MapType::DevmapHash,
+                "cpumap" => MapType::Cpumap,
+                "xskmap" => MapType::Xskmap,
+                "sockmap" => MapType::Sockmap,
+                "sockhash" => MapType::Sockhash,
                 _ => {
                     return Err(parser.error_with_help(
                         format!("Unknown map type: {}", t_tok.lexeme),
-                        "Valid types: hash, array, ringbuf, lru_hash, prog_array"
+                        "Valid types: hash, array, ringbuf, lru_hash, prog_array, perf_event_array, queue, stack, \
+                         percpu_hash, percpu_array, lru_percpu_hash, lpm_trie, array_of_maps, hash_of_maps, \
+                         devmap, devmap_hash, cpumap, xskmap, sockmap, sockhash"
                     ));
                 }
             });
@@ -37,13 +59,13 @@ pub fn parse_map(parser: &mut Parser) -> Result<MapDecl, ParseError> {
         }
         if parser.r#match(TokenKind::KeywordKey) {
             expect_token(parser, TokenKind::Colon)?;
-            key_type = Some(parse_type(parser)?);
+            key_type = Some(parse_data_type(parser)?);
             expect_token(parser, TokenKind::Semicolon)?;
             continue;
         }
         if parser.r#match(TokenKind::KeywordValue) {
             expect_token(parser, TokenKind::Colon)?;
-            value_type = Some(parse_type(parser)?);
+            value_type = Some(parse_data_type(parser)?);
             expect_token(parser, TokenKind::Semicolon)?;
             continue;
         }        
@@ -64,51 +86,285 @@ pub fn parse_map(parser: &mut Parser) -> Result<MapDecl, ParseError> {
             continue;
         }
         
+        if match_field(parser, "programs") {
+            expect_token(parser, TokenKind::Colon)?;
+            expect_token(parser, TokenKind::LBracket)?;
+            while !parser.check(TokenKind::RBracket) {
+                let slot_loc = parser.current_loc();
+                let index_tok = parser.expect(TokenKind::Number)?;
+                let index = index_tok
+                    .int_value
+                    .and_then(|n| u32::try_from(n).ok())
+                    .ok_or_else(|| parser.error("Program index must be a non-negative integer"))?;
+                expect_token(parser, TokenKind::Colon)?;
+                let unit_tok = parser.expect(TokenKind::Identifier)?;
+
+                programs.push(ProgramSlot {
+                    index,
+                    unit: unit_tok.lexeme,
+                    loc: slot_loc,
+                });
+
+                if !parser.r#match(TokenKind::Comma) {
+                    break;
+                }
+            }
+            expect_token(parser, TokenKind::RBracket)?;
+            expect_token(parser, TokenKind::Semicolon)?;
+            continue;
+        }
+
+        if match_field(parser, "flags") {
+            expect_token(parser, TokenKind::Colon)?;
+            expect_token(parser, TokenKind::LBracket)?;
+            while !parser.check(TokenKind::RBracket) {
+                let flag_tok = parser.expect(TokenKind::Identifier)?;
+                let flag = MapFlag::from_name(&flag_tok.lexeme).ok_or_else(|| {
+                    let valid: Vec<String> = MapFlag::ALL.iter().map(|f| f.to_string()).collect();
+                    parser.error_with_help(
+                        format!("Unknown map flag: {}", flag_tok.lexeme),
+                        format!("Valid flags: {}", valid.join(", ")),
+                    )
+                })?;
+                flags.push(flag);
+
+                if !parser.r#match(TokenKind::Comma) {
+                    break;
+                }
+            }
+            expect_token(parser, TokenKind::RBracket)?;
+            expect_token(parser, TokenKind::Semicolon)?;
+            continue;
+        }
+
+        if match_field(parser, "pinning") {
+            expect_token(parser, TokenKind::Colon)?;
+            let pin_tok = parser.expect(TokenKind::Identifier)?;
+            pinning = match pin_tok.lexeme.as_str() {
+                "by_name" => Pinning::ByName,
+                "none" => Pinning::None,
+                other => {
+                    return Err(parser.error_with_help(
+                        format!("Unknown pinning: {}", other),
+                        "Valid pinning: by_name, none"
+                    ));
+                }
+            };
+            expect_token(parser, TokenKind::Semicolon)?;
+            continue;
+        }
+
+        if match_field(parser, "numa_node") {
+            expect_token(parser, TokenKind::Colon)?;
+            let node_tok = parser.expect(TokenKind::Number)?;
+            let node = node_tok
+                .int_value
+                .and_then(|n| u32::try_from(n).ok())
+                .ok_or_else(|| parser.error("NUMA node must be a non-negative integer"))?;
+            numa_node = Some(node);
+            expect_token(parser, TokenKind::Semicolon)?;
+            continue;
+        }
+
+        if match_field(parser, "inner") {
+            expect_token(parser, TokenKind::Colon)?;
+            let inner_tok = parser.expect(TokenKind::Identifier)?;
+            inner = Some(inner_tok.lexeme);
+            expect_token(parser, TokenKind::Semicolon)?;
+            continue;
+        }
+
         return Err(parser.error_with_help(
             format!("Unexpected token inside map: {}", parser.current_kind()),
-            "Expected one of: type, key, value, max"
+            "Expected one of: type, key, value, max, programs, flags, pinning, numa_node, inner"
         ));
     }
     
     expect_token(parser, TokenKind::RBrace)?;
     
-    if map_type.is_none() {
+    let Some(map_type) = map_type else {
         return Err(parser.error("Map missing required field: type"));
+    };
+    // Perf event and prog arrays and redirect maps map slot numbers to fds,
+    // ifindexes or CPUs, so their layout is fixed. A sockhash is keyed by
+    // whatever identifies a connection. libbpf sizes a perf event array to
+    // the number of CPUs when `max` is 0.
+    if matches!(
+        map_type,
+        MapType::PerfEventArray
+            | MapType::ProgArray
+            | MapType::Devmap
+            | MapType::DevmapHash
+            | MapType::Cpumap
+            | MapType::Xskmap
+            | MapType::Sockmap
+    ) {
+        key_type.get_or_insert(DataType::Scalar(Type::U32));
+    }
+    if matches!(
+        map_type,
+        MapType::PerfEventArray
+            | MapType::ProgArray
+            | MapType::Devmap
+            | MapType::DevmapHash
+            | MapType::Cpumap
+            | MapType::Xskmap
+            | MapType::Sockmap
+            | MapType::Sockhash
+    ) {
+        value_type.get_or_insert(DataType::Scalar(Type::U32));
+    }
+    if map_type == MapType::PerfEventArray {
+        max_entries.get_or_insert(0);
     }
-    if key_type.is_none() {
+    if key_type.is_none() && map_type.has_key() {
         return Err(parser.error("Map missing required field: key"));
     }
-    if value_type.is_none() {
+    if value_type.is_none() && map_type.has_value() {
         return Err(parser.error("Map missing required field: value"));
     }
     if max_entries.is_none() {
         return Err(parser.error("Map missing required field: max"));
     }
+    if inner.is_none() && map_type.is_map_of_maps() {
+        return Err(parser.error("Map missing required field: inner"));
+    }
 
     Ok(MapDecl {
         name: map_name_tok.lexeme,
-        map_type: map_type.unwrap(),
-        key_type: key_type.unwrap(),
-        value_type: value_type.unwrap(),
+        map_type,
+        key_type,
+        value_type,
         max_entries: max_entries.unwrap(),
+        programs,
+        inner,
+        flags,
+        pinning,
+        numa_node,
         loc: map_loc,
     })
 }
 
+/// Matches a field written as a plain identifier, which leaves the name free
+/// for variables elsewhere.
+pub fn match_field(parser: &mut Parser, name: &str) -> bool {
+    if parser.check(TokenKind::Identifier) && parser.current().lexeme == name {
+        let _ = parser.advance();
+        true
+    } else {
+        false
+    }
+}
+
 pub fn parse_type(parser: &mut Parser) -> Result<Type, ParseError> {
     let t = parser.current().clone();
     parser.advance()?;
 
     match t.kind {
+        TokenKind::TypeU8 => Ok(Type::U8),
+        TokenKind::TypeU16 => Ok(Type::U16),
         TokenKind::TypeU32 => Ok(Type::U32),
         TokenKind::TypeU64 => Ok(Type::U64),
+        TokenKind::TypeI8 => Ok(Type::I8),
+        TokenKind::TypeI16 => Ok(Type::I16),
         TokenKind::TypeI32 => Ok(Type::I32),
         TokenKind::TypeI64 => Ok(Type::I64),
-        _ => Err(parser.error("Expected type (u32, u64, i32, i64)")),
+        _ => Err(parser.error("Expected type (u8, u16, u32, u64, i8, i16, i32, i64)")),
     }
 }
 
+/// A primitive type, an array of one (`u8[16]`), or the name of a declared
+/// struct.
+pub fn parse_data_type(parser: &mut Parser) -> Result<DataType, ParseError> {
+    if parser.check(TokenKind::Identifier) {
+        let name_tok = parser.expect(TokenKind::Identifier)?;
+        return Ok(DataType::Struct(name_tok.lexeme));
+    }
+
+    let elem = parse_type(parser)?;
+    if !parser.r#match(TokenKind::LBracket) {
+        return Ok(DataType::Scalar(elem));
+    }
+
+    let len_tok = parser.expect(TokenKind::Number)?;
+    let len = len_tok
+        .int_value
+        .and_then(|n| u32::try_from(n).ok())
+        .filter(|&n| n > 0)
+        .ok_or_else(|| parser.error("Array length must be a positive integer"))?;
+    expect_token(parser, TokenKind::RBracket)?;
+    Ok(DataType::Array(elem, len))
+}
+
 pub fn expect_token(parser: &mut Parser, kind: TokenKind) -> Result<(), ParseError> {
     parser.expect(kind)?;
     Ok(())
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use crate::ast::{DataType, MapDecl, MapFlag, MapType, Pinning, Type};
+    use crate::parser::parse;
+
+    fn map(fields: &str) -> Result<MapDecl, String> {
+        let src = format!("map m {{\n{}\n}}\n", fields);
+        parse(&src).map(|mut program| program.maps.remove(0)).map_err(|e| e.message)
+    }
+
+    #[test]
+    fn perf_event_array_defaults_to_a_cpu_indexed_fd_array() {
+        let m = map("    type: .perf_event_array;").unwrap();
+        assert_eq!(m.map_type, MapType::PerfEventArray);
+        assert_eq!(m.key_type, Some(DataType::Scalar(Type::U32)));
+        assert_eq!(m.value_type, Some(DataType::Scalar(Type::U32)));
+        assert_eq!(m.max_entries, 0);
+    }
+
+    #[test]
+    fn unknown_map_type_is_rejected() {
+        let err = map("    type: .perf_array;").unwrap_err();
+        assert_eq!(err, "Unknown map type: perf_array");
+    }
+
+    #[test]
+    fn programs_fill_prog_array_slots() {
+        let m = map("    type: .prog_array;\n    max: 4;\n    programs: [0: parse_v4, 3: parse_v6];").unwrap();
+        let slots: Vec<_> = m.programs.iter().map(|slot| (slot.index, slot.unit.as_str())).collect();
+        assert_eq!(slots, [(0, "parse_v4"), (3, "parse_v6")]);
+
+        let err = map("    type: .prog_array;\n    max: 4;\n    programs: [first: parse_v4];").unwrap_err();
+        assert_eq!(err, "Expected number, found identifier");
+    }
+
+    #[test]
+    fn programs_is_only_a_field_inside_a_map() {
+        let src = "unit u {\n    section: \"xdp\";\n    reg programs = 2;\n    return programs;\n}\n";
+        let program = parse(src).unwrap_or_else(|e| panic!("{}", e.message));
+        assert_eq!(program.units[0].body.len(), 2);
+    }
+
+    #[test]
+    fn attributes_are_parsed() {
+        let m = map("    type: .hash;\n    key: u32;\n    value: u64;\n    max: 8;\n    flags: [no_prealloc, rdonly_prog];\n    pinning: by_name;\n    numa_node: 1;").unwrap();
+        assert_eq!(m.flags, [MapFlag::NoPrealloc, MapFlag::RdonlyProg]);
+        assert_eq!(m.pinning, Pinning::ByName);
+        assert_eq!(m.numa_node, Some(1));
+    }
+
+    #[test]
+    fn unknown_attribute_values_are_rejected() {
+        let base = "    type: .hash;\n    key: u32;\n    value: u64;\n    max: 8;\n";
+        assert_eq!(map(&format!("{}    flags: [prealloc];", base)).unwrap_err(), "Unknown map flag: prealloc");
+        assert_eq!(map(&format!("{}    pinning: by_path;", base)).unwrap_err(), "Unknown pinning: by_path");
+    }
+
+    #[test]
+    fn map_of_maps_names_its_inner_prototype() {
+        let m = map("    type: .hash_of_maps;\n    key: u32;\n    max: 8;\n    inner: counts;").unwrap();
+        assert_eq!(m.map_type, MapType::HashOfMaps);
+        assert_eq!(m.inner.as_deref(), Some("counts"));
+        assert_eq!(m.value_type, None);
+
+        let err = map("    type: .array_of_maps;\n    key: u32;\n    max: 8;").unwrap_err();
+        assert_eq!(err, "Map missing required field: inner");
+    }
+}
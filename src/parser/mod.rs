@@ -1,7 +1,9 @@
 pub mod token;
+#[allow(clippy::module_inception)]
 pub mod parser;
 pub mod program;
 pub mod map;
+pub mod structs;
 pub mod unit;
 
 pub use token::{Token, TokenKind, SourceLoc};
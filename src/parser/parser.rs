@@ -18,22 +18,6 @@ pub struct ParseError {
     pub help: Option<String>,
 }
 
-#[derive(Debug, Clone, Copy)]
-pub enum BinOp {
-    Add,
-}
-
-#[derive(Debug, Clone)]
-pub enum Expr {
-    Number(i64),
-    Var(String),
-    Binary {
-        left: Box<Expr>,
-        op: BinOp,
-        right: Box<Expr>,
-    },
-}
-
 impl ParseError {
     pub fn new(span: crate::parser::SourceLoc, message: impl Into<String>) -> Self {
         Self {
@@ -53,6 +37,9 @@ pub struct Parser<'src> {
     _src: &'src str,
     lexer: Lexer<'src>,
     current: Token,
+    /// Whether `Name { ... }` may start a struct literal. Off while parsing
+    /// a `for` range, where the `{` opens the loop body instead.
+    struct_literals: bool,
 }
 
 impl<'src> Parser<'src> {
@@ -66,6 +53,7 @@ impl<'src> Parser<'src> {
             _src: src,
             lexer,
             current,
+            struct_literals: true,
         })
     }
 
@@ -116,6 +104,14 @@ impl<'src> Parser<'src> {
         ParseError::new(self.current.loc, message).with_help(help)
     }
 
+    pub fn struct_literals(&self) -> bool {
+        self.struct_literals
+    }
+
+    pub fn set_struct_literals(&mut self, allowed: bool) {
+        self.struct_literals = allowed;
+    }
+
     pub fn current(&self) -> &Token {
         &self.current
     }
@@ -128,43 +124,12 @@ impl<'src> Parser<'src> {
         self.current.kind
     }
 
-    pub fn _current_lexeme(&self) -> &str {
-        &self.current.lexeme
-    }
-
-    pub fn parse_expr(&mut self) -> Result<Expr, ParseError> {
-        self.parse_additive()
+    /// Kind of the token after the current one, without consuming either.
+    pub fn peek_kind(&self) -> TokenKind {
+        self.lexer.clone().next_token().map_or(TokenKind::Eof, |token| token.kind)
     }
 
-    fn parse_additive(&mut self) -> Result<Expr, ParseError> {
-        let mut left = self.parse_primary()?;
-
-        while self.current_kind() == TokenKind::Plus {
-            self.advance()?; // eat '+'
-            let right = self.parse_primary()?;
-            left = Expr::Binary {
-                left: Box::new(left),
-                op: BinOp::Add,
-                right: Box::new(right),
-            };
-        }
-
-        Ok(left)
-    }
-
-    fn parse_primary(&mut self) -> Result<Expr, ParseError> {
-        match self.current_kind() {
-            TokenKind::Number => {
-                let tok = self.current.clone();
-                self.advance()?;
-                Ok(Expr::Number(tok.int_value.unwrap()))
-            }
-            TokenKind::Identifier => {
-                let tok = self.current.clone();
-                self.advance()?;
-                Ok(Expr::Var(tok.lexeme))
-            }
-            _ => Err(self.error("Expected expression")),
-        }
+    pub fn _current_lexeme(&self) -> &str {
+        &self.current.lexeme
     }
 }
@@ -3,14 +3,19 @@ use super::ParseError;
 use crate::ast::Program;
 use crate::parser::TokenKind;
 use crate::parser::map::parse_map;
+use crate::parser::structs::parse_struct;
 use crate::parser::unit::parse_unit;
 
 pub fn parse_program(parser: &mut Parser) -> Result<Program, ParseError> {
+    let mut structs = Vec::new();
     let mut maps = Vec::new();
     let mut units = Vec::new();
 
     while !parser.check(TokenKind::Eof) {
-        if parser.r#match(TokenKind::KeywordMap) {
+        if parser.r#match(TokenKind::KeywordStruct) {
+            let decl = parse_struct(parser)?;
+            structs.push(decl);
+        } else if parser.r#match(TokenKind::KeywordMap) {
             let map = parse_map(parser)?;
             maps.push(map);
         } else if parser.check(TokenKind::KeywordUnit) {
@@ -18,11 +23,11 @@ pub fn parse_program(parser: &mut Parser) -> Result<Program, ParseError> {
             units.push(unit);
         } else {
             return Err(parser.error_with_help(
-                "Expected 'map' or 'unit'",
-                "Programs must start with map declarations or unit definitions"
+                "Expected 'struct', 'map' or 'unit'",
+                "Programs must start with struct or map declarations or unit definitions"
             ));
         }
     }
 
-    Ok(Program { maps, units })
+    Ok(Program { structs, maps, units })
 }
\ No newline at end of file
@@ -0,0 +1,37 @@
+use super::{Parser, ParseError};
+use crate::ast::{StructDecl, StructField};
+use crate::parser::map::{expect_token, parse_data_type};
+use crate::parser::TokenKind;
+
+pub fn parse_struct(parser: &mut Parser) -> Result<StructDecl, ParseError> {
+    let struct_loc = parser.current_loc();
+
+    let name_tok = parser.expect(TokenKind::Identifier)?;
+    expect_token(parser, TokenKind::LBrace)?;
+
+    let mut fields = Vec::new();
+    while !parser.check(TokenKind::RBrace) {
+        let field_loc = parser.current_loc();
+        let field_tok = parser.expect(TokenKind::Identifier)?;
+        expect_token(parser, TokenKind::Colon)?;
+        let ty = parse_data_type(parser)?;
+
+        fields.push(StructField {
+            name: field_tok.lexeme,
+            ty,
+            loc: field_loc,
+        });
+
+        if !parser.r#match(TokenKind::Comma) {
+            break;
+        }
+    }
+
+    expect_token(parser, TokenKind::RBrace)?;
+
+    Ok(StructDecl {
+        name: name_tok.lexeme,
+        fields,
+        loc: struct_loc,
+    })
+}
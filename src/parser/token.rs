@@ -42,7 +42,15 @@ pub enum TokenKind {
     KeywordMax,
     KeywordIf,
     KeywordGuard,
+    KeywordElse,
     KeywordHeap,
+    KeywordAs,
+    KeywordFor,
+    KeywordIn,
+    KeywordStruct,
+    KeywordAtomic,
+    KeywordTailCall,
+    KeywordRedirect,
 
     // Map types
     MapTypeHash,
@@ -52,8 +60,12 @@ pub enum TokenKind {
     MapTypeProgArray,
 
     // Primitive types
+    TypeU8,
+    TypeU16,
     TypeU32,
     TypeU64,
+    TypeI8,
+    TypeI16,
     TypeI32,
     TypeI64,
 
@@ -62,8 +74,12 @@ pub enum TokenKind {
     RBrace,
     LParen,
     RParen,
+    LBracket,
+    RBracket,
     Colon,
+    Comma,
     Dot,
+    DotDot,
     Semicolon,
 
     // Assignment
@@ -73,6 +89,11 @@ pub enum TokenKind {
     StarEquals,
     SlashEquals,
     PercentEquals,
+    AmpEquals,
+    PipeEquals,
+    CaretEquals,
+    ShlEquals,
+    ShrEquals,
 
     // Operators
     Plus,
@@ -81,10 +102,31 @@ pub enum TokenKind {
     Slash,
     Percent,
 
+    // Bitwise operators
+    Amp,
+    Pipe,
+    Caret,
+    Tilde,
+    Shl,
+    Shr,
+
+    // Comparison / logical operators
+    EqualsEquals,
+    BangEquals,
+    Less,
+    LessEquals,
+    Greater,
+    GreaterEquals,
+    AndAnd,
+    OrOr,
+    Bang,
+
     // Literals / identifiers
     Identifier,
     StringLiteral,
     Number,
+    /// `10.0.0.0/8` or `2001:db8::/32`.
+    CidrLiteral,
 
     // End of file
     Eof,
@@ -108,6 +150,7 @@ impl TokenKind {
                 | Self::KeywordMax
                 | Self::KeywordIf
                 | Self::KeywordGuard
+                | Self::KeywordElse
                 | Self::KeywordHeap
         )
     }
@@ -128,7 +171,14 @@ impl TokenKind {
     pub fn _is_primitive_type(&self) -> bool {
         matches!(
             self,
-            Self::TypeU32 | Self::TypeU64 | Self::TypeI32 | Self::TypeI64
+            Self::TypeU8
+                | Self::TypeU16
+                | Self::TypeU32
+                | Self::TypeU64
+                | Self::TypeI8
+                | Self::TypeI16
+                | Self::TypeI32
+                | Self::TypeI64
         )
     }
 }
@@ -150,7 +200,15 @@ impl fmt::Display for TokenKind {
             Self::KeywordMax => write!(f, "max"),
             Self::KeywordIf => write!(f, "if"),
             Self::KeywordGuard => write!(f, "guard"),
+            Self::KeywordElse => write!(f, "else"),
             Self::KeywordHeap => write!(f, "heap"),
+            Self::KeywordAs => write!(f, "as"),
+            Self::KeywordFor => write!(f, "for"),
+            Self::KeywordIn => write!(f, "in"),
+            Self::KeywordStruct => write!(f, "struct"),
+            Self::KeywordAtomic => write!(f, "atomic"),
+            Self::KeywordTailCall => write!(f, "tail_call"),
+            Self::KeywordRedirect => write!(f, "redirect"),
 
             // Map types
             Self::MapTypeHash => write!(f, "hash"),
@@ -160,8 +218,12 @@ impl fmt::Display for TokenKind {
             Self::MapTypeProgArray => write!(f, "prog_array"),
 
             // Primitive types
+            Self::TypeU8 => write!(f, "u8"),
+            Self::TypeU16 => write!(f, "u16"),
             Self::TypeU32 => write!(f, "u32"),
             Self::TypeU64 => write!(f, "u64"),
+            Self::TypeI8 => write!(f, "i8"),
+            Self::TypeI16 => write!(f, "i16"),
             Self::TypeI32 => write!(f, "i32"),
             Self::TypeI64 => write!(f, "i64"),
 
@@ -170,8 +232,12 @@ impl fmt::Display for TokenKind {
             Self::RBrace => write!(f, "}}"),
             Self::LParen => write!(f, "("),
             Self::RParen => write!(f, ")"),
+            Self::LBracket => write!(f, "["),
+            Self::RBracket => write!(f, "]"),
             Self::Colon => write!(f, ":"),
+            Self::Comma => write!(f, ","),
             Self::Dot => write!(f, "."),
+            Self::DotDot => write!(f, ".."),
             Self::Semicolon => write!(f, ";"),
 
             // Assignment
@@ -181,6 +247,11 @@ impl fmt::Display for TokenKind {
             Self::StarEquals => write!(f, "*="),
             Self::SlashEquals => write!(f, "/="),
             Self::PercentEquals => write!(f, "%="),
+            Self::AmpEquals => write!(f, "&="),
+            Self::PipeEquals => write!(f, "|="),
+            Self::CaretEquals => write!(f, "^="),
+            Self::ShlEquals => write!(f, "<<="),
+            Self::ShrEquals => write!(f, ">>="),
 
             // Operators
             Self::Plus => write!(f, "+"),
@@ -189,10 +260,30 @@ impl fmt::Display for TokenKind {
             Self::Slash => write!(f, "/"),
             Self::Percent => write!(f, "%"),
 
+            // Bitwise operators
+            Self::Amp => write!(f, "&"),
+            Self::Pipe => write!(f, "|"),
+            Self::Caret => write!(f, "^"),
+            Self::Tilde => write!(f, "~"),
+            Self::Shl => write!(f, "<<"),
+            Self::Shr => write!(f, ">>"),
+
+            // Comparison / logical operators
+            Self::EqualsEquals => write!(f, "=="),
+            Self::BangEquals => write!(f, "!="),
+            Self::Less => write!(f, "<"),
+            Self::LessEquals => write!(f, "<="),
+            Self::Greater => write!(f, ">"),
+            Self::GreaterEquals => write!(f, ">="),
+            Self::AndAnd => write!(f, "&&"),
+            Self::OrOr => write!(f, "||"),
+            Self::Bang => write!(f, "!"),
+
             // Literals / identifiers
             Self::Identifier => write!(f, "identifier"),
             Self::StringLiteral => write!(f, "string literal"),
             Self::Number => write!(f, "number"),
+            Self::CidrLiteral => write!(f, "CIDR literal"),
 
             // EOF
             Self::Eof => write!(f, "end of file"),
@@ -1,8 +1,10 @@
 use super::{Parser, ParseError};
 use crate::{ast::{
-    Assignment, AssignmentOp,  Expr, ExprKind, HeapLookup, HeapVarDecl, IfGuard, MethodCall, Stmt, StmtKind, Unit, VarDecl, VarType
-}, parser::{TokenKind, map::expect_token}};
+    Assignment, AssignmentOp, AtomicExpr, AtomicOp, BinOp, BinaryExpr, CastExpr, Cidr, DataType, Expr, ExprKind, FieldAccess, FieldInit, ForLoop,
+    HeapVarDecl, IfGuard, IndexExpr, MethodCall, Redirect, Stmt, StmtKind, StructLiteral, TailCall, UnaryExpr, UnaryOp, Unit, VarDecl, VarType
+}, parser::{TokenKind, map::{expect_token, match_field, parse_data_type, parse_type}}};
 use std::boxed::Box;
+use std::net::IpAddr;
 
 pub fn parse_unit(parser: &mut Parser) -> Result<Unit, ParseError> {
     let unit_loc = parser.current_loc();
@@ -14,6 +16,7 @@ pub fn parse_unit(parser: &mut Parser) -> Result<Unit, ParseError> {
     let mut sections = Vec::new();
     let mut body = Vec::new();
     let mut license: Option<String> = None;
+    let mut fallback: Option<String> = None;
 
     while !parser.check(TokenKind::RBrace) {
         if parser.r#match(TokenKind::KeywordSection) {
@@ -36,6 +39,23 @@ pub fn parse_unit(parser: &mut Parser) -> Result<Unit, ParseError> {
             continue;
         }
 
+        if match_unit_field(parser, "fallback") {
+            expect_token(parser, TokenKind::Colon)?;
+            let verdict = if parser.check(TokenKind::Number) {
+                let num_tok = parser.expect(TokenKind::Number)?;
+                num_tok
+                    .int_value
+                    .ok_or_else(|| parser.error("Invalid number literal"))?
+                    .to_string()
+            } else {
+                parser.expect(TokenKind::Identifier)?.lexeme
+            };
+
+            fallback = Some(verdict);
+            expect_token(parser, TokenKind::Semicolon)?;
+            continue;
+        }
+
         parse_stmt(parser, &mut body)?;
     }
 
@@ -46,23 +66,30 @@ pub fn parse_unit(parser: &mut Parser) -> Result<Unit, ParseError> {
         loc: unit_loc,
         sections,
         license,
+        fallback,
         body,
     })
 }
 
+/// Matches a unit field written as a plain identifier. Only `name:` starts
+/// a field, so a variable of the same name still parses as a statement.
+fn match_unit_field(parser: &mut Parser, name: &str) -> bool {
+    parser.peek_kind() == TokenKind::Colon && match_field(parser, name)
+}
+
 fn parse_stmt(parser: &mut Parser, body: &mut Vec<Stmt>) -> Result<(), ParseError> {
     if parser.r#match(TokenKind::KeywordReg) {
         let var_loc = parser.current_loc();
         let var_name_tok = parser.expect(TokenKind::Identifier)?;
-        expect_token(parser, TokenKind::Equals)?;
-        let value_expr = parse_expr(parser)?;
-        expect_token(parser, TokenKind::Semicolon)?;
+        let ty = parse_annotation(parser)?;
+        let value = parse_initializer(parser)?;
 
         body.push(Stmt {
             kind: StmtKind::VarDecl(VarDecl {
                 name: var_name_tok.lexeme,
                 var_type: VarType::Reg,
-                value: Box::new(value_expr),
+                ty,
+                value,
             }),
             loc: var_loc,
         });
@@ -72,15 +99,15 @@ fn parse_stmt(parser: &mut Parser, body: &mut Vec<Stmt>) -> Result<(), ParseErro
     if parser.r#match(TokenKind::KeywordImm) {
         let var_loc = parser.current_loc();
         let var_name_tok = parser.expect(TokenKind::Identifier)?;
-        expect_token(parser, TokenKind::Equals)?;
-        let value_expr = parse_expr(parser)?;
-        expect_token(parser, TokenKind::Semicolon)?;
+        let ty = parse_annotation(parser)?;
+        let value = parse_initializer(parser)?;
 
         body.push(Stmt {
             kind: StmtKind::VarDecl(VarDecl {
                 name: var_name_tok.lexeme,
                 var_type: VarType::Imm,
-                value: Box::new(value_expr),
+                ty,
+                value,
             }),
             loc: var_loc,
         });
@@ -90,31 +117,21 @@ fn parse_stmt(parser: &mut Parser, body: &mut Vec<Stmt>) -> Result<(), ParseErro
     if parser.r#match(TokenKind::KeywordHeap) {
         let var_loc = parser.current_loc();
         let var_name_tok = parser.expect(TokenKind::Identifier)?;
+        let ty = parse_annotation(parser)?;
         expect_token(parser, TokenKind::Equals)?;
 
         let map_name_tok = parser.expect(TokenKind::Identifier)?;
         expect_token(parser, TokenKind::Dot)?;
-        let lookup_tok = parser.expect(TokenKind::Identifier)?;
-        
-        if lookup_tok.lexeme != "lookup" {
-            return Err(parser.error_with_help(
-                "Expected 'lookup' method",
-                format!("Found: {}", lookup_tok.lexeme)
-            ));
-        }
-
+        let method_tok = parser.expect(TokenKind::Identifier)?;
         expect_token(parser, TokenKind::LParen)?;
-        let key_expr = parse_expr(parser)?;
-        expect_token(parser, TokenKind::RParen)?;
+        let call = parse_method_call(parser, map_name_tok.lexeme, method_tok.lexeme)?;
         expect_token(parser, TokenKind::Semicolon)?;
 
         body.push(Stmt {
             kind: StmtKind::HeapVarDecl(HeapVarDecl {
                 name: var_name_tok.lexeme,
-                lookup: HeapLookup {
-                    map_name: map_name_tok.lexeme,
-                    key_expr: Box::new(key_expr),
-                },
+                ty,
+                call,
             }),
             loc: var_loc,
         });
@@ -137,7 +154,7 @@ fn parse_stmt(parser: &mut Parser, body: &mut Vec<Stmt>) -> Result<(), ParseErro
         let if_loc = parser.current_loc();
         expect_token(parser, TokenKind::KeywordGuard)?;
         expect_token(parser, TokenKind::LParen)?;
-        let var_tok = parser.expect(TokenKind::Identifier)?;
+        let condition = parse_expr(parser)?;
         expect_token(parser, TokenKind::RParen)?;
         expect_token(parser, TokenKind::LBrace)?;
 
@@ -146,55 +163,285 @@ fn parse_stmt(parser: &mut Parser, body: &mut Vec<Stmt>) -> Result<(), ParseErro
             parse_stmt(parser, &mut guard_body)?;
         }
 
+        let mut else_body = Vec::new();
+        if parser.r#match(TokenKind::KeywordElse) {
+            if parser.check(TokenKind::KeywordIf) {
+                parse_stmt(parser, &mut else_body)?;
+            } else {
+                expect_token(parser, TokenKind::LBrace)?;
+                while !parser.r#match(TokenKind::RBrace) {
+                    parse_stmt(parser, &mut else_body)?;
+                }
+            }
+        }
+
         body.push(Stmt {
             kind: StmtKind::IfGuard(IfGuard {
-                condition: Expr {
-                    kind: ExprKind::Variable(var_tok.lexeme.clone()),
-                    loc: var_tok.loc,
-                },
+                condition,
                 body: guard_body,
+                else_body,
             }),
             loc: if_loc,
         });
         return Ok(());
     }
 
-    let target = parse_expr(parser)?;
-    let target_loc = target.loc;
-    if parser.r#match(TokenKind::Equals) {
-        let value = parse_expr(parser)?;
-        expect_token(parser, TokenKind::Semicolon)?;
-        
+    if parser.r#match(TokenKind::KeywordFor) {
+        let for_loc = parser.current_loc();
+        let var_tok = parser.expect(TokenKind::Identifier)?;
+        expect_token(parser, TokenKind::KeywordIn)?;
+        parser.set_struct_literals(false);
+        let start = parse_expr(parser)?;
+        expect_token(parser, TokenKind::DotDot)?;
+        let end = parse_expr(parser)?;
+        parser.set_struct_literals(true);
+        expect_token(parser, TokenKind::LBrace)?;
+
+        let mut loop_body = Vec::new();
+        while !parser.r#match(TokenKind::RBrace) {
+            parse_stmt(parser, &mut loop_body)?;
+        }
+
         body.push(Stmt {
-            kind: StmtKind::Assignment(Assignment {
-                target: Box::new(target),
-                op: AssignmentOp::Assign,
-                value: Box::new(value),
+            kind: StmtKind::For(ForLoop {
+                var: var_tok.lexeme,
+                start,
+                end,
+                body: loop_body,
             }),
-            loc: target_loc,
+            loc: for_loc,
         });
         return Ok(());
-    } else if parser.r#match(TokenKind::PlusEquals) {
-        let value = parse_expr(parser)?;
+    }
+
+    let stmt_loc = parser.current_loc();
+
+    // tail_call(map, index);
+    if parser.r#match(TokenKind::KeywordTailCall) {
+        expect_token(parser, TokenKind::LParen)?;
+        let map_tok = parser.expect(TokenKind::Identifier)?;
+        expect_token(parser, TokenKind::Comma)?;
+        let index = parse_expr(parser)?;
+        expect_token(parser, TokenKind::RParen)?;
         expect_token(parser, TokenKind::Semicolon)?;
-        
+
         body.push(Stmt {
-            kind: StmtKind::Assignment(Assignment {
-                target: Box::new(target),
-                op: AssignmentOp::AddAssign,
-                value: Box::new(value),
+            kind: StmtKind::TailCall(TailCall {
+                map_name: map_tok.lexeme,
+                index,
             }),
-            loc: target_loc,
+            loc: stmt_loc,
         });
         return Ok(());
     }
 
-    Err(parser.error("Unexpected statement")
-        .with_help("Expected: reg, imm, heap, return, if, or expression"))
+    let atomic = parser.r#match(TokenKind::KeywordAtomic);
+    let is_atomic_call = atomic
+        && parser.check(TokenKind::Identifier)
+        && matches!(parser.current().lexeme.as_str(), "xchg" | "cmpxchg");
+    let target = if is_atomic_call {
+        parse_atomic_call(parser, stmt_loc)?
+    } else {
+        parse_expr(parser)?
+    };
+
+    // a call made for its effect: `map.delete(key);`
+    if (!atomic || is_atomic_call) && parser.check(TokenKind::Semicolon) {
+        if let ExprKind::MethodCall(_) | ExprKind::Atomic(_) = target.kind {
+            parser.advance()?;
+            body.push(Stmt {
+                kind: StmtKind::Expr(target),
+                loc: stmt_loc,
+            });
+            return Ok(());
+        }
+    }
+
+    let op = match parser.current_kind() {
+        TokenKind::Equals => AssignmentOp::Assign,
+        TokenKind::PlusEquals => AssignmentOp::AddAssign,
+        TokenKind::MinusEquals => AssignmentOp::SubAssign,
+        TokenKind::StarEquals => AssignmentOp::MulAssign,
+        TokenKind::SlashEquals => AssignmentOp::DivAssign,
+        TokenKind::PercentEquals => AssignmentOp::ModAssign,
+        TokenKind::AmpEquals => AssignmentOp::AndAssign,
+        TokenKind::PipeEquals => AssignmentOp::OrAssign,
+        TokenKind::CaretEquals => AssignmentOp::XorAssign,
+        TokenKind::ShlEquals => AssignmentOp::ShlAssign,
+        TokenKind::ShrEquals => AssignmentOp::ShrAssign,
+        _ => {
+            return Err(parser.error("Unexpected statement")
+                .with_help("Expected: reg, imm, heap, return, if, for, or expression"));
+        }
+    };
+    parser.advance()?;
+    let value = parse_expr(parser)?;
+    expect_token(parser, TokenKind::Semicolon)?;
+
+    body.push(Stmt {
+        kind: StmtKind::Assignment(Assignment {
+            target: Box::new(target),
+            op,
+            value: Box::new(value),
+            atomic,
+        }),
+        loc: stmt_loc,
+    });
+    Ok(())
+}
+
+// optional `: type` after a binding name
+fn parse_annotation(parser: &mut Parser) -> Result<Option<DataType>, ParseError> {
+    if parser.r#match(TokenKind::Colon) {
+        Ok(Some(parse_data_type(parser)?))
+    } else {
+        Ok(None)
+    }
+}
+
+// `= value;`, or just `;` for a buffer that starts zeroed
+fn parse_initializer(parser: &mut Parser) -> Result<Option<Box<Expr>>, ParseError> {
+    if parser.r#match(TokenKind::Semicolon) {
+        return Ok(None);
+    }
+    expect_token(parser, TokenKind::Equals)?;
+    let value_expr = parse_expr(parser)?;
+    expect_token(parser, TokenKind::Semicolon)?;
+    Ok(Some(Box::new(value_expr)))
 }
 
 pub fn parse_expr(parser: &mut Parser) -> Result<Expr, ParseError> {
-    parse_add(parser)
+    parse_or(parser)
+}
+
+// ||
+fn parse_or(parser: &mut Parser) -> Result<Expr, ParseError> {
+    let mut expr = parse_and(parser)?;
+
+    while parser.r#match(TokenKind::OrOr) {
+        let rhs = parse_and(parser)?;
+        expr = binary(BinOp::Or, expr, rhs);
+    }
+
+    Ok(expr)
+}
+
+// &&
+fn parse_and(parser: &mut Parser) -> Result<Expr, ParseError> {
+    let mut expr = parse_equality(parser)?;
+
+    while parser.r#match(TokenKind::AndAnd) {
+        let rhs = parse_equality(parser)?;
+        expr = binary(BinOp::And, expr, rhs);
+    }
+
+    Ok(expr)
+}
+
+// == !=
+fn parse_equality(parser: &mut Parser) -> Result<Expr, ParseError> {
+    let mut expr = parse_comparison(parser)?;
+
+    loop {
+        let op = match parser.current_kind() {
+            TokenKind::EqualsEquals => BinOp::Eq,
+            TokenKind::BangEquals => BinOp::Ne,
+            _ => break,
+        };
+        parser.advance()?;
+        let rhs = parse_comparison(parser)?;
+        expr = binary(op, expr, rhs);
+    }
+
+    Ok(expr)
+}
+
+// < <= > >=
+fn parse_comparison(parser: &mut Parser) -> Result<Expr, ParseError> {
+    let mut expr = parse_bit_or(parser)?;
+
+    loop {
+        let op = match parser.current_kind() {
+            TokenKind::Less => BinOp::Lt,
+            TokenKind::LessEquals => BinOp::Le,
+            TokenKind::Greater => BinOp::Gt,
+            TokenKind::GreaterEquals => BinOp::Ge,
+            _ => break,
+        };
+        parser.advance()?;
+        let rhs = parse_bit_or(parser)?;
+        expr = binary(op, expr, rhs);
+    }
+
+    Ok(expr)
+}
+
+// |
+// Bitwise operators bind tighter than comparisons, so `flags & MASK == 0`
+// tests the masked value.
+fn parse_bit_or(parser: &mut Parser) -> Result<Expr, ParseError> {
+    let mut expr = parse_bit_xor(parser)?;
+
+    while parser.r#match(TokenKind::Pipe) {
+        let rhs = parse_bit_xor(parser)?;
+        expr = binary(BinOp::BitOr, expr, rhs);
+    }
+
+    Ok(expr)
+}
+
+// ^
+fn parse_bit_xor(parser: &mut Parser) -> Result<Expr, ParseError> {
+    let mut expr = parse_bit_and(parser)?;
+
+    while parser.r#match(TokenKind::Caret) {
+        let rhs = parse_bit_and(parser)?;
+        expr = binary(BinOp::BitXor, expr, rhs);
+    }
+
+    Ok(expr)
+}
+
+// &
+fn parse_bit_and(parser: &mut Parser) -> Result<Expr, ParseError> {
+    let mut expr = parse_shift(parser)?;
+
+    while parser.r#match(TokenKind::Amp) {
+        let rhs = parse_shift(parser)?;
+        expr = binary(BinOp::BitAnd, expr, rhs);
+    }
+
+    Ok(expr)
+}
+
+// << >>
+fn parse_shift(parser: &mut Parser) -> Result<Expr, ParseError> {
+    let mut expr = parse_add(parser)?;
+
+    loop {
+        let op = match parser.current_kind() {
+            TokenKind::Shl => BinOp::Shl,
+            TokenKind::Shr => BinOp::Shr,
+            _ => break,
+        };
+        parser.advance()?;
+        let rhs = parse_add(parser)?;
+        expr = binary(op, expr, rhs);
+    }
+
+    Ok(expr)
+}
+
+fn binary(op: BinOp, left: Expr, right: Expr) -> Expr {
+    let loc = left.loc;
+    Expr {
+        kind: ExprKind::Binary(BinaryExpr {
+            op,
+            left: Box::new(left),
+            right: Box::new(right),
+        }),
+        loc,
+    }
 }
 
 fn parse_add(parser: &mut Parser) -> Result<Expr, ParseError> {
@@ -237,11 +484,11 @@ fn parse_add(parser: &mut Parser) -> Result<Expr, ParseError> {
 
 // * / %
 fn parse_mul(parser: &mut Parser) -> Result<Expr, ParseError> {
-    let mut expr = parse_unary(parser)?;
+    let mut expr = parse_cast(parser)?;
 
     loop {
         if parser.r#match(TokenKind::Star) {
-            let rhs = parse_unary(parser)?;
+            let rhs = parse_cast(parser)?;
             let loc = expr.loc;
             expr = Expr {
                 kind: ExprKind::Binary(crate::ast::BinaryExpr {
@@ -255,7 +502,7 @@ fn parse_mul(parser: &mut Parser) -> Result<Expr, ParseError> {
         }
 
         if parser.r#match(TokenKind::Slash) {
-            let rhs = parse_unary(parser)?;
+            let rhs = parse_cast(parser)?;
             let loc = expr.loc;
             expr = Expr {
                 kind: ExprKind::Binary(crate::ast::BinaryExpr {
@@ -269,7 +516,7 @@ fn parse_mul(parser: &mut Parser) -> Result<Expr, ParseError> {
         }
 
         if parser.r#match(TokenKind::Percent) {
-            let rhs = parse_unary(parser)?;
+            let rhs = parse_cast(parser)?;
             let loc = expr.loc;
             expr = Expr {
                 kind: ExprKind::Binary(crate::ast::BinaryExpr {
@@ -288,8 +535,45 @@ fn parse_mul(parser: &mut Parser) -> Result<Expr, ParseError> {
     Ok(expr)
 }
 
-// unary: *expr (dereference)
+// expr as type
+fn parse_cast(parser: &mut Parser) -> Result<Expr, ParseError> {
+    let mut expr = parse_unary(parser)?;
+
+    while parser.r#match(TokenKind::KeywordAs) {
+        let ty = parse_type(parser)?;
+        let loc = expr.loc;
+        expr = Expr {
+            kind: ExprKind::Cast(CastExpr {
+                expr: Box::new(expr),
+                ty,
+            }),
+            loc,
+        };
+    }
+
+    Ok(expr)
+}
+
+// unary: *expr (dereference), !expr, ~expr
 fn parse_unary(parser: &mut Parser) -> Result<Expr, ParseError> {
+    let op_loc = parser.current_loc();
+    let op = match parser.current_kind() {
+        TokenKind::Bang => Some(UnaryOp::Not),
+        TokenKind::Tilde => Some(UnaryOp::BitNot),
+        _ => None,
+    };
+    if let Some(op) = op {
+        parser.advance()?;
+        let inner = parse_unary(parser)?;
+        return Ok(Expr {
+            kind: ExprKind::Unary(UnaryExpr {
+                op,
+                operand: Box::new(inner),
+            }),
+            loc: op_loc,
+        });
+    }
+
     if parser.r#match(TokenKind::Star) {
         let inner = parse_unary(parser)?;
         let inner_loc = inner.loc;
@@ -299,7 +583,27 @@ fn parse_unary(parser: &mut Parser) -> Result<Expr, ParseError> {
         });
     }
 
-    parse_primary(parser)
+    parse_postfix(parser)
+}
+
+// postfix: expr[index]
+fn parse_postfix(parser: &mut Parser) -> Result<Expr, ParseError> {
+    let mut expr = parse_primary(parser)?;
+
+    while parser.r#match(TokenKind::LBracket) {
+        let index = parse_expr(parser)?;
+        expect_token(parser, TokenKind::RBracket)?;
+        let loc = expr.loc;
+        expr = Expr {
+            kind: ExprKind::Index(IndexExpr {
+                base: Box::new(expr),
+                index: Box::new(index),
+            }),
+            loc,
+        };
+    }
+
+    Ok(expr)
 }
 
 fn parse_primary(parser: &mut Parser) -> Result<Expr, ParseError> {
@@ -316,6 +620,47 @@ fn parse_primary(parser: &mut Parser) -> Result<Expr, ParseError> {
         });
     }
 
+    // 10.0.0.0/8, 2001:db8::/32
+    if parser.check(TokenKind::CidrLiteral) {
+        let cidr_tok = parser.expect(TokenKind::CidrLiteral)?;
+        let cidr = parse_cidr(&cidr_tok.lexeme).map_err(|message| {
+            ParseError::new(cidr_tok.loc, message)
+                .with_help("Write an address and a prefix length, like `10.0.0.0/8` or `2001:db8::/32`")
+        })?;
+
+        return Ok(Expr {
+            kind: ExprKind::Cidr(cidr),
+            loc: cidr_tok.loc,
+        });
+    }
+
+    // redirect(map, key, fallback)
+    let redirect_loc = parser.current_loc();
+    if parser.r#match(TokenKind::KeywordRedirect) {
+        expect_token(parser, TokenKind::LParen)?;
+        let map_tok = parser.expect(TokenKind::Identifier)?;
+        expect_token(parser, TokenKind::Comma)?;
+        let key = parse_expr(parser)?;
+        expect_token(parser, TokenKind::Comma)?;
+        let fallback = parse_expr(parser)?;
+        expect_token(parser, TokenKind::RParen)?;
+
+        return Ok(Expr {
+            kind: ExprKind::Redirect(Redirect {
+                map_name: map_tok.lexeme,
+                key: Box::new(key),
+                fallback: Box::new(fallback),
+            }),
+            loc: redirect_loc,
+        });
+    }
+
+    // atomic xchg(place, value), atomic cmpxchg(place, old, new)
+    let atomic_loc = parser.current_loc();
+    if parser.r#match(TokenKind::KeywordAtomic) {
+        return parse_atomic_call(parser, atomic_loc);
+    }
+
     // (expr)
     if parser.r#match(TokenKind::LParen) {
         let e = parse_expr(parser)?;
@@ -326,18 +671,55 @@ fn parse_primary(parser: &mut Parser) -> Result<Expr, ParseError> {
     // identifier or method call
     let receiver_tok = parser.expect(TokenKind::Identifier)?;
 
-    // method call: receiver.method(arg)
+    // method call: receiver.method(args), or field access: ptr.field
     if parser.r#match(TokenKind::Dot) {
         let method_tok = parser.expect(TokenKind::Identifier)?;
-        expect_token(parser, TokenKind::LParen)?;
-        let arg = parse_expr(parser)?;
-        expect_token(parser, TokenKind::RParen)?;
+        if !parser.r#match(TokenKind::LParen) {
+            return Ok(Expr {
+                kind: ExprKind::Field(FieldAccess {
+                    base: Box::new(Expr {
+                        kind: ExprKind::Variable(receiver_tok.lexeme),
+                        loc: receiver_tok.loc,
+                    }),
+                    field: method_tok.lexeme,
+                }),
+                loc: receiver_tok.loc,
+            });
+        }
+        let loc = receiver_tok.loc;
+        let call = parse_method_call(parser, receiver_tok.lexeme, method_tok.lexeme)?;
+
+        return Ok(Expr {
+            kind: ExprKind::MethodCall(call),
+            loc,
+        });
+    }
+
+    // struct literal: name { field: value, ... }
+    if parser.struct_literals() && parser.r#match(TokenKind::LBrace) {
+        let mut fields = Vec::new();
+        while !parser.check(TokenKind::RBrace) {
+            let field_loc = parser.current_loc();
+            let field_tok = parser.expect(TokenKind::Identifier)?;
+            expect_token(parser, TokenKind::Colon)?;
+            let value = parse_expr(parser)?;
+
+            fields.push(FieldInit {
+                name: field_tok.lexeme,
+                value,
+                loc: field_loc,
+            });
+
+            if !parser.r#match(TokenKind::Comma) {
+                break;
+            }
+        }
+        expect_token(parser, TokenKind::RBrace)?;
 
         return Ok(Expr {
-            kind: ExprKind::MethodCall(MethodCall {
-                receiver: receiver_tok.lexeme,
-                method: method_tok.lexeme,
-                arg: Box::new(arg),
+            kind: ExprKind::StructLiteral(StructLiteral {
+                name: receiver_tok.lexeme,
+                fields,
             }),
             loc: receiver_tok.loc,
         });
@@ -348,4 +730,254 @@ fn parse_primary(parser: &mut Parser) -> Result<Expr, ParseError> {
         kind: ExprKind::Variable(receiver_tok.lexeme),
         loc: receiver_tok.loc,
     })
-}
\ No newline at end of file
+}
+
+// the text of a CIDR literal token: `address/prefix_len`
+fn parse_cidr(text: &str) -> Result<Cidr, String> {
+    let (addr, prefix_len) = text
+        .split_once('/')
+        .ok_or_else(|| format!("Invalid CIDR literal '{}'", text))?;
+    let addr: IpAddr = addr
+        .parse()
+        .map_err(|_| format!("Invalid address '{}' in CIDR literal", addr))?;
+    let bits = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix_len = prefix_len
+        .parse::<u8>()
+        .ok()
+        .filter(|&n| n <= bits)
+        .ok_or_else(|| format!("Prefix length {} is longer than the {} bits of the address", prefix_len, bits))?;
+    Ok(Cidr { addr, prefix_len })
+}
+
+// after `atomic`: xchg(place, value) or cmpxchg(place, old, new)
+fn parse_atomic_call(parser: &mut Parser, loc: crate::parser::SourceLoc) -> Result<Expr, ParseError> {
+    let op_tok = parser.expect(TokenKind::Identifier)?;
+    let op = match op_tok.lexeme.as_str() {
+        "xchg" => AtomicOp::Xchg,
+        "cmpxchg" => AtomicOp::Cmpxchg,
+        other => {
+            return Err(parser.error_with_help(
+                format!("Unknown atomic operation '{}'", other),
+                "Expected xchg or cmpxchg; updates are written `atomic *p += n`",
+            ));
+        }
+    };
+    expect_token(parser, TokenKind::LParen)?;
+    let place = parse_expr(parser)?;
+    let mut args = Vec::new();
+    for _ in 0..op.arity() {
+        expect_token(parser, TokenKind::Comma)?;
+        args.push(parse_expr(parser)?);
+    }
+    expect_token(parser, TokenKind::RParen)?;
+
+    Ok(Expr {
+        kind: ExprKind::Atomic(AtomicExpr {
+            op,
+            place: Box::new(place),
+            args,
+        }),
+        loc,
+    })
+}
+
+// after `(`: the arguments of `receiver.method(...)` up to `)`. A ring
+// buffer's `reserve` takes the record type instead of values.
+fn parse_method_call(parser: &mut Parser, receiver: String, method: String) -> Result<MethodCall, ParseError> {
+    if method == "reserve" {
+        let type_arg = if parser.check(TokenKind::RParen) {
+            None
+        } else {
+            Some(parse_data_type(parser)?)
+        };
+        expect_token(parser, TokenKind::RParen)?;
+        return Ok(MethodCall { receiver, method, type_arg, args: Vec::new(), inner_key: None });
+    }
+
+    let args = parse_call_args(parser)?;
+
+    // outer.lookup(k).lookup(inner_key)
+    let mut inner_key = None;
+    if parser.r#match(TokenKind::Dot) {
+        let inner_tok = parser.expect(TokenKind::Identifier)?;
+        if inner_tok.lexeme != "lookup" {
+            return Err(ParseError::new(inner_tok.loc, format!("Unexpected method '{}' on a map lookup", inner_tok.lexeme))
+                .with_help("Only a lookup in a map of maps can be followed, by `.lookup(key)`"));
+        }
+        expect_token(parser, TokenKind::LParen)?;
+        inner_key = Some(Box::new(parse_expr(parser)?));
+        expect_token(parser, TokenKind::RParen)?;
+    }
+    Ok(MethodCall { receiver, method, type_arg: None, args, inner_key })
+}
+
+// after `(`: comma-separated arguments up to `)`
+fn parse_call_args(parser: &mut Parser) -> Result<Vec<Expr>, ParseError> {
+    let mut args = Vec::new();
+    while !parser.check(TokenKind::RParen) {
+        args.push(parse_expr(parser)?);
+        if !parser.r#match(TokenKind::Comma) {
+            break;
+        }
+    }
+    expect_token(parser, TokenKind::RParen)?;
+    Ok(args)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ast::{Expr, ExprKind, IfGuard, StmtKind, Unit};
+    use crate::parser::parse;
+
+    fn unit(body: &str) -> Unit {
+        let src = format!("unit u {{\n    section: \"xdp\";\n{}\n}}\n", body);
+        let mut program = parse(&src).unwrap_or_else(|e| panic!("{}: {}", e.message, src));
+        program.units.remove(0)
+    }
+
+    fn guard(body: &str) -> IfGuard {
+        match unit(body).body.remove(0).kind {
+            StmtKind::IfGuard(guard) => guard,
+            other => panic!("expected a guard, found {:?}", other),
+        }
+    }
+
+    // `(op left right)`, so tests can spell out how an expression grouped
+    fn shape(expr: &Expr) -> String {
+        match &expr.kind {
+            ExprKind::Binary(b) => format!("({:?} {} {})", b.op, shape(&b.left), shape(&b.right)),
+            ExprKind::Unary(u) => format!("({:?} {})", u.op, shape(&u.operand)),
+            ExprKind::Variable(name) => name.clone(),
+            ExprKind::Number(n) => n.to_string(),
+            other => format!("{:?}", other),
+        }
+    }
+
+    #[test]
+    fn condition_operators_follow_c_precedence() {
+        let g = guard("if guard(a < b && !(a == 3) || b >= 7) { return 1; }");
+        assert_eq!(shape(&g.condition), "(Or (And (Lt a b) (Not (Eq a 3))) (Ge b 7))");
+
+        let g = guard("if guard(a != 1 && b <= 2 && c > 3) { return 1; }");
+        assert_eq!(shape(&g.condition), "(And (And (Ne a 1) (Le b 2)) (Gt c 3))");
+    }
+
+    #[test]
+    fn incomplete_condition_is_rejected() {
+        let src = "unit u {\n    section: \"xdp\";\n    if guard(a <) { return 1; }\n}\n";
+        assert!(parse(src).is_err());
+        let src = "unit u {\n    section: \"xdp\";\n    if guard(a && ) { return 1; }\n}\n";
+        assert!(parse(src).is_err());
+    }
+
+    #[test]
+    fn else_if_nests_in_the_else_arm() {
+        let g = guard("if guard(a == 1) { return 1; } else if guard(a == 2) { return 2; } else { return 3; }");
+        assert_eq!(g.body.len(), 1);
+        assert_eq!(g.else_body.len(), 1);
+        let inner = match &g.else_body[0].kind {
+            StmtKind::IfGuard(inner) => inner,
+            other => panic!("expected a nested guard, found {:?}", other),
+        };
+        assert_eq!(shape(&inner.condition), "(Eq a 2)");
+        assert_eq!(inner.else_body.len(), 1);
+    }
+
+    #[test]
+    fn else_needs_a_block_or_an_if() {
+        let src = "unit u {\n    section: \"xdp\";\n    if guard(a) { return 1; } else return 2;\n}\n";
+        assert!(parse(src).is_err());
+        let src = "unit u {\n    section: \"xdp\";\n    else { return 2; }\n}\n";
+        assert!(parse(src).is_err());
+    }
+
+    #[test]
+    fn fallback_is_only_a_field_before_a_colon() {
+        let u = unit("    fallback: XDP_DROP;\n    reg fallback = 2;\n    fallback += 1;\n    return fallback;");
+        assert_eq!(u.fallback.as_deref(), Some("XDP_DROP"));
+        assert_eq!(u.body.len(), 3);
+
+        let src = "unit u {\n    section: \"xdp\";\n    fallback XDP_DROP;\n}\n";
+        assert!(parse(src).is_err());
+    }
+
+    #[test]
+    fn bitwise_operators_bind_tighter_than_comparisons() {
+        let g = guard("if guard(flags & 4 == 0 || a | b ^ c & d != 1 << 2 + 1) { return 1; }");
+        assert_eq!(
+            shape(&g.condition),
+            "(Or (Eq (BitAnd flags 4) 0) (Ne (BitOr a (BitXor b (BitAnd c d))) (Shl 1 (Add 2 1))))"
+        );
+    }
+
+    #[test]
+    fn every_compound_assignment_parses() {
+        use crate::ast::AssignmentOp::*;
+        let body = "    x = 1;\n    x += 1;\n    x -= 1;\n    x *= 1;\n    x /= 1;\n    x %= 1;\n    x &= 1;\n    x |= 1;\n    x ^= 1;\n    x <<= 1;\n    x >>= 1;";
+        let ops: Vec<_> = unit(body)
+            .body
+            .into_iter()
+            .map(|stmt| match stmt.kind {
+                StmtKind::Assignment(assign) => assign.op,
+                other => panic!("expected an assignment, found {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            ops,
+            [Assign, AddAssign, SubAssign, MulAssign, DivAssign, ModAssign, AndAssign, OrAssign, XorAssign, ShlAssign, ShrAssign]
+        );
+    }
+
+    #[test]
+    fn compound_assignment_needs_a_value() {
+        let src = "unit u {\n    section: \"xdp\";\n    x -= ;\n}\n";
+        assert!(parse(src).is_err());
+        let src = "unit u {\n    section: \"xdp\";\n    x =- 1\n}\n";
+        assert!(parse(src).is_err());
+    }
+
+    #[test]
+    fn cidr_literal_holds_address_and_prefix() {
+        let u = unit("    return deny.lookup(192.168.0.0/16);");
+        let StmtKind::Return(value) = &u.body[0].kind else {
+            panic!("expected a return, found {:?}", u.body[0].kind);
+        };
+        let ExprKind::MethodCall(call) = &value.kind else {
+            panic!("expected a lookup, found {:?}", value.kind);
+        };
+        let ExprKind::Cidr(cidr) = &call.args[0].kind else {
+            panic!("expected a CIDR literal, found {:?}", call.args[0].kind);
+        };
+        assert_eq!(cidr.prefix_len, 16);
+        assert_eq!(cidr.octets(), [192, 168, 0, 0]);
+    }
+
+    #[test]
+    fn invalid_cidr_literal_is_rejected() {
+        for (cidr, message) in [
+            ("300.0.0.0/8", "Invalid address '300.0.0.0' in CIDR literal"),
+            ("10.0.0.0/33", "Prefix length 33 is longer than the 32 bits of the address"),
+            ("2001:db8::/129", "Prefix length 129 is longer than the 128 bits of the address"),
+        ] {
+            let src = format!("unit u {{\n    section: \"xdp\";\n    return deny.lookup({});\n}}\n", cidr);
+            assert_eq!(parse(&src).map(|_| ()).unwrap_err().message, message);
+        }
+    }
+
+    #[test]
+    fn chained_lookup_keys_the_inner_map() {
+        let u = unit("    return tenants.lookup(t).lookup(dst);");
+        let StmtKind::Return(value) = &u.body[0].kind else {
+            panic!("expected a return, found {:?}", u.body[0].kind);
+        };
+        let ExprKind::MethodCall(call) = &value.kind else {
+            panic!("expected a lookup, found {:?}", value.kind);
+        };
+        assert_eq!((call.receiver.as_str(), call.method.as_str()), ("tenants", "lookup"));
+        assert_eq!(shape(&call.args[0]), "t");
+        assert_eq!(call.inner_key.as_deref().map(shape).as_deref(), Some("dst"));
+
+        let src = "unit u {\n    section: \"xdp\";\n    return tenants.lookup(t).delete(dst);\n}\n";
+        assert!(parse(src).is_err());
+    }
+}
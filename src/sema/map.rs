@@ -1,56 +1,564 @@
 
-use crate::ast::{MapDecl, MapType, Type};
-use crate::diagnostics::{DiagnosticReporter, Severity};
+use crate::ast::structs::field_layout;
+use crate::ast::{DataType, MapDecl, MapFlag, MapType, StructDecl, Unit};
+use crate::diagnostics::DiagnosticReporter;
 use crate::parser::SourceLoc;
-use std::collections::HashSet;
-    
+use crate::sema::{check_name, SectionValidator};
+use std::collections::{HashMap, HashSet};
 
-#[derive(Debug, thiserror::Error)]
-pub enum MapValidationError {
-    #[error("Duplicate map name: {0}")]
-    DuplicateMapName(String),
-    
-    #[error("Map 'max_entries' must be greater than zero")]
-    InvalidMaxEntries,
-    
-    #[error("Invalid map type")]
-    InvalidType,
-}
+/// Page size ring buffer sizes are checked against. Arm64 and ppc64 kernels
+/// can be built with larger pages; 4 KiB is the common case.
+const PAGE_SIZE: u32 = 4096;
 
+/// Largest per-CPU value the kernel allocates (`PCPU_MIN_UNIT_SIZE`).
+const MAX_PERCPU_VALUE_SIZE: u32 = 32 * 1024;
 
 pub fn check_map(
     map_decl: &MapDecl,
+    structs: &HashMap<&str, &StructDecl>,
     diagnostics: &mut DiagnosticReporter,
     map_names: &mut HashSet<String>,
-) -> Result<(), MapValidationError> {
+) {
     if !map_names.insert(map_decl.name.clone()) {
         diagnostics.report_error(
             format!("Duplicate map name: '{}'", map_decl.name),
             map_decl.loc,
         );
-        return Err(MapValidationError::DuplicateMapName(map_decl.name.clone()));
     }
+    check_name("Map", &map_decl.name, map_decl.loc, diagnostics);
 
-    if map_decl.max_entries == 0 {
+    // Zero sizes a perf event array to the number of CPUs.
+    if map_decl.max_entries == 0 && map_decl.map_type != MapType::PerfEventArray {
         diagnostics.report_error(
             "Map 'max_entries' must be greater than zero",
             map_decl.loc,
         );
-        return Err(MapValidationError::InvalidMaxEntries);
     }
 
-    match map_decl.key_type {
-        Type::U32 | Type::U64 | Type::I32 | Type::I64 => {} // Valid
+    // The data area is mapped as whole pages, and the kernel only accepts a
+    // power-of-two number of them.
+    let max = map_decl.max_entries;
+    if map_decl.map_type == MapType::Ringbuf && max != 0 && !(max.is_power_of_two() && max >= PAGE_SIZE) {
+        diagnostics
+            .report_error(
+                format!(
+                    "Ring buffer '{}' size must be a power-of-two multiple of {} bytes, found {}",
+                    map_decl.name, PAGE_SIZE, max
+                ),
+                map_decl.loc,
+            )
+            .with_help(format!("Use `max: {};`", max.max(PAGE_SIZE).checked_next_power_of_two().unwrap_or(1 << 31)));
+    }
+
+    let key_size = match &map_decl.key_type {
+        Some(key_type) if !map_decl.map_type.has_key() => {
+            report_unexpected_type(map_decl, "key", key_type, diagnostics);
+            None
+        }
+        Some(key_type) => resolve_size(key_type, structs, map_decl.loc, diagnostics),
+        None => None,
+    };
+    let value_size = match &map_decl.value_type {
+        Some(value_type) if !map_decl.map_type.has_value() => {
+            report_unexpected_type(map_decl, "value", value_type, diagnostics);
+            None
+        }
+        Some(value_type) => resolve_size(value_type, structs, map_decl.loc, diagnostics),
+        None => None,
+    };
+
+    // Array-like maps are indexed by a 32-bit slot number; the kernel
+    // rejects any other key size when the map is created. A devmap_hash
+    // is keyed by any 32-bit number, usually the ifindex itself.
+    let indexed = matches!(
+        map_decl.map_type,
+        MapType::Array
+            | MapType::PercpuArray
+            | MapType::ProgArray
+            | MapType::PerfEventArray
+            | MapType::ArrayOfMaps
+            | MapType::Devmap
+            | MapType::DevmapHash
+            | MapType::Cpumap
+            | MapType::Xskmap
+            | MapType::Sockmap
+    );
+    if let (true, Some(key_type), Some(size)) = (indexed, &map_decl.key_type, key_size) {
+        if size != 4 {
+            diagnostics
+                .report_error(
+                    format!(
+                        "Map '{}' is indexed by slot number and needs a 4-byte key, found {}",
+                        map_decl.name, key_type
+                    ),
+                    map_decl.loc,
+                )
+                .with_help("Use `key: u32`");
+        }
+    }
+
+    if let (true, Some(value_type), Some(size)) = (map_decl.map_type.is_percpu(), &map_decl.value_type, value_size) {
+        if size > MAX_PERCPU_VALUE_SIZE {
+            diagnostics
+                .report_error(
+                    format!(
+                        "Per-CPU map '{}' value {} is {} bytes, more than the kernel's limit of {}",
+                        map_decl.name, value_type, size, MAX_PERCPU_VALUE_SIZE
+                    ),
+                    map_decl.loc,
+                )
+                .with_help("Shrink the value, or use a map that is not per-CPU");
+        }
     }
 
-    match map_decl.value_type {
-        Type::U32 | Type::U64 | Type::I32 | Type::I64 => {} // Valid
+    if let (MapType::LpmTrie, Some(key_type)) = (map_decl.map_type, &map_decl.key_type) {
+        check_lpm_key(map_decl, key_type, structs, diagnostics);
     }
-    
-    match map_decl.map_type {
-        MapType::Hash | MapType::Array | MapType::Ringbuf | 
-        MapType::LruHash | MapType::ProgArray | MapType::PerfEventArray => {} // Valid
+
+    check_flags(map_decl, diagnostics);
+    if map_decl.numa_node.is_some() && map_decl.map_type.is_percpu() {
+        diagnostics
+            .report_error(
+                format!("Per-CPU map '{}' cannot be placed on a NUMA node", map_decl.name),
+                map_decl.loc,
+            )
+            .with_help("Each CPU's values are allocated on its own node; remove `numa_node`");
+    }
+
+    if let (Some((holds, sizes)), Some(value_type), Some(size)) =
+        (fixed_value_layout(map_decl.map_type), &map_decl.value_type, value_size)
+    {
+        if !sizes.contains(&size) {
+            let expected = match sizes {
+                [4] => "a 4-byte value",
+                _ => "a 4- or 8-byte value",
+            };
+            diagnostics
+                .report_error(
+                    format!("Map '{}' holds {} and needs {}, found {}", map_decl.name, holds, expected, value_type),
+                    map_decl.loc,
+                )
+                .with_help("Use `value: u32`, or leave out `value`");
+        }
+    }
+}
+
+/// What each slot of a map the kernel fills in itself holds, and the value
+/// sizes it accepts. Redirect maps take an optional program fd after the
+/// target, run on the redirected packet.
+fn fixed_value_layout(map_type: MapType) -> Option<(&'static str, &'static [u32])> {
+    let layout: (&str, &[u32]) = match map_type {
+        MapType::PerfEventArray => ("perf event fds", &[4]),
+        MapType::ProgArray => ("program fds", &[4]),
+        MapType::Devmap | MapType::DevmapHash => ("ifindexes", &[4, 8]),
+        MapType::Cpumap => ("CPU queue sizes", &[4, 8]),
+        MapType::Xskmap => ("AF_XDP socket fds", &[4]),
+        MapType::Sockmap | MapType::Sockhash => ("socket fds", &[4, 8]),
+        _ => return None,
+    };
+    Some(layout)
+}
+
+/// Checks the `programs` a prog_array is filled with: each names a unit and
+/// fits in the map, and all are of one program type, since a tail call
+/// cannot change it.
+pub fn check_programs(
+    map_decl: &MapDecl,
+    units: &HashMap<&str, &Unit>,
+    diagnostics: &mut DiagnosticReporter,
+) {
+    if map_decl.programs.is_empty() {
+        return;
+    }
+    if map_decl.map_type != MapType::ProgArray {
+        diagnostics
+            .report_error(
+                format!("Map '{}' is a {} and cannot list programs", map_decl.name, map_decl.map_type),
+                map_decl.loc,
+            )
+            .with_help("Only `prog_array` maps hold programs");
+        return;
+    }
+
+    let mut indices = HashSet::new();
+    let mut first: Option<(&str, &str)> = None;
+    for slot in &map_decl.programs {
+        if slot.index >= map_decl.max_entries {
+            diagnostics.report_error(
+                format!(
+                    "Program index {} is out of range for map '{}' with {} entries",
+                    slot.index, map_decl.name, map_decl.max_entries
+                ),
+                slot.loc,
+            );
+        }
+        if !indices.insert(slot.index) {
+            diagnostics.report_error(
+                format!("Duplicate program index {} in map '{}'", slot.index, map_decl.name),
+                slot.loc,
+            );
+        }
+
+        let Some(unit) = units.get(slot.unit.as_str()) else {
+            diagnostics.report_error(format!("Unknown unit: '{}'", slot.unit), slot.loc);
+            continue;
+        };
+        let Some(ty) = unit.sections.first().and_then(|s| SectionValidator::program_type(s)) else {
+            continue;
+        };
+        match first {
+            None => first = Some((&slot.unit, ty)),
+            Some((other, other_ty)) if other_ty != ty => {
+                diagnostics
+                    .report_error(
+                        format!(
+                            "Unit '{}' is a {} program but '{}' in the same prog_array is a {} program",
+                            slot.unit, ty, other, other_ty
+                        ),
+                        slot.loc,
+                    )
+                    .with_help("A tail call cannot change the program type");
+            }
+            Some(_) => {}
+        }
+    }
+}
+
+/// Checks the `inner` prototype of a map of maps names a map that can be
+/// stored in one.
+pub fn check_inner(map_decl: &MapDecl, maps: &HashMap<&str, &MapDecl>, diagnostics: &mut DiagnosticReporter) {
+    let Some(inner) = &map_decl.inner else {
+        return;
+    };
+    if !map_decl.map_type.is_map_of_maps() {
+        diagnostics
+            .report_error(
+                format!("Map '{}' is a {} and has no inner map", map_decl.name, map_decl.map_type),
+                map_decl.loc,
+            )
+            .with_help("Only `array_of_maps` and `hash_of_maps` take `inner`");
+        return;
     }
 
-    Ok(())
-}
\ No newline at end of file
+    let Some(prototype) = maps.get(inner.as_str()) else {
+        diagnostics.report_error(format!("Unknown map: '{}'", inner), map_decl.loc);
+        return;
+    };
+    // The kernel does not nest maps of maps, and a program only reaches an
+    // inner map through `lookup`, which a prog_array does not have.
+    if prototype.map_type.is_map_of_maps() || prototype.map_type == MapType::ProgArray {
+        diagnostics
+            .report_error(
+                format!(
+                    "Map '{}' cannot hold maps like '{}', a {}",
+                    map_decl.name, inner, prototype.map_type
+                ),
+                map_decl.loc,
+            )
+            .with_help("Inner maps cannot be maps of maps or prog_arrays");
+    }
+}
+
+/// Map types whose creation accepts `flag`.
+fn flag_map_types(flag: MapFlag) -> &'static [MapType] {
+    match flag {
+        MapFlag::NoPrealloc => &[MapType::Hash, MapType::PercpuHash, MapType::LpmTrie],
+        MapFlag::NoCommonLru => &[MapType::LruHash, MapType::LruPercpuHash],
+        MapFlag::RdonlyProg | MapFlag::WronlyProg => &[
+            MapType::Hash,
+            MapType::Array,
+            MapType::LruHash,
+            MapType::PercpuHash,
+            MapType::PercpuArray,
+            MapType::LruPercpuHash,
+            MapType::LpmTrie,
+            MapType::Queue,
+            MapType::Stack,
+        ],
+        // Programs never access the slots of fd arrays directly, but they
+        // can still be read-only or write-only to userspace.
+        MapFlag::Rdonly | MapFlag::Wronly => &[
+            MapType::Hash,
+            MapType::Array,
+            MapType::LruHash,
+            MapType::PercpuHash,
+            MapType::PercpuArray,
+            MapType::LruPercpuHash,
+            MapType::LpmTrie,
+            MapType::Queue,
+            MapType::Stack,
+            MapType::ProgArray,
+            MapType::PerfEventArray,
+            MapType::Devmap,
+            MapType::DevmapHash,
+            MapType::Xskmap,
+            MapType::Sockmap,
+            MapType::Sockhash,
+        ],
+        MapFlag::Mmapable => &[MapType::Array],
+    }
+}
+
+/// Checks each flag is accepted by the map type, and that no flag is
+/// repeated or contradicts another.
+fn check_flags(map_decl: &MapDecl, diagnostics: &mut DiagnosticReporter) {
+    let mut seen = HashSet::new();
+    for &flag in &map_decl.flags {
+        if !seen.insert(flag) {
+            diagnostics.report_error(
+                format!("Duplicate flag '{}' in map '{}'", flag, map_decl.name),
+                map_decl.loc,
+            );
+            continue;
+        }
+
+        let types = flag_map_types(flag);
+        if !types.contains(&map_decl.map_type) {
+            let names: Vec<String> = types.iter().map(|t| t.to_string()).collect();
+            diagnostics
+                .report_error(
+                    format!("Map '{}' is a {} and does not take flag '{}'", map_decl.name, map_decl.map_type, flag),
+                    map_decl.loc,
+                )
+                .with_help(format!("`{}` applies to: {}", flag, names.join(", ")));
+        }
+    }
+
+    for (a, b) in [(MapFlag::Rdonly, MapFlag::Wronly), (MapFlag::RdonlyProg, MapFlag::WronlyProg)] {
+        if seen.contains(&a) && seen.contains(&b) {
+            diagnostics
+                .report_error(
+                    format!("Map '{}' cannot be both {} and {}", map_decl.name, a, b),
+                    map_decl.loc,
+                )
+                .with_help(format!("Keep one of `{}` and `{}`", a, b));
+        }
+    }
+}
+
+/// An `lpm_trie` key is a `prefixlen: u32` immediately followed by 1 to 256
+/// bytes of address, with no padding anywhere.
+fn check_lpm_key(
+    map_decl: &MapDecl,
+    key_type: &DataType,
+    structs: &HashMap<&str, &StructDecl>,
+    diagnostics: &mut DiagnosticReporter,
+) {
+    let decl = match key_type {
+        DataType::Struct(name) => match structs.get(name.as_str()) {
+            Some(decl) => decl,
+            None => return,
+        },
+        _ => {
+            diagnostics
+                .report_error(
+                    format!("LPM trie '{}' needs a prefix key struct, found {}", map_decl.name, key_type),
+                    map_decl.loc,
+                )
+                .with_help("Declare `struct prefix_v4 { prefixlen: u32, addr: u32 }` and use `key: prefix_v4;`");
+            return;
+        }
+    };
+    let Some(data) = decl.lpm_data() else {
+        diagnostics
+            .report_error(
+                format!(
+                    "LPM trie '{}' key '{}' must be `prefixlen: u32` followed by one address field",
+                    map_decl.name, decl.name
+                ),
+                map_decl.loc,
+            )
+            .with_help("For example `struct prefix_v6 { prefixlen: u32, addr: u8[16] }`");
+        return;
+    };
+
+    let (size, align) = field_layout(&data.ty);
+    if align > 4 || !(1..=256).contains(&size) {
+        diagnostics
+            .report_error(
+                format!(
+                    "LPM trie '{}' address `{}` must be 1 to 256 bytes directly after `prefixlen`, found {}",
+                    map_decl.name, data.name, data.ty
+                ),
+                map_decl.loc,
+            )
+            .with_help("Use `u32` for IPv4 or `u8[16]` for IPv6");
+    }
+}
+
+/// Reports a `key` or `value` field on a map type that has none.
+fn report_unexpected_type(map_decl: &MapDecl, field: &str, ty: &DataType, diagnostics: &mut DiagnosticReporter) {
+    let reason = match map_decl.map_type {
+        MapType::Ringbuf => "records are typed by `reserve(T)` or `output(value)`",
+        MapType::ArrayOfMaps | MapType::HashOfMaps => "values are maps shaped like its `inner` prototype",
+        _ => "entries are pushed and popped",
+    };
+    diagnostics
+        .report_error(
+            format!("Map '{}' is a {} and has no {}, found {}", map_decl.name, map_decl.map_type, field, ty),
+            map_decl.loc,
+        )
+        .with_help(format!("Remove the `{}` field; {}", field, reason));
+}
+
+/// Size in bytes of a key or value type, reporting an unknown struct.
+fn resolve_size(
+    ty: &DataType,
+    structs: &HashMap<&str, &StructDecl>,
+    loc: SourceLoc,
+    diagnostics: &mut DiagnosticReporter,
+) -> Option<u32> {
+    match ty {
+        DataType::Scalar(ty) => Some(u32::from(ty.size())),
+        DataType::Array(elem, len) => Some(u32::from(elem.size()) * len),
+        DataType::Struct(name) => match structs.get(name.as_str()) {
+            Some(decl) => Some(decl.size()),
+            None => {
+                diagnostics
+                    .report_error(format!("Unknown type: '{}'", name), loc)
+                    .with_help("Declare it with `struct` or use a primitive type");
+                None
+            }
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::testing::{assert_error, errors};
+
+    fn ringbuf(max: u32) -> String {
+        format!(
+            "map events {{\n    type: .ringbuf;\n    max: {};\n}}\n\nunit t {{\n    section: \"xdp\";\n    license: \"GPL\";\n\n    events.output(1);\n    return 2;\n}}\n",
+            max
+        )
+    }
+
+    #[test]
+    fn ring_buffer_of_whole_pages_is_accepted() {
+        for max in [4096, 262144] {
+            let src = ringbuf(max);
+            assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+        }
+    }
+
+    #[test]
+    fn ring_buffer_size_is_checked() {
+        for max in [1024, 12288] {
+            assert_error(
+                &ringbuf(max),
+                &format!("Ring buffer 'events' size must be a power-of-two multiple of 4096 bytes, found {}", max),
+            );
+        }
+    }
+
+    #[test]
+    fn perf_event_array_holds_fds() {
+        let src = "map perf {\n    type: .perf_event_array;\n    value: u64;\n}\n";
+        assert_error(src, "Map 'perf' holds perf event fds and needs a 4-byte value, found u64");
+
+        let src = "map perf {\n    type: .perf_event_array;\n    value: u32;\n    max: 8;\n}\n";
+        assert!(errors(src).is_empty(), "{:?}", errors(src));
+    }
+
+    const UNITS: &str = "unit a {\n    section: \"xdp\";\n    license: \"GPL\";\n    return 2;\n}\n\n\
+                         unit b {\n    section: \"xdp\";\n    license: \"GPL\";\n    return 2;\n}\n\n\
+                         unit k {\n    section: \"kprobe/sys_open\";\n    license: \"GPL\";\n    return 0;\n}\n";
+
+    fn jumps(programs: &str) -> String {
+        format!("map jumps {{\n    type: .prog_array;\n    max: 2;\n    programs: [{}];\n}}\n\n{}", programs, UNITS)
+    }
+
+    #[test]
+    fn programs_of_one_type_are_accepted() {
+        let src = jumps("0: a, 1: b");
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn programs_are_checked_against_the_map() {
+        assert_error(&jumps("0: a, 0: b"), "Duplicate program index 0 in map 'jumps'");
+        assert_error(&jumps("2: a"), "Program index 2 is out of range for map 'jumps' with 2 entries");
+        assert_error(&jumps("0: missing"), "Unknown unit: 'missing'");
+        assert_error(
+            &jumps("0: a, 1: k"),
+            "Unit 'k' is a kprobe program but 'a' in the same prog_array is a xdp program",
+        );
+    }
+
+    fn trie(structs: &str, key: &str) -> String {
+        format!("{}\nmap deny {{\n    type: .lpm_trie;\n    key: {};\n    value: u32;\n    max: 16;\n}}\n", structs, key)
+    }
+
+    #[test]
+    fn lpm_trie_key_is_a_prefix_header_and_address() {
+        for addr in ["u32", "u8[16]"] {
+            let src = trie(&format!("struct prefix {{\n    prefixlen: u32,\n    addr: {}\n}}\n", addr), "prefix");
+            assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+        }
+    }
+
+    #[test]
+    fn lpm_trie_key_shape_is_checked() {
+        assert_error(&trie("", "u32"), "LPM trie 'deny' needs a prefix key struct, found u32");
+        assert_error(
+            &trie("struct flat {\n    addr: u32,\n    prefixlen: u32\n}\n", "flat"),
+            "LPM trie 'deny' key 'flat' must be `prefixlen: u32` followed by one address field",
+        );
+        assert_error(
+            &trie("struct wide {\n    prefixlen: u32,\n    addr: u64\n}\n", "wide"),
+            "LPM trie 'deny' address `addr` must be 1 to 256 bytes directly after `prefixlen`",
+        );
+    }
+
+    fn attributed(map_type: &str, attributes: &str) -> String {
+        format!("map m {{\n    type: .{};\n    key: u32;\n    value: u64;\n    max: 16;\n{}\n}}\n", map_type, attributes)
+    }
+
+    #[test]
+    fn attributes_suited_to_the_map_are_accepted() {
+        for src in [
+            attributed("hash", "    flags: [no_prealloc];\n    pinning: by_name;\n    numa_node: 1;"),
+            attributed("array", "    flags: [rdonly_prog, mmapable];"),
+            attributed("lru_hash", "    flags: [no_common_lru];"),
+        ] {
+            assert!(errors(&src).is_empty(), "{}: {:?}", src, errors(&src));
+        }
+    }
+
+    #[test]
+    fn attributes_are_checked_per_map_type() {
+        assert_error(
+            &attributed("lru_hash", "    flags: [no_prealloc];"),
+            "Map 'm' is a lru_hash and does not take flag 'no_prealloc'",
+        );
+        assert_error(&attributed("hash", "    flags: [rdonly_prog, rdonly_prog];"), "Duplicate flag 'rdonly_prog' in map 'm'");
+        assert_error(
+            &attributed("hash", "    flags: [rdonly_prog, wronly_prog];"),
+            "Map 'm' cannot be both rdonly_prog and wronly_prog",
+        );
+        assert_error(&attributed("percpu_array", "    numa_node: 0;"), "Per-CPU map 'm' cannot be placed on a NUMA node");
+    }
+
+    const COUNTS: &str = "map counts {\n    type: .hash;\n    key: u32;\n    value: u64;\n    max: 16;\n}\n";
+
+    fn outer(map_type: &str, inner: &str) -> String {
+        format!("{}map outer {{\n    type: .{};\n    key: u32;\n    max: 4;\n    inner: {};\n}}\n", COUNTS, map_type, inner)
+    }
+
+    #[test]
+    fn maps_of_maps_hold_their_prototype() {
+        for map_type in ["hash_of_maps", "array_of_maps"] {
+            let src = outer(map_type, "counts");
+            assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+        }
+    }
+
+    #[test]
+    fn inner_prototype_is_checked() {
+        assert_error(&outer("hash_of_maps", "nope"), "Unknown map: 'nope'");
+        let src = format!("map jumps {{\n    type: .prog_array;\n    max: 4;\n}}\n{}", outer("hash_of_maps", "jumps"));
+        assert_error(&src, "Map 'outer' cannot hold maps like 'jumps', a prog_array");
+        assert_error(&outer("hash", "counts").replace("    max: 4;\n", "    value: u64;\n    max: 4;\n"), "Map 'outer' is a hash and has no inner map");
+    }
+}
@@ -1,34 +1,69 @@
 
 pub mod map;
+pub mod null_safety;
+pub mod ringbuf;
 pub mod unit;
 pub mod section;
+pub mod structs;
+pub mod types;
 
-use crate::ast::Program;
+pub use section::SectionValidator;
+
+use crate::ast::{MapDecl, Program, StructDecl, Unit};
 use crate::diagnostics::DiagnosticReporter;
-use std::collections::HashSet;
-    
-#[derive(Debug, thiserror::Error)]
-pub enum SemanticError {
-    #[error("Map validation failed")]
-    MapError(#[from] map::MapValidationError),
-    
-    #[error("Unit validation failed")]
-    UnitError(#[from] unit::UnitValidationError),
-}
+use crate::parser::SourceLoc;
+use std::collections::{HashMap, HashSet};
+
+/// Runs every semantic check over the parsed program. Problems are
+/// collected in `diagnostics`; the caller decides whether to continue.
+pub fn check_program(program: &Program, diagnostics: &mut DiagnosticReporter) {
+    let mut struct_names = HashSet::new();
+
+    for struct_decl in &program.structs {
+        structs::check_struct(struct_decl, diagnostics, &mut struct_names);
+    }
+
+    // A duplicate is reported above; the first declaration stays in effect.
+    let mut structs: HashMap<&str, &StructDecl> = HashMap::new();
+    for struct_decl in &program.structs {
+        structs.entry(struct_decl.name.as_str()).or_insert(struct_decl);
+    }
 
-pub fn check_program(
-    program: &Program,
-    diagnostics: &mut DiagnosticReporter,
-) -> Result<(), SemanticError> {
     let mut map_names = HashSet::new();
 
     for map_decl in &program.maps {
-        map::check_map(map_decl, diagnostics, &mut map_names)?;
+        map::check_map(map_decl, &structs, diagnostics, &mut map_names);
+    }
+
+    let mut unit_names = HashSet::new();
+
+    let maps: HashMap<&str, &MapDecl> = program.maps.iter().map(|m| (m.name.as_str(), m)).collect();
+    let units: HashMap<&str, &Unit> = program.units.iter().map(|u| (u.name.as_str(), u)).collect();
+
+    for map_decl in &program.maps {
+        map::check_programs(map_decl, &units, diagnostics);
+        map::check_inner(map_decl, &maps, diagnostics);
     }
 
     for unit_decl in &program.units {
-        unit::check_unit(unit_decl, diagnostics)?;
+        unit::check_unit(unit_decl, diagnostics, &mut unit_names);
+        // Maps and unit functions are both global symbols of the object.
+        if maps.contains_key(unit_decl.name.as_str()) {
+            diagnostics
+                .report_error(format!("Unit '{}' has the same name as a map", unit_decl.name), unit_decl.loc)
+                .with_help("Maps and units share one namespace in the object; rename one of them");
+        }
+        types::check_unit_types(unit_decl, &maps, &structs, &units, diagnostics);
     }
+    unit::check_licenses(&program.units, diagnostics);
+}
 
-    Ok(())
-}
\ No newline at end of file
+/// Names starting with `__` belong to the generated C: its locals, context
+/// parameters, padding fields and map prototype types.
+pub fn check_name(kind: &str, name: &str, loc: SourceLoc, diagnostics: &mut DiagnosticReporter) {
+    if name.starts_with("__") {
+        diagnostics
+            .report_error(format!("{} name '{}' is reserved", kind, name), loc)
+            .with_help("Names starting with `__` are used by the generated code");
+    }
+}
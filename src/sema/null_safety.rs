@@ -0,0 +1,236 @@
+use crate::diagnostics::DiagnosticReporter;
+use crate::ir::unit::BasicBlock;
+use crate::ir::{BlockId, Opcode, Operand, Terminator, UnaryOp, UnitIr, VarId};
+use crate::parser::SourceLoc;
+use std::collections::{BTreeSet, HashMap};
+
+/// Map pointers known to be non-null at some program point.
+type NonNull = BTreeSet<VarId>;
+
+/// Proves that every load or store through a `heap` pointer happens on a
+/// path where a guard has seen the pointer succeed, and reports the
+/// statements where that cannot be shown.
+///
+/// This is a forward must-analysis over the CFG: a pointer is non-null on
+/// entry to a block only if it is non-null along every incoming edge. A
+/// branch on a null check makes its pointer non-null along the `then` edge
+/// (or the `else` edge for `!p`); a fresh lookup, or copying a possibly-null
+/// pointer, makes it unknown again. A lookup of a constant index within an
+/// array cannot fail, as the verifier also knows, and needs no guard.
+pub fn check_null_safety(unit: &UnitIr, diagnostics: &mut DiagnosticReporter) {
+    let mut entry_facts: HashMap<BlockId, NonNull> = HashMap::new();
+    entry_facts.insert(unit.entry, NonNull::new());
+
+    // Blocks without an entry yet have not been reached and contribute
+    // nothing to the intersection, which is the usual "everything" start
+    // value of a must-analysis.
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in &unit.blocks {
+            let Some(facts) = entry_facts.get(&block.id).cloned() else {
+                continue;
+            };
+            let out = transfer(unit, block, facts, &mut |_, _| {});
+
+            for succ in block.terminator.successors() {
+                let mut edge = out.clone();
+                if let Some((ptr, true)) = null_test_on_edge(unit, block, succ) {
+                    edge.insert(ptr);
+                }
+
+                let merged = match entry_facts.get(&succ) {
+                    Some(existing) => existing.intersection(&edge).copied().collect(),
+                    None => edge,
+                };
+                if entry_facts.get(&succ) != Some(&merged) {
+                    entry_facts.insert(succ, merged);
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    for block in &unit.blocks {
+        let Some(facts) = entry_facts.get(&block.id).cloned() else {
+            continue;
+        };
+        transfer(unit, block, facts, &mut |ptr, loc| {
+            let name = unit.var(ptr).name.as_deref().unwrap_or("map value");
+            diagnostics
+                .report_error(format!("Possible null dereference of `{}`", name), loc)
+                .with_help(format!(
+                    "The map call may fail; access it inside `if guard({}) {{ ... }}`",
+                    name
+                ));
+        });
+    }
+}
+
+/// Runs the block's instructions over `facts`, calling `unsafe_access` for
+/// every dereference of a pointer not known to be non-null.
+fn transfer(
+    unit: &UnitIr,
+    block: &BasicBlock,
+    mut facts: NonNull,
+    unsafe_access: &mut dyn FnMut(VarId, SourceLoc),
+) -> NonNull {
+    for inst in &block.instructions {
+        match &inst.opcode {
+            Opcode::LoadKey
+            | Opcode::Store { .. }
+            | Opcode::LoadField { .. }
+            | Opcode::StoreField { .. }
+            | Opcode::LoadIndex { .. }
+            | Opcode::StoreIndex { .. }
+            | Opcode::Atomic { .. }
+            | Opcode::RingbufSubmit
+            | Opcode::RingbufDiscard => {
+                if let Some(Operand::Var(ptr)) = inst.operands.first() {
+                    // Once reported, treat the pointer as checked so the same
+                    // missing guard is not reported again further down.
+                    if unit.var(*ptr).is_ptr && facts.insert(*ptr) {
+                        unsafe_access(*ptr, inst.loc);
+                    }
+                }
+            }
+            Opcode::Copy if unit.var(inst.result).is_ptr => {
+                let source_checked = matches!(
+                    inst.operands.first(),
+                    Some(Operand::Var(src)) if facts.contains(src)
+                );
+                if source_checked {
+                    facts.insert(inst.result);
+                } else {
+                    facts.remove(&inst.result);
+                }
+            }
+            Opcode::CallMap { in_bounds: true, .. } => {
+                facts.insert(inst.result);
+            }
+            _ => {
+                facts.remove(&inst.result);
+            }
+        }
+    }
+    facts
+}
+
+/// The pointer a branch at the end of `block` tests against null, if any,
+/// and whether it is non-null when control goes to `succ`.
+pub fn null_test_on_edge(unit: &UnitIr, block: &BasicBlock, succ: BlockId) -> Option<(VarId, bool)> {
+    let Terminator::Branch { condition: Operand::Var(cond), true_block, false_block } = &block.terminator else {
+        return None;
+    };
+    if true_block == false_block {
+        return None;
+    }
+
+    let def = block.instructions.iter().rev().find(|inst| inst.result == *cond)?;
+    let Some(Operand::Var(ptr)) = def.operands.first() else {
+        return None;
+    };
+    if !unit.var(*ptr).is_ptr {
+        return None;
+    }
+
+    match def.opcode {
+        Opcode::NullCheck => Some((*ptr, succ == *true_block)),
+        Opcode::Unary { op: UnaryOp::Not } => Some((*ptr, succ == *false_block)),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::testing::{assert_error, errors};
+
+    fn unit(body: &str) -> String {
+        format!(
+            "map results {{\n    type: .array;\n    key: u32;\n    value: u64;\n    max: 8;\n}}\n\nunit t {{\n    section: \"xdp\";\n    license: \"GPL\";\n\n    reg a = ctx.load_u8(0);\n{}\n    return 2;\n}}\n",
+            body
+        )
+    }
+
+    #[test]
+    fn guarded_dereference_is_accepted() {
+        let src = unit("    heap p = results.lookup(a);\n    if guard(p) {\n        *p = 1;\n    }");
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn early_return_on_null_guards_the_rest() {
+        let src = unit("    heap p = results.lookup(a);\n    if guard(!p) {\n        return 1;\n    }\n    *p = 1;");
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn unguarded_dereference_is_rejected() {
+        let src = unit("    if guard(a < 10) {\n        heap p = results.lookup(a);\n        *p = 1;\n    }");
+        assert_error(&src, "Possible null dereference of `p`");
+    }
+
+    #[test]
+    fn guard_on_one_path_does_not_cover_the_merge() {
+        let src = unit("    heap p = results.lookup(a);\n    if guard(a < 10) {\n        if guard(!p) {\n            return 1;\n        }\n    }\n    *p = 1;");
+        assert_error(&src, "Possible null dereference of `p`");
+
+        let src = unit("    heap p = results.lookup(a);\n    if guard(p) {\n        p = results.lookup(a);\n        *p = 1;\n    }");
+        assert_error(&src, "Possible null dereference of `p`");
+    }
+
+    #[test]
+    fn left_operand_guards_the_right_one() {
+        let src = unit("    heap p = results.lookup(a);\n    if guard(p && *p > 0) {\n        *p += 1;\n    }");
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+
+        let src = unit("    heap p = results.lookup(a);\n    if guard(p || *p > 0) {\n        reg x = 1;\n    }");
+        assert_error(&src, "Possible null dereference of `p`");
+    }
+
+    #[test]
+    fn constant_index_within_an_array_needs_no_guard() {
+        let src = unit("    imm LAST = 7;\n    heap p = results.lookup(LAST);\n    *p = 1;");
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+
+        let src = unit("    heap p = results.lookup(8);\n    *p = 1;");
+        assert_error(&src, "Possible null dereference of `p`");
+
+        let maps = "map counts {\n    type: .hash;\n    key: u32;\n    value: u64;\n    max: 8;\n}\n\n";
+        let src = format!("{}{}", maps, unit("    heap p = counts.lookup(0);\n    *p = 1;"));
+        assert_error(&src, "Possible null dereference of `p`");
+    }
+
+    #[test]
+    fn indexing_a_map_value_needs_a_guard() {
+        let maps = "map history {\n    type: .array;\n    key: u32;\n    value: u32[4];\n    max: 1;\n}\n\n";
+        let src = format!("{}{}", maps, unit("    heap h = history.lookup(a);\n    if guard(h) {\n        h[1] += 1;\n    }"));
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+
+        let src = format!("{}{}", maps, unit("    heap h = history.lookup(a);\n    reg x = h[1];"));
+        assert_error(&src, "Possible null dereference of `h`");
+    }
+
+    #[test]
+    fn atomic_updates_need_a_guard() {
+        let src = unit("    heap p = results.lookup(a);\n    if guard(p) {\n        atomic *p += 1;\n        reg old = atomic xchg(*p, 0);\n    }");
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+
+        let src = unit("    heap p = results.lookup(a);\n    atomic *p += 1;");
+        assert_error(&src, "Possible null dereference of `p`");
+
+        let src = unit("    heap p = results.lookup(a);\n    reg old = atomic cmpxchg(*p, 0, 1);");
+        assert_error(&src, "Possible null dereference of `p`");
+    }
+
+    #[test]
+    fn inner_lookup_result_needs_a_guard() {
+        let maps = "map tenants {\n    type: .array_of_maps;\n    key: u32;\n    max: 4;\n    inner: results;\n}\n";
+        let guarded = unit("    heap p = tenants.lookup(0).lookup(1);\n    if guard(p) {\n        *p = 1;\n    }");
+        let src = format!("{}{}", maps, guarded);
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+
+        let src = format!("{}{}", maps, unit("    heap p = tenants.lookup(0).lookup(1);\n    *p = 1;"));
+        assert_error(&src, "Possible null dereference of `p`");
+    }
+}
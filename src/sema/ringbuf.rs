@@ -0,0 +1,184 @@
+use crate::diagnostics::DiagnosticReporter;
+use crate::ir::unit::BasicBlock;
+use crate::ir::{BlockId, Instruction, Opcode, Operand, Terminator, UnitIr, VarId};
+use crate::parser::SourceLoc;
+use crate::sema::null_safety::null_test_on_edge;
+use std::collections::{BTreeSet, HashMap};
+
+/// Ring buffer records reserved and not yet handed back on some path.
+type Outstanding = BTreeSet<VarId>;
+
+/// Reports ring buffer records that can reach the end of the program
+/// without being submitted or discarded, which the verifier rejects.
+///
+/// This is a forward may-analysis over the CFG: a record is outstanding
+/// from its `reserve` until `submit` or `discard`, or along the edge where a
+/// guard sees the reservation fail. It is reported at a `return` reached
+/// with the record outstanding, at a packet bounds check, whose fallback
+/// verdict returns just the same, and at a tail call, which never returns.
+pub fn check_records_released(unit: &UnitIr, diagnostics: &mut DiagnosticReporter) {
+    let mut entry_facts: HashMap<BlockId, Outstanding> = HashMap::new();
+    entry_facts.insert(unit.entry, Outstanding::new());
+
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in &unit.blocks {
+            let Some(facts) = entry_facts.get(&block.id).cloned() else {
+                continue;
+            };
+            let out = transfer(block, facts, &mut |_, _| {});
+
+            for succ in block.terminator.successors() {
+                let mut edge = out.clone();
+                if let Some((ptr, false)) = null_test_on_edge(unit, block, succ) {
+                    edge.remove(&ptr);
+                }
+
+                let merged = match entry_facts.get(&succ) {
+                    Some(existing) => existing.union(&edge).copied().collect(),
+                    None => edge,
+                };
+                if entry_facts.get(&succ) != Some(&merged) {
+                    entry_facts.insert(succ, merged);
+                    changed = true;
+                }
+            }
+        }
+    }
+
+    let mut reported = BTreeSet::new();
+    for block in &unit.blocks {
+        let Some(facts) = entry_facts.get(&block.id).cloned() else {
+            continue;
+        };
+        let out = transfer(block, facts, &mut |record, inst| {
+            let Some((map, _)) = reservation(unit, record) else {
+                return;
+            };
+            if !reported.insert(record) {
+                return;
+            }
+            let name = record_name(unit, record);
+            let (message, help) = match inst.opcode {
+                Opcode::TailCall { .. } => (
+                    format!("Tail call while ring buffer record `{}` is reserved", name),
+                    format!("Submit or discard `{}` before `tail_call`", name),
+                ),
+                _ => (
+                    format!("A short packet returns here while ring buffer record `{}` is reserved", name),
+                    format!("Load packet data before `{}.reserve`", map),
+                ),
+            };
+            diagnostics.report_error(message, inst.loc).with_help(help);
+        });
+
+        if !matches!(block.terminator, Terminator::Return(_)) {
+            continue;
+        }
+        for record in out {
+            let Some((map, loc)) = reservation(unit, record) else {
+                continue;
+            };
+            if reported.insert(record) {
+                let name = record_name(unit, record);
+                diagnostics
+                    .report_error(
+                        format!("Ring buffer record `{}` is not submitted or discarded on every path", name),
+                        loc,
+                    )
+                    .with_help(format!(
+                        "Call `{}.submit({})` or `{}.discard({})` before returning",
+                        map, name, map, name
+                    ));
+            }
+        }
+    }
+}
+
+/// Runs the block's instructions over `facts`, calling `leaves` for every
+/// record outstanding at a packet bounds check or tail call.
+fn transfer(
+    block: &BasicBlock,
+    mut facts: Outstanding,
+    leaves: &mut dyn FnMut(VarId, &Instruction),
+) -> Outstanding {
+    for inst in &block.instructions {
+        match &inst.opcode {
+            Opcode::RingbufReserve { .. } => {
+                facts.insert(inst.result);
+            }
+            Opcode::RingbufSubmit | Opcode::RingbufDiscard => {
+                if let Some(Operand::Var(record)) = inst.operands.first() {
+                    facts.remove(record);
+                }
+            }
+            Opcode::BoundsCheck { .. } | Opcode::TailCall { .. } => {
+                for record in &facts {
+                    leaves(*record, inst);
+                }
+            }
+            _ => {}
+        }
+    }
+    facts
+}
+
+fn record_name(unit: &UnitIr, record: VarId) -> &str {
+    unit.var(record).name.as_deref().unwrap_or("record")
+}
+
+/// The ring buffer `record` was reserved in, and where.
+fn reservation(unit: &UnitIr, record: VarId) -> Option<(&str, SourceLoc)> {
+    unit.blocks
+        .iter()
+        .flat_map(|block| &block.instructions)
+        .find_map(|inst| match &inst.opcode {
+            Opcode::RingbufReserve { map_name } if inst.result == record => Some((map_name.as_str(), inst.loc)),
+            _ => None,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::testing::{assert_error, errors};
+
+    fn unit(body: &str) -> String {
+        format!(
+            "map events {{\n    type: .ringbuf;\n    max: 4096;\n}}\n\nmap jumps {{\n    type: .prog_array;\n    max: 1;\n}}\n\nunit t {{\n    section: \"xdp\";\n    license: \"GPL\";\n\n    reg a = ctx.load_u8(0);\n{}\n    return 2;\n}}\n",
+            body
+        )
+    }
+
+    #[test]
+    fn record_released_on_every_path_is_accepted() {
+        let src = unit(
+            "    heap e = events.reserve(u32);\n    if guard(e) {\n        *e = a;\n        if guard(a == 0) {\n            events.discard(e);\n        } else {\n            events.submit(e);\n        }\n    }",
+        );
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn failed_reservation_needs_no_release() {
+        let src = unit("    heap e = events.reserve(u32);\n    if guard(!e) {\n        return 1;\n    }\n    *e = a;\n    events.submit(e);");
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn record_kept_on_one_path_is_rejected() {
+        let src = unit("    heap e = events.reserve(u32);\n    if guard(e) {\n        if guard(a == 0) {\n            events.submit(e);\n        }\n    }");
+        assert_error(&src, "Ring buffer record `e` is not submitted or discarded on every path");
+    }
+
+    #[test]
+    fn packet_load_while_reserved_is_rejected() {
+        let src = unit("    heap e = events.reserve(u32);\n    if guard(e) {\n        *e = ctx.load_u32(26);\n        events.submit(e);\n    }");
+        assert_error(&src, "A short packet returns here while ring buffer record `e` is reserved");
+    }
+
+    #[test]
+    fn tail_call_while_reserved_is_rejected() {
+        let src = unit("    heap e = events.reserve(u32);\n    if guard(e) {\n        tail_call(jumps, 0);\n        events.submit(e);\n    }");
+        assert_error(&src, "Tail call while ring buffer record `e` is reserved");
+    }
+}
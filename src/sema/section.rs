@@ -1,12 +1,11 @@
 
-use once_cell::sync::Lazy;
-use regex::Regex;
-
 const STATIC_SECTIONS: &[&str] = &[
     "xdp", "xdp/ingress", "xdp/egress", "xdp/frags", "xdp/devmap", "xdp/cpumap", "xdp/offload",
-    "tc", "classifier", "action", "tcx/ingress", "tcx/egress", "tc/ingress", "tc/egress",
+    "tc", "tcx", "classifier", "action", "tcx/ingress", "tcx/egress", "tc/ingress", "tc/egress",
     "tracepoint", "tp", "raw_tracepoint", "raw_tp", "tp_btf",
     "cgroup_skb", "cgroup_sock", "cgroup_skb/ingress", "cgroup_skb/egress", "sockops", "sk_msg",
+    "cgroup/skb/ingress", "cgroup/skb/egress", "cgroup/sock", "cgroup/sock_addr",
+    "sk_skb/stream_parser", "sk_skb/stream_verdict",
     "maps", "license", "version", "perf_event",
 ];
 
@@ -36,8 +35,8 @@ impl SectionValidator {
             return Self::is_valid_identifier(event);
         }
 
-        if section.starts_with("tp_btf/") {
-            return Self::is_valid_identifier(&section[7..]);
+        if let Some(event) = section.strip_prefix("tp_btf/") {
+            return Self::is_valid_identifier(event);
         }
 
         if section.starts_with("kprobe/") || section.starts_with("kretprobe/") {
@@ -47,6 +46,11 @@ impl SectionValidator {
             return Self::is_valid_identifier(func);
         }
 
+        if section.starts_with("fentry/") || section.starts_with("fexit/") || section.starts_with("lsm/") {
+            let func = section.split_once('/').map(|(_, f)| f).unwrap_or_default();
+            return Self::is_valid_identifier(func);
+        }
+
         if section.starts_with("uprobe/") || section.starts_with("uretprobe/") {
             let sym = section.strip_prefix("uprobe/")
                 .or_else(|| section.strip_prefix("uretprobe/"))
@@ -72,6 +76,70 @@ impl SectionValidator {
         false
     }
 
+    /// Verdicts a program attached at `section` may return when it gives up
+    /// on a packet, default first. `None` for program types without direct
+    /// packet access.
+    pub fn packet_verdicts(section: &str) -> Option<&'static [&'static str]> {
+        let family = section.split('/').next().unwrap_or_default();
+        match family {
+            "xdp" => Some(&["XDP_PASS", "XDP_DROP", "XDP_ABORTED", "XDP_TX"]),
+            "tc" | "tcx" | "classifier" | "action" => Some(&["TC_ACT_OK", "TC_ACT_SHOT", "TC_ACT_UNSPEC"]),
+            "sk_skb" | "cgroup_skb" => Some(&["SK_DROP", "SK_PASS"]),
+            "cgroup" if section.starts_with("cgroup/skb/") => Some(&["SK_DROP", "SK_PASS"]),
+            _ => None,
+        }
+    }
+
+    /// Verdicts a `redirect` in a program attached at `section` may fall
+    /// back to when the map slot is empty: its packet verdicts, or
+    /// `SK_DROP` and `SK_PASS` in an sk_msg program.
+    pub fn redirect_fallbacks(section: &str) -> Option<&'static [&'static str]> {
+        if section.split('/').next() == Some("sk_msg") {
+            return Some(&["SK_DROP", "SK_PASS"]);
+        }
+        Self::packet_verdicts(section)
+    }
+
+    /// The value the kernel gives a verdict of `packet_verdicts`.
+    pub fn verdict_value(verdict: &str) -> Option<i64> {
+        let value = match verdict {
+            "XDP_ABORTED" | "TC_ACT_OK" | "SK_DROP" => 0,
+            "XDP_DROP" | "SK_PASS" => 1,
+            "XDP_PASS" | "TC_ACT_SHOT" => 2,
+            "XDP_TX" => 3,
+            "TC_ACT_UNSPEC" => -1,
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// The kernel program type a program attached at `section` is loaded
+    /// as. A tail call can only jump between programs of the same type.
+    pub fn program_type(section: &str) -> Option<&'static str> {
+        let family = section.split('/').next().unwrap_or_default();
+        let ty = match family {
+            "xdp" => "xdp",
+            "tc" | "tcx" | "classifier" => "sched_cls",
+            "action" => "sched_act",
+            "tracepoint" | "tp" => "tracepoint",
+            "raw_tracepoint" | "raw_tp" => "raw_tracepoint",
+            "tp_btf" | "fentry" | "fexit" => "tracing",
+            "kprobe" | "kretprobe" | "uprobe" | "uretprobe" => "kprobe",
+            "lsm" => "lsm",
+            "cgroup_skb" => "cgroup_skb",
+            "cgroup_sock" => "cgroup_sock",
+            "cgroup" if section.starts_with("cgroup/skb/") => "cgroup_skb",
+            "cgroup" if section == "cgroup/sock" => "cgroup_sock",
+            "cgroup" if section == "cgroup/sock_addr" => "cgroup_sock_addr",
+            "sockops" => "sock_ops",
+            "sk_skb" => "sk_skb",
+            "sk_msg" => "sk_msg",
+            "perf_event" => "perf_event",
+            _ => return None,
+        };
+        Some(ty)
+    }
+
     fn is_valid_tc_extras(extras: &str) -> bool {
         extras.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '.' || c == '-')
     }
@@ -102,8 +170,36 @@ impl SectionValidator {
   Probes (Dynamic):
     - kprobe/<func>, kretprobe/<func>
     - uprobe/<sym>, uretprobe/<sym>
+    - fentry/<func>, fexit/<func>, lsm/<hook>
+  Sockets / cgroups:
+    - sk_skb/stream_parser, sk_skb/stream_verdict, sk_msg
+    - cgroup/skb/ingress, cgroup/skb/egress, cgroup/sock, cgroup/sock_addr
   Infrastructure:
     - maps, license, version
 "#
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::SectionValidator;
+
+    #[test]
+    fn redirect_fallbacks_follow_the_program_type() {
+        assert_eq!(
+            SectionValidator::redirect_fallbacks("xdp/devmap"),
+            Some(&["XDP_PASS", "XDP_DROP", "XDP_ABORTED", "XDP_TX"][..])
+        );
+        assert_eq!(SectionValidator::redirect_fallbacks("sk_msg"), Some(&["SK_DROP", "SK_PASS"][..]));
+        assert_eq!(SectionValidator::redirect_fallbacks("kprobe/do_sys_open"), None);
+    }
+
+    #[test]
+    fn verdicts_have_their_kernel_values() {
+        let values: Vec<_> = ["XDP_ABORTED", "XDP_DROP", "XDP_PASS", "XDP_TX", "SK_DROP", "SK_PASS"]
+            .iter()
+            .map(|name| SectionValidator::verdict_value(name))
+            .collect();
+        assert_eq!(values, [Some(0), Some(1), Some(2), Some(3), Some(0), Some(1)]);
+        assert_eq!(SectionValidator::verdict_value("XDP_REDIRECT"), None);
+    }
+}
@@ -0,0 +1,62 @@
+use crate::ast::{DataType, StructDecl};
+use crate::diagnostics::DiagnosticReporter;
+use crate::sema::check_name;
+use std::collections::HashSet;
+
+pub fn check_struct(
+    struct_decl: &StructDecl,
+    diagnostics: &mut DiagnosticReporter,
+    struct_names: &mut HashSet<String>,
+) {
+    if !struct_names.insert(struct_decl.name.clone()) {
+        diagnostics.report_error(
+            format!("Duplicate struct name: '{}'", struct_decl.name),
+            struct_decl.loc,
+        );
+    }
+    check_name("Struct", &struct_decl.name, struct_decl.loc, diagnostics);
+
+    if struct_decl.fields.is_empty() {
+        diagnostics.report_error(
+            format!("Struct '{}' must have at least one field", struct_decl.name),
+            struct_decl.loc,
+        );
+    }
+
+    let mut field_names = HashSet::new();
+    for field in &struct_decl.fields {
+        if !field_names.insert(field.name.as_str()) {
+            diagnostics.report_error(
+                format!("Duplicate field '{}' in struct '{}'", field.name, struct_decl.name),
+                field.loc,
+            );
+        }
+        check_name("Field", &field.name, field.loc, diagnostics);
+        if let DataType::Struct(name) = &field.ty {
+            diagnostics
+                .report_error(format!("Field '{}' cannot be a struct ('{}')", field.name, name), field.loc)
+                .with_help("Struct fields are integers or arrays of integers");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::testing::{assert_error, errors};
+
+    const UNIT: &str = "unit u {\n    section: \"xdp\";\n    license: \"GPL\";\n    return 2;\n}\n";
+
+    #[test]
+    fn struct_declarations_are_accepted() {
+        let src = format!("struct flow_key {{\n    saddr: u32,\n    sport: u16,\n    proto: u8,\n}}\n\n{}", UNIT);
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn malformed_structs_are_rejected() {
+        let src = format!("struct k {{ a: u32, a: u16 }}\nstruct k {{ x: u8 }}\nstruct empty {{ }}\n{}", UNIT);
+        assert_error(&src, "Duplicate field 'a' in struct 'k'");
+        assert_error(&src, "Duplicate struct name: 'k'");
+        assert_error(&src, "Struct 'empty' must have at least one field");
+    }
+}
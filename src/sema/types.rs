@@ -0,0 +1,1952 @@
+use crate::ast::{
+    Assignment, AssignmentOp, AtomicExpr, BinOp, BinaryExpr, Cidr, DataType, Expr, ExprKind, FieldAccess, IndexExpr, MapDecl, MapType, MethodCall,
+    Redirect, Stmt, StmtKind, StructDecl, StructLiteral, TailCall, Type, UnaryOp, Unit, VarDecl, VarType,
+};
+use crate::ast::structs::field_layout;
+use crate::sema::SectionValidator;
+use crate::diagnostics::DiagnosticReporter;
+use crate::parser::SourceLoc;
+use std::collections::HashMap;
+use std::fmt;
+
+/// Type of an expression as seen by the checker.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Ty<'a> {
+    /// An integer of known width and signedness.
+    Int(Type),
+    /// A compile-time constant. It has no width of its own and takes the
+    /// type of whatever it is combined with, as long as the value fits.
+    Const(i64),
+    /// A `heap` pointer to a map value.
+    Ptr(Object<'a>),
+    /// A struct literal or an array buffer. Neither is a value of its own:
+    /// a struct literal can only be a map key, and an array can be indexed
+    /// or used as one.
+    Aggregate(Object<'a>),
+    /// Something already reported; checks involving it are skipped so one
+    /// mistake does not cascade.
+    Error,
+}
+
+/// What a map pointer refers to, or what an aggregate holds.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Object<'a> {
+    Scalar(Type),
+    Struct(&'a StructDecl),
+    Array(Type, u32),
+}
+
+impl fmt::Display for Object<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Object::Scalar(ty) => ty.fmt(f),
+            Object::Struct(decl) => write!(f, "struct {}", decl.name),
+            Object::Array(elem, len) => write!(f, "{}[{}]", elem, len),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BindingKind {
+    Reg,
+    Imm,
+    Heap,
+    /// A `heap` record reserved in a ring buffer; it must be handed back with
+    /// `submit` or `discard`.
+    Record,
+    /// A `for` loop counter over `start..end`; read-only so the trip count
+    /// stays fixed.
+    Loop { start: i64, end: i64 },
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Binding<'a> {
+    kind: BindingKind,
+    ty: Ty<'a>,
+}
+
+/// Width and type of the value produced by a `ctx.load_*` method.
+pub fn ctx_load(method: &str) -> Option<(u8, Type)> {
+    let ty = match method {
+        "load_u8" => Type::U8,
+        "load_u16" => Type::U16,
+        "load_u32" => Type::U32,
+        "load_u64" => Type::U64,
+        "load_i8" => Type::I8,
+        "load_i16" => Type::I16,
+        "load_i32" => Type::I32,
+        "load_i64" => Type::I64,
+        _ => return None,
+    };
+    Some((ty.size(), ty))
+}
+
+/// Most times a loop body may run, counting the iterations of the loops
+/// around it. Loops are emitted as plain `while` loops, which the verifier
+/// walks one iteration at a time within its budget of one million
+/// instructions per program; 8192 iterations leave room for a body of about
+/// a hundred instructions.
+const MAX_LOOP_ITERATIONS: i64 = 8192;
+
+/// Type of the counter of a `for` loop over `start..end`.
+pub fn loop_counter_type(start: i64, end: i64) -> Type {
+    if start >= 0 {
+        if Type::U32.fits(end) {
+            Type::U32
+        } else {
+            Type::U64
+        }
+    } else if Type::I32.fits(start) && Type::I32.fits(end) {
+        Type::I32
+    } else {
+        Type::I64
+    }
+}
+
+/// Type an unannotated binding initialised with a bare constant gets.
+pub fn constant_type(value: i64) -> Type {
+    if value < 0 {
+        Type::I64
+    } else {
+        Type::U64
+    }
+}
+
+/// Evaluates `expr` if it only involves literals and `imm` constants, which
+/// `constant` resolves by name.
+pub fn const_eval(expr: &Expr, constant: &dyn Fn(&str) -> Option<i64>) -> Option<i64> {
+    match &expr.kind {
+        ExprKind::Number(n) => Some(*n),
+        ExprKind::Variable(name) => constant(name),
+        ExprKind::Binary(bin) => {
+            let left = const_eval(&bin.left, constant)?;
+            let right = const_eval(&bin.right, constant)?;
+            fold_binary(bin.op, left, right)
+        }
+        ExprKind::Unary(unary) => {
+            let value = const_eval(&unary.operand, constant)?;
+            Some(fold_unary(unary.op, value))
+        }
+        ExprKind::Cast(cast) => Some(cast.ty.wrap(const_eval(&cast.expr, constant)?)),
+        _ => None,
+    }
+}
+
+/// Folds a binary operator over two constants. `None` on overflow or
+/// division by zero. A left shift works on the 64-bit pattern, so masks such
+/// as `1 << 63` fold; whether the result fits its type is checked where it
+/// is used.
+pub fn fold_binary(op: BinOp, left: i64, right: i64) -> Option<i64> {
+    match op {
+        BinOp::Add => left.checked_add(right),
+        BinOp::Sub => left.checked_sub(right),
+        BinOp::Mul => left.checked_mul(right),
+        BinOp::Div => left.checked_div(right),
+        BinOp::Mod => left.checked_rem(right),
+        BinOp::Eq => Some((left == right) as i64),
+        BinOp::Ne => Some((left != right) as i64),
+        BinOp::Lt => Some((left < right) as i64),
+        BinOp::Le => Some((left <= right) as i64),
+        BinOp::Gt => Some((left > right) as i64),
+        BinOp::Ge => Some((left >= right) as i64),
+        BinOp::And => Some((left != 0 && right != 0) as i64),
+        BinOp::Or => Some((left != 0 || right != 0) as i64),
+        BinOp::BitAnd => Some(left & right),
+        BinOp::BitOr => Some(left | right),
+        BinOp::BitXor => Some(left ^ right),
+        BinOp::Shl => u32::try_from(right).ok().filter(|&n| n < 64).map(|n| left.wrapping_shl(n)),
+        BinOp::Shr => left.checked_shr(u32::try_from(right).ok()?),
+    }
+}
+
+pub fn fold_unary(op: UnaryOp, value: i64) -> i64 {
+    match op {
+        UnaryOp::Not => (value == 0) as i64,
+        UnaryOp::BitNot => !value,
+    }
+}
+
+/// Whether `op` yields a truth value rather than a number.
+pub fn is_boolean_op(op: BinOp) -> bool {
+    matches!(
+        op,
+        BinOp::Eq | BinOp::Ne | BinOp::Lt | BinOp::Le | BinOp::Gt | BinOp::Ge | BinOp::And | BinOp::Or
+    )
+}
+
+/// Whether `op` shifts its left operand, whose type alone decides the
+/// result's; the right operand is only a bit count.
+pub fn is_shift_op(op: BinOp) -> bool {
+    matches!(op, BinOp::Shl | BinOp::Shr)
+}
+
+/// Whether `op` works on the bits of its operands, so a constant operand
+/// is a bit pattern rather than a number.
+fn is_bitwise_op(op: BinOp) -> bool {
+    matches!(op, BinOp::BitAnd | BinOp::BitOr | BinOp::BitXor)
+}
+
+/// Whether `expr` builds a bit pattern, whose constant value may read as
+/// negative: `1 << 63` is a `u64` mask, not an overflow.
+fn is_bit_pattern(expr: &Expr) -> bool {
+    match &expr.kind {
+        ExprKind::Binary(bin) => is_shift_op(bin.op) || is_bitwise_op(bin.op),
+        ExprKind::Unary(unary) => unary.op == UnaryOp::BitNot,
+        _ => false,
+    }
+}
+
+/// The atomic instruction `op` has, for `atomic place op= value`.
+pub fn atomic_op(op: AssignmentOp) -> Option<BinOp> {
+    match op {
+        AssignmentOp::AddAssign
+        | AssignmentOp::SubAssign
+        | AssignmentOp::AndAssign
+        | AssignmentOp::OrAssign
+        | AssignmentOp::XorAssign => op.binary_op(),
+        _ => None,
+    }
+}
+
+/// Fewest and most arguments a map method takes; `None` if there is no
+/// such method.
+pub fn map_method_arity(method: &str) -> Option<(usize, usize)> {
+    match method {
+        "pop" | "peek" | "reserve" => Some((0, 0)),
+        "lookup" | "delete" | "submit" | "discard" | "output" => Some((1, 1)),
+        "push" => Some((1, 2)),
+        "lookup_or_init" => Some((2, 2)),
+        "update" => Some((2, 3)),
+        "emit" => Some((2, 2)),
+        _ => None,
+    }
+}
+
+/// Flags of `update` and `push`, under the kernel's names.
+const MAP_FLAGS: &[(&str, i64)] = &[("BPF_ANY", 0), ("BPF_NOEXIST", 1), ("BPF_EXIST", 2), ("BPF_F_LOCK", 4)];
+
+/// Value of the map flag called `name`.
+fn map_flag(name: &str) -> Option<i64> {
+    MAP_FLAGS.iter().find(|(flag, _)| *flag == name).map(|(_, value)| *value)
+}
+
+/// Evaluates the flags of `update` or `push`, which may name the
+/// kernel's flags alongside literals and `imm` constants.
+pub fn flags_value(flags: &Expr, constant: &dyn Fn(&str) -> Option<i64>) -> Option<i64> {
+    const_eval(flags, &|name| constant(name).or_else(|| map_flag(name)))
+}
+
+/// Flags `method` accepts on maps of `map_type`. Array slots always
+/// exist, so an insert that requires a missing key could never succeed;
+/// a full queue or stack either fails (`BPF_ANY`) or drops its oldest
+/// element (`BPF_EXIST`). `BPF_F_LOCK` needs a value holding a
+/// `bpf_spin_lock`, which no declared type can.
+fn accepted_flags(map_type: MapType, method: &str) -> &'static [&'static str] {
+    match (method, map_type) {
+        ("push", _) | (_, MapType::Array | MapType::PercpuArray) => &["BPF_ANY", "BPF_EXIST"],
+        _ => &["BPF_ANY", "BPF_NOEXIST", "BPF_EXIST"],
+    }
+}
+
+/// Whether `method` only has an effect and no value, so can only be
+/// called as a statement.
+fn is_statement_method(method: &str) -> bool {
+    matches!(method, "submit" | "discard")
+}
+
+/// Whether maps of `map_type` provide `method`.
+fn supports_method(map_type: MapType, method: &str) -> bool {
+    match method {
+        // A trie lookup finds the longest matching prefix rather than the
+        // key itself, so `lookup_or_init` could not tell when to insert.
+        "lookup" | "update" if map_type == MapType::LpmTrie => true,
+        // Programs can only read the inner maps; userspace installs them.
+        "lookup" if map_type.is_map_of_maps() => true,
+        "lookup" | "lookup_or_init" | "update" => {
+            matches!(
+                map_type,
+                MapType::Hash
+                    | MapType::Array
+                    | MapType::LruHash
+                    | MapType::PercpuHash
+                    | MapType::PercpuArray
+                    | MapType::LruPercpuHash
+            )
+        }
+        // Array slots always exist and cannot be removed.
+        "delete" => matches!(
+            map_type,
+            MapType::Hash | MapType::LruHash | MapType::PercpuHash | MapType::LruPercpuHash | MapType::LpmTrie
+        ),
+        "push" | "pop" | "peek" => matches!(map_type, MapType::Queue | MapType::Stack),
+        "reserve" | "submit" | "discard" | "output" => map_type == MapType::Ringbuf,
+        "emit" => map_type == MapType::PerfEventArray,
+        _ => false,
+    }
+}
+
+/// Program types a `redirect` through `map_type` is legal in; empty for
+/// maps that are not redirect targets.
+fn redirect_targets(map_type: MapType) -> &'static [&'static str] {
+    match map_type {
+        MapType::Devmap | MapType::DevmapHash | MapType::Cpumap | MapType::Xskmap => &["xdp"],
+        MapType::Sockmap | MapType::Sockhash => &["sk_skb", "sk_msg"],
+        _ => &[],
+    }
+}
+
+/// Evaluates the fallback of a `redirect` in a program attached at
+/// `section`, which may name one of its `redirect_fallbacks` verdicts.
+pub fn fallback_value(fallback: &Expr, section: &str, constant: &dyn Fn(&str) -> Option<i64>) -> Option<i64> {
+    let verdicts = SectionValidator::redirect_fallbacks(section).unwrap_or_default();
+    const_eval(fallback, &|name| {
+        constant(name).or_else(|| verdicts.contains(&name).then(|| SectionValidator::verdict_value(name)).flatten())
+    })
+}
+
+pub fn check_unit_types(
+    unit: &Unit,
+    maps: &HashMap<&str, &MapDecl>,
+    structs: &HashMap<&str, &StructDecl>,
+    units: &HashMap<&str, &Unit>,
+    diagnostics: &mut DiagnosticReporter,
+) {
+    let mut checker = TypeChecker {
+        maps,
+        structs,
+        units,
+        program_type: unit.sections.first().and_then(|s| SectionValidator::program_type(s)),
+        section: unit.sections.first().map_or("", |s| s.as_str()),
+        diagnostics,
+        scopes: vec![HashMap::new()],
+        iterations: 1,
+    };
+    checker.check_stmts(&unit.body);
+}
+
+struct TypeChecker<'a> {
+    maps: &'a HashMap<&'a str, &'a MapDecl>,
+    structs: &'a HashMap<&'a str, &'a StructDecl>,
+    units: &'a HashMap<&'a str, &'a Unit>,
+    /// Kernel program type of the unit being checked.
+    program_type: Option<&'static str>,
+    /// Section the unit is attached at.
+    section: &'a str,
+    diagnostics: &'a mut DiagnosticReporter,
+    scopes: Vec<HashMap<String, Binding<'a>>>,
+    /// How many times the statements being checked run: the product of the
+    /// trip counts of the loops around them.
+    iterations: i128,
+}
+
+impl<'a> TypeChecker<'a> {
+    fn lookup(&self, name: &str) -> Option<Binding<'a>> {
+        self.scopes.iter().rev().find_map(|scope| scope.get(name).copied())
+    }
+
+    fn constant(&self, name: &str) -> Option<i64> {
+        match self.lookup(name)?.ty {
+            Ty::Const(value) => Some(value),
+            _ => None,
+        }
+    }
+
+    fn declare(&mut self, name: &str, kind: BindingKind, ty: Ty<'a>) {
+        if let Some(scope) = self.scopes.last_mut() {
+            scope.insert(name.to_string(), Binding { kind, ty });
+        }
+    }
+
+    fn check_block(&mut self, stmts: &[Stmt]) {
+        self.scopes.push(HashMap::new());
+        self.check_stmts(stmts);
+        self.scopes.pop();
+    }
+
+    fn check_stmts(&mut self, stmts: &[Stmt]) {
+        for stmt in stmts {
+            self.check_stmt(stmt);
+        }
+    }
+
+    fn check_stmt(&mut self, stmt: &Stmt) {
+        match &stmt.kind {
+            StmtKind::VarDecl(decl) => {
+                let ty = self.check_var_decl(decl, stmt.loc);
+                let kind = match decl.var_type {
+                    VarType::Reg => BindingKind::Reg,
+                    VarType::Imm => BindingKind::Imm,
+                };
+                self.declare(&decl.name, kind, ty);
+            }
+
+            StmtKind::HeapVarDecl(decl) => {
+                let ty = match self.check_method_call(&decl.call, stmt.loc) {
+                    Ty::Ptr(object) => {
+                        // `outer.lookup(k).lookup(k2)` points into a map
+                        // shaped like the inner prototype.
+                        let holder = match self.maps.get(decl.call.receiver.as_str()) {
+                            Some(map) if decl.call.inner_key.is_some() => map.inner.as_deref(),
+                            _ => Some(decl.call.receiver.as_str()),
+                        };
+                        let value_type = holder
+                            .and_then(|name| self.maps.get(name))
+                            .and_then(|map| map.value_type.as_ref());
+                        match (&decl.ty, value_type) {
+                            (Some(ty), Some(value_type)) if ty != value_type => {
+                                self.diagnostics
+                                    .report_error(
+                                        format!(
+                                            "Type mismatch: map `{}` holds {} values, not {}",
+                                            holder.unwrap_or_default(), value_type, ty
+                                        ),
+                                        stmt.loc,
+                                    )
+                                    .with_help(format!("Write `heap {}: {} = ...`", decl.name, value_type));
+                                Ty::Error
+                            }
+                            _ => Ty::Ptr(object),
+                        }
+                    }
+                    Ty::Error => Ty::Error,
+                    other => {
+                        self.diagnostics
+                            .report_error(
+                                format!("`heap` needs a map value pointer, found {}", describe(other)),
+                                stmt.loc,
+                            )
+                            .with_help("Bind the result of `lookup`, `lookup_or_init`, `pop` or `peek`");
+                        Ty::Error
+                    }
+                };
+                let kind = match decl.call.method.as_str() {
+                    "reserve" => BindingKind::Record,
+                    _ => BindingKind::Heap,
+                };
+                self.declare(&decl.name, kind, ty);
+            }
+
+            StmtKind::Expr(expr) => match &expr.kind {
+                ExprKind::MethodCall(call) => {
+                    self.check_method_call(call, expr.loc);
+                }
+                _ => {
+                    self.check_expr(expr);
+                }
+            },
+
+            StmtKind::Return(expr) => {
+                match self.check_expr(expr) {
+                    Ty::Ptr(_) => {
+                        self.diagnostics
+                            .report_error("Cannot return a map pointer", expr.loc)
+                            .with_help("Return the value it points to with `*`");
+                    }
+                    Ty::Aggregate(object) => self.report_aggregate_value(object, expr.loc),
+                    _ => {}
+                }
+            }
+
+            StmtKind::Assignment(assign) => {
+                let value = self.check_expr(&assign.value);
+                let compound = assign.op != AssignmentOp::Assign;
+                if assign.atomic {
+                    self.check_atomic_update(assign, value, stmt.loc);
+                    return;
+                }
+
+                match &assign.target.kind {
+                    ExprKind::Variable(name) => match self.lookup(name) {
+                        None => {
+                            self.diagnostics
+                                .report_error(format!("Undefined variable: `{}`", name), assign.target.loc);
+                        }
+                        Some(binding) => match (binding.kind, binding.ty) {
+                            (BindingKind::Loop { .. }, _) => {
+                                self.diagnostics
+                                    .report_error(format!("Cannot assign to loop counter `{}`", name), assign.target.loc)
+                                    .with_help("Copy it into a `reg` first");
+                            }
+                            (BindingKind::Imm, _) => {
+                                self.diagnostics
+                                    .report_error(format!("Cannot assign to `imm {}`", name), assign.target.loc)
+                                    .with_help("Declare it with `reg` to make it mutable");
+                            }
+                            (BindingKind::Record, _) => {
+                                self.diagnostics
+                                    .report_error(format!("Cannot assign to ring buffer record `{}`", name), assign.target.loc)
+                                    .with_help("Submit or discard it, then reserve another");
+                            }
+                            (BindingKind::Heap, ptr @ Ty::Ptr(_)) => {
+                                if compound {
+                                    self.diagnostics.report_error(
+                                        "Arithmetic on a map pointer is not allowed",
+                                        assign.target.loc,
+                                    );
+                                } else if value != ptr && value != Ty::Error {
+                                    self.diagnostics.report_error(
+                                        format!("Expected a {}, found {}", describe(ptr), describe(value)),
+                                        assign.value.loc,
+                                    );
+                                }
+                            }
+                            (_, Ty::Int(ty)) => self.check_assigned_value(assign.op, value, ty, assign.value.loc),
+                            (_, Ty::Aggregate(object)) => self.report_whole_assignment(object, assign.target.loc),
+                            _ => {}
+                        },
+                    },
+
+                    ExprKind::Dereference(ptr) => {
+                        if let Some(pointee) = self.check_deref(ptr) {
+                            self.check_assigned_value(assign.op, value, pointee, assign.value.loc);
+                        }
+                    }
+
+                    ExprKind::Field(_) | ExprKind::Index(_) => match self.check_expr(&assign.target) {
+                        Ty::Int(ty) => self.check_assigned_value(assign.op, value, ty, assign.value.loc),
+                        Ty::Aggregate(object) => self.report_whole_assignment(object, assign.target.loc),
+                        _ => {}
+                    },
+
+                    _ => {
+                        self.diagnostics.report_error("Invalid assignment target", assign.target.loc);
+                    }
+                }
+            }
+
+            StmtKind::IfGuard(guard) => {
+                if let Ty::Aggregate(object) = self.check_expr(&guard.condition) {
+                    self.report_aggregate_value(object, guard.condition.loc);
+                }
+                self.check_block(&guard.body);
+                self.check_block(&guard.else_body);
+            }
+
+            StmtKind::TailCall(call) => self.check_tail_call(call, stmt.loc),
+
+            StmtKind::For(for_loop) => {
+                let start = self.check_loop_bound(&for_loop.start);
+                let end = self.check_loop_bound(&for_loop.end);
+
+                let outer_iterations = self.iterations;
+                let counter = match (start, end) {
+                    (Some(start), Some(end)) => {
+                        let trips = i128::from(end) - i128::from(start);
+                        let total = trips.max(0) * outer_iterations;
+                        if trips <= 0 {
+                            self.diagnostics
+                                .report_warning(format!("Loop over {}..{} never runs", start, end), stmt.loc);
+                        } else if trips > i128::from(MAX_LOOP_ITERATIONS) {
+                            self.diagnostics
+                                .report_error(
+                                    format!("Loop runs {} times; at most {} iterations are allowed", trips, MAX_LOOP_ITERATIONS),
+                                    stmt.loc,
+                                )
+                                .with_help("The verifier follows every iteration of a loop");
+                        } else if total > i128::from(MAX_LOOP_ITERATIONS) {
+                            self.diagnostics
+                                .report_error(
+                                    format!(
+                                        "Loop body runs {} times counting the loops around it; at most {} iterations are allowed",
+                                        total, MAX_LOOP_ITERATIONS
+                                    ),
+                                    stmt.loc,
+                                )
+                                .with_help("The verifier follows every iteration of a loop");
+                        }
+                        // Past the cap the body is counted afresh, so the
+                        // loops nested in it are not reported as well.
+                        self.iterations = if total > i128::from(MAX_LOOP_ITERATIONS) { 1 } else { total.max(1) };
+                        Ty::Int(loop_counter_type(start, end))
+                    }
+                    _ => Ty::Error,
+                };
+
+                self.scopes.push(HashMap::new());
+                let kind = BindingKind::Loop {
+                    start: start.unwrap_or(0),
+                    end: end.unwrap_or(0),
+                };
+                self.declare(&for_loop.var, kind, counter);
+                self.check_block(&for_loop.body);
+                self.scopes.pop();
+                self.iterations = outer_iterations;
+            }
+        }
+    }
+
+    fn check_expr(&mut self, expr: &Expr) -> Ty<'a> {
+        match &expr.kind {
+            ExprKind::Number(n) => Ty::Const(*n),
+
+            ExprKind::Variable(name) => match self.lookup(name) {
+                Some(binding) => binding.ty,
+                None => {
+                    self.diagnostics
+                        .report_error(format!("Undefined variable: `{}`", name), expr.loc);
+                    Ty::Error
+                }
+            },
+
+            ExprKind::MethodCall(call) if call.receiver != "ctx" && is_statement_method(&call.method) => {
+                self.check_method_call(call, expr.loc);
+                self.diagnostics
+                    .report_error(format!("`{}.{}` has no value", call.receiver, call.method), expr.loc)
+                    .with_help("Call it as a statement");
+                Ty::Error
+            }
+
+            ExprKind::MethodCall(call) => self.check_method_call(call, expr.loc),
+
+            ExprKind::Cidr(_) => {
+                self.diagnostics
+                    .report_error("A CIDR literal can only be the key of an `lpm_trie` map", expr.loc)
+                    .with_help("Pass it to `lookup`, `update` or `delete` on an `lpm_trie` map");
+                Ty::Error
+            }
+
+            ExprKind::Redirect(redirect) => self.check_redirect(redirect, expr.loc),
+
+            ExprKind::Dereference(ptr) => match self.check_deref(ptr) {
+                Some(pointee) => Ty::Int(pointee),
+                None => Ty::Error,
+            },
+
+            ExprKind::Binary(bin) => {
+                let left = self.check_expr(&bin.left);
+                let right = self.check_expr(&bin.right);
+
+                for (ty, operand) in [(left, &bin.left), (right, &bin.right)] {
+                    if let Ty::Aggregate(object) = ty {
+                        self.report_aggregate_value(object, operand.loc);
+                        return Ty::Error;
+                    }
+                }
+
+                if matches!(bin.op, BinOp::And | BinOp::Or) {
+                    // Any integer or map pointer is a valid truth value.
+                    return match (left, right) {
+                        (Ty::Const(l), Ty::Const(r)) => fold_binary(bin.op, l, r).map_or(Ty::Error, Ty::Const),
+                        _ => Ty::Int(Type::U32),
+                    };
+                }
+
+                if let (Ty::Ptr(_), _) | (_, Ty::Ptr(_)) = (left, right) {
+                    self.diagnostics
+                        .report_error("Arithmetic on a map pointer is not allowed", expr.loc)
+                        .with_help("Dereference it with `*` to use the value");
+                    return Ty::Error;
+                }
+
+                if is_shift_op(bin.op) {
+                    return self.check_shift(bin, left, right, expr.loc);
+                }
+
+                let ty = match (left, right) {
+                    (Ty::Error, _) | (_, Ty::Error) => return Ty::Error,
+                    (Ty::Const(l), Ty::Const(r)) => {
+                        return match fold_binary(bin.op, l, r) {
+                            Some(n) => Ty::Const(n),
+                            None => {
+                                self.diagnostics
+                                    .report_error("Constant expression overflows or divides by zero", expr.loc);
+                                Ty::Error
+                            }
+                        };
+                    }
+                    (Ty::Int(t), Ty::Const(n)) => {
+                        self.check_operand_constant(bin.op, n, t, bin.right.loc);
+                        t
+                    }
+                    (Ty::Const(n), Ty::Int(t)) => {
+                        self.check_operand_constant(bin.op, n, t, bin.left.loc);
+                        t
+                    }
+                    (Ty::Int(l), Ty::Int(r)) => match l.common(r) {
+                        Some(t) => t,
+                        None => {
+                            self.diagnostics
+                                .report_error(format!("Mismatched operand types: {} and {}", l, r), expr.loc)
+                                .with_help("Convert one side with `as`");
+                            return Ty::Error;
+                        }
+                    },
+                    _ => return Ty::Error,
+                };
+
+                if is_boolean_op(bin.op) {
+                    Ty::Int(Type::U32)
+                } else {
+                    Ty::Int(ty)
+                }
+            }
+
+            ExprKind::Unary(unary) => match (unary.op, self.check_expr(&unary.operand)) {
+                (_, Ty::Const(n)) => Ty::Const(fold_unary(unary.op, n)),
+                (_, Ty::Error) => Ty::Error,
+                (_, Ty::Aggregate(object)) => {
+                    self.report_aggregate_value(object, unary.operand.loc);
+                    Ty::Error
+                }
+                (UnaryOp::BitNot, Ty::Int(ty)) => Ty::Int(ty),
+                (UnaryOp::BitNot, Ty::Ptr(_)) => {
+                    self.diagnostics
+                        .report_error("Arithmetic on a map pointer is not allowed", unary.operand.loc)
+                        .with_help("Dereference it with `*` to use the value");
+                    Ty::Error
+                }
+                (UnaryOp::Not, _) => Ty::Int(Type::U32),
+            },
+
+            ExprKind::Cast(cast) => match self.check_expr(&cast.expr) {
+                Ty::Const(_) | Ty::Int(_) => Ty::Int(cast.ty),
+                Ty::Ptr(_) => {
+                    self.diagnostics
+                        .report_error("Cannot cast a map pointer", cast.expr.loc)
+                        .with_help("Dereference it with `*` to cast the value");
+                    Ty::Error
+                }
+                Ty::Aggregate(object) => {
+                    self.report_aggregate_value(object, cast.expr.loc);
+                    Ty::Error
+                }
+                Ty::Error => Ty::Error,
+            },
+
+            ExprKind::Field(access) => self.check_field(access),
+
+            ExprKind::StructLiteral(literal) => self.check_struct_literal(literal, expr.loc),
+
+            ExprKind::Index(index) => self.check_index(index),
+
+            ExprKind::Atomic(atomic) => self.check_atomic_expr(atomic),
+        }
+    }
+
+    fn check_var_decl(&mut self, decl: &VarDecl, loc: SourceLoc) -> Ty<'a> {
+        let keyword = match decl.var_type {
+            VarType::Reg => "reg",
+            VarType::Imm => "imm",
+        };
+
+        let annotation = match &decl.ty {
+            None => None,
+            Some(DataType::Scalar(ty)) => Some(*ty),
+            Some(DataType::Array(elem, len)) if decl.var_type == VarType::Reg => {
+                if let Some(value) = &decl.value {
+                    self.diagnostics
+                        .report_error("An array buffer starts zeroed and cannot be initialised", value.loc)
+                        .with_help(format!("Write `reg {}: {}[{}];` and fill it by index", decl.name, elem, len));
+                }
+                return Ty::Aggregate(Object::Array(*elem, *len));
+            }
+            Some(other) => {
+                self.diagnostics
+                    .report_error(format!("`{}` cannot hold a value of type {}", keyword, other), loc)
+                    .with_help("`reg` holds integers and arrays, `imm` holds integer constants");
+                return Ty::Error;
+            }
+        };
+
+        let Some(value_expr) = &decl.value else {
+            self.diagnostics
+                .report_error(format!("`{} {}` needs an initial value", keyword, decl.name), loc)
+                .with_help("Only array buffers start zeroed");
+            return Ty::Error;
+        };
+
+        let value = self.check_expr(value_expr);
+        match decl.var_type {
+            VarType::Imm => match const_eval(value_expr, &|name| self.constant(name)) {
+                Some(n) => {
+                    if let Some(ty) = annotation {
+                        self.check_declared_constant(value_expr, n, ty);
+                    }
+                    Ty::Const(n)
+                }
+                None => {
+                    if value != Ty::Error {
+                        self.diagnostics
+                            .report_error(
+                                format!("`imm {}` must be initialised with a constant", decl.name),
+                                value_expr.loc,
+                            )
+                            .with_help("Use `reg` for values computed at run time");
+                    }
+                    Ty::Error
+                }
+            },
+            VarType::Reg => match (value, annotation) {
+                (Ty::Aggregate(object), _) => {
+                    self.report_aggregate_value(object, value_expr.loc);
+                    Ty::Error
+                }
+                (Ty::Ptr(_), _) => {
+                    self.diagnostics
+                        .report_error("A map lookup result must be bound with `heap`", value_expr.loc)
+                        .with_help(format!("Write `heap {} = ...`", decl.name));
+                    Ty::Error
+                }
+                (Ty::Const(n), Some(ty)) => {
+                    self.check_declared_constant(value_expr, n, ty);
+                    Ty::Int(ty)
+                }
+                (_, Some(ty)) => {
+                    self.check_assignable(value, ty, value_expr.loc);
+                    Ty::Int(ty)
+                }
+                (Ty::Const(n), None) => Ty::Int(constant_type(n)),
+                (other, None) => other,
+            },
+        }
+    }
+
+    /// Checks `base[index]` and returns the element type.
+    fn check_index(&mut self, index: &IndexExpr) -> Ty<'a> {
+        let base = self.check_expr(&index.base);
+        let index_ty = self.check_expr(&index.index);
+
+        let (elem, len) = match base {
+            Ty::Aggregate(Object::Array(elem, len)) | Ty::Ptr(Object::Array(elem, len)) => (elem, len),
+            Ty::Error => return Ty::Error,
+            other => {
+                self.diagnostics
+                    .report_error(format!("Cannot index {}", describe(other)), index.base.loc)
+                    .with_help("Only arrays can be indexed");
+                return Ty::Error;
+            }
+        };
+
+        if index_ty != Ty::Error {
+            self.check_index_bound(&index.index, len);
+        }
+        Ty::Int(elem)
+    }
+
+    /// The verifier rejects any access it cannot prove stays inside the
+    /// array, so an index must be a constant in range or the counter of a
+    /// loop whose whole range is.
+    fn check_index_bound(&mut self, index: &Expr, len: u32) {
+        if let Some(n) = const_eval(index, &|name| self.constant(name)) {
+            if n < 0 || n >= i64::from(len) {
+                self.diagnostics.report_error(
+                    format!("Index {} is out of bounds for an array of {} elements", n, len),
+                    index.loc,
+                );
+            }
+            return;
+        }
+
+        if let ExprKind::Variable(name) = &index.kind {
+            if let Some(Binding { kind: BindingKind::Loop { start, end }, .. }) = self.lookup(name) {
+                if start < 0 || end > i64::from(len) {
+                    self.diagnostics
+                        .report_error(
+                            format!(
+                                "Loop counter `{}` runs over {}..{}, outside an array of {} elements",
+                                name, start, end, len
+                            ),
+                            index.loc,
+                        )
+                        .with_help(format!("Bound the loop by the array length: `0..{}`", len));
+                }
+                return;
+            }
+        }
+
+        self.diagnostics
+            .report_error("Array index must be a constant or a loop counter", index.loc)
+            .with_help(format!("The verifier must see the index stay below {}; index inside `for i in 0..{}`", len, len));
+    }
+
+    /// Checks `ptr.field` and returns the field's type.
+    fn check_field(&mut self, access: &FieldAccess) -> Ty<'a> {
+        match self.check_expr(&access.base) {
+            Ty::Ptr(Object::Struct(decl)) => match decl.field(&access.field) {
+                Some(field) => match field.ty {
+                    DataType::Scalar(ty) => Ty::Int(ty),
+                    DataType::Array(elem, len) => Ty::Aggregate(Object::Array(elem, len)),
+                    // Nested structs are rejected with the declaration.
+                    DataType::Struct(_) => Ty::Error,
+                },
+                None => {
+                    let names: Vec<&str> = decl.fields.iter().map(|f| f.name.as_str()).collect();
+                    self.diagnostics
+                        .report_error(
+                            format!("Struct `{}` has no field `{}`", decl.name, access.field),
+                            access.base.loc,
+                        )
+                        .with_help(format!("Fields are: {}", names.join(", ")));
+                    Ty::Error
+                }
+            },
+            Ty::Error => Ty::Error,
+            other => {
+                self.diagnostics
+                    .report_error(format!("Cannot access field `{}` of {}", access.field, describe(other)), access.base.loc)
+                    .with_help("Only `heap` pointers to struct map values have fields");
+                Ty::Error
+            }
+        }
+    }
+
+    /// Checks that a struct literal names a declared struct and sets every
+    /// field exactly once, so no key byte is left unset.
+    fn check_struct_literal(&mut self, literal: &StructLiteral, loc: SourceLoc) -> Ty<'a> {
+        let Some(decl) = self.structs.get(literal.name.as_str()).copied() else {
+            self.diagnostics.report_error(format!("Unknown struct: `{}`", literal.name), loc);
+            return Ty::Error;
+        };
+
+        let mut seen = Vec::new();
+        for init in &literal.fields {
+            let value = self.check_expr(&init.value);
+            match decl.field(&init.name) {
+                None => {
+                    self.diagnostics
+                        .report_error(format!("Struct `{}` has no field `{}`", decl.name, init.name), init.loc);
+                }
+                Some(_) if seen.contains(&init.name.as_str()) => {
+                    self.diagnostics.report_error(format!("Field `{}` is set twice", init.name), init.loc);
+                }
+                Some(field) => {
+                    seen.push(init.name.as_str());
+                    match &field.ty {
+                        DataType::Scalar(ty) => self.check_assignable(value, *ty, init.value.loc),
+                        DataType::Array(elem, len) => {
+                            let expected = Object::Array(*elem, *len);
+                            if value != Ty::Aggregate(expected) && value != Ty::Error {
+                                self.diagnostics
+                                    .report_error(
+                                        format!("Type mismatch: expected {}, found {}", expected, describe(value)),
+                                        init.value.loc,
+                                    )
+                                    .with_help(format!("Pass a `reg` buffer declared as `{}`", expected));
+                            }
+                        }
+                        DataType::Struct(_) => {}
+                    }
+                }
+            }
+        }
+
+        let missing: Vec<&str> = decl
+            .fields
+            .iter()
+            .map(|f| f.name.as_str())
+            .filter(|name| !seen.contains(name))
+            .collect();
+        if !missing.is_empty() {
+            self.diagnostics
+                .report_error(format!("Missing fields in `{}`: {}", decl.name, missing.join(", ")), loc)
+                .with_help("Every field of a map key must be set");
+        }
+        Ty::Aggregate(Object::Struct(decl))
+    }
+
+    fn report_aggregate_value(&mut self, object: Object<'a>, loc: SourceLoc) {
+        match object {
+            Object::Array(..) => {
+                self.diagnostics
+                    .report_error("An array can only be indexed or used as a map key", loc)
+                    .with_help("Read one element with `[i]`");
+            }
+            _ => {
+                self.diagnostics
+                    .report_error("A struct value can only be used as a map key", loc)
+                    .with_help("Pass it directly to `lookup`");
+            }
+        }
+    }
+
+    fn report_whole_assignment(&mut self, object: Object<'a>, loc: SourceLoc) {
+        self.diagnostics
+            .report_error(format!("Cannot assign to a {} as a whole", object), loc)
+            .with_help("Assign its elements by index");
+    }
+
+    /// What a key or value of type `ty` is to the checker. `None` for an
+    /// unknown struct, which is reported with the map declaration.
+    fn resolve(&self, ty: &DataType) -> Option<Object<'a>> {
+        match ty {
+            DataType::Scalar(ty) => Some(Object::Scalar(*ty)),
+            DataType::Struct(name) => self.structs.get(name.as_str()).map(|decl| Object::Struct(decl)),
+            DataType::Array(elem, len) => Some(Object::Array(*elem, *len)),
+        }
+    }
+
+    /// Type of a `heap` pointer to a value of `ty`.
+    fn pointer_to(&self, ty: &DataType) -> Ty<'a> {
+        self.resolve(ty).map_or(Ty::Error, Ty::Ptr)
+    }
+
+    /// A loop bound must be known at compile time so the verifier can prove
+    /// the loop terminates.
+    fn check_loop_bound(&mut self, bound: &Expr) -> Option<i64> {
+        let ty = self.check_expr(bound);
+        let value = const_eval(bound, &|name| self.constant(name));
+        if value.is_none() && ty != Ty::Error {
+            self.diagnostics
+                .report_error("Loop bound must be a constant", bound.loc)
+                .with_help("Declare the bound with `imm` so the trip count is known at compile time");
+        }
+        value
+    }
+
+    fn check_method_call(&mut self, call: &MethodCall, loc: SourceLoc) -> Ty<'a> {
+        if call.inner_key.is_some() {
+            let nested = call.method == "lookup"
+                && self.maps.get(call.receiver.as_str()).is_some_and(|map| map.map_type.is_map_of_maps());
+            if !nested {
+                self.diagnostics
+                    .report_error(format!("`{}.{}` does not find a map to look up in", call.receiver, call.method), loc)
+                    .with_help("Only `lookup` on an `array_of_maps` or `hash_of_maps` can be followed by `.lookup(key)`");
+                return Ty::Error;
+            }
+        }
+
+        if call.receiver == "ctx" {
+            let Some((_, ty)) = ctx_load(&call.method) else {
+                self.diagnostics
+                    .report_error(format!("Unknown context method: `ctx.{}`", call.method), loc)
+                    .with_help("Context loads are `load_u8` .. `load_u64` and `load_i8` .. `load_i64`");
+                return Ty::Error;
+            };
+
+            if self.check_arity(call, (1, 1), loc) {
+                let offset = &call.args[0];
+                match self.check_expr(offset) {
+                    Ty::Const(_) | Ty::Error => {}
+                    _ => {
+                        self.diagnostics
+                            .report_error("Context load offset must be a constant", offset.loc);
+                    }
+                }
+            }
+            return Ty::Int(ty);
+        }
+
+        let Some(map) = self.maps.get(call.receiver.as_str()).copied() else {
+            self.diagnostics.report_error(format!("Unknown map: `{}`", call.receiver), loc);
+            return Ty::Error;
+        };
+        let Some(arity) = map_method_arity(&call.method) else {
+            self.diagnostics
+                .report_error(format!("Unknown map method: `{}.{}`", call.receiver, call.method), loc);
+            return Ty::Error;
+        };
+        if !supports_method(map.map_type, &call.method) {
+            self.diagnostics
+                .report_error(format!("Map `{}` does not support `{}`", call.receiver, call.method), loc)
+                .with_help(format!("`{}` is declared `type: .{};`", call.receiver, map.map_type));
+            return Ty::Error;
+        }
+        if !self.check_arity(call, arity, loc) {
+            return Ty::Error;
+        }
+
+        let args = &call.args;
+        let value_type = map.value_type.as_ref();
+        match call.method.as_str() {
+            "lookup" => {
+                self.check_map_operand(&args[0], map.key_type.as_ref());
+                match (&map.inner, &call.inner_key) {
+                    (Some(inner), Some(inner_key)) => self.check_inner_lookup(inner, inner_key),
+                    (Some(_), None) => {
+                        self.diagnostics
+                            .report_error(
+                                format!("A lookup in `{}` finds a map, not a value", call.receiver),
+                                loc,
+                            )
+                            .with_help(format!("Look up a key in it too: `{}.lookup(key).lookup(inner_key)`", call.receiver));
+                        Ty::Error
+                    }
+                    (None, _) => value_type.map_or(Ty::Error, |ty| self.pointer_to(ty)),
+                }
+            }
+            "lookup_or_init" => {
+                self.check_map_operand(&args[0], map.key_type.as_ref());
+                self.check_map_operand(&args[1], value_type);
+                value_type.map_or(Ty::Error, |ty| self.pointer_to(ty))
+            }
+            "update" => {
+                self.check_map_operand(&args[0], map.key_type.as_ref());
+                self.check_map_operand(&args[1], value_type);
+                if let Some(flags) = args.get(2) {
+                    self.check_flags(flags, map, "update");
+                }
+                Ty::Int(Type::I64)
+            }
+            "delete" => {
+                self.check_map_operand(&args[0], map.key_type.as_ref());
+                Ty::Int(Type::I64)
+            }
+            "push" => {
+                self.check_map_operand(&args[0], value_type);
+                if let Some(flags) = args.get(1) {
+                    self.check_flags(flags, map, "push");
+                }
+                Ty::Int(Type::I64)
+            }
+            "reserve" => self.check_reserve(call, loc),
+            "submit" | "discard" => {
+                self.check_record(call, &args[0]);
+                // No value; `check_expr` reports any use of one.
+                Ty::Error
+            }
+            "output" => {
+                self.check_event_value(&args[0]);
+                Ty::Int(Type::I64)
+            }
+            "emit" => {
+                if !matches!(&args[0].kind, ExprKind::Variable(name) if name == "ctx") {
+                    self.diagnostics
+                        .report_error(format!("`{}.emit` takes the program context first", call.receiver), args[0].loc)
+                        .with_help(format!("Write `{}.emit(ctx, value)`", call.receiver));
+                }
+                self.check_event_value(&args[1]);
+                Ty::Int(Type::I64)
+            }
+            _ => value_type.map_or(Ty::Error, |ty| self.pointer_to(ty)),
+        }
+    }
+
+    /// Checks a value copied out to userspace by `output` or `emit`. Its
+    /// bytes are sent as they are, so a map pointer would only send an
+    /// address.
+    fn check_event_value(&mut self, value: &Expr) {
+        let ty = self.check_expr(value);
+        if let Ty::Ptr(_) = ty {
+            self.diagnostics
+                .report_error(format!("Cannot send {}", describe(ty)), value.loc)
+                .with_help("Send the value it points to");
+        }
+    }
+
+    /// Checks `ringbuf.reserve(T)` and returns a pointer to the record.
+    fn check_reserve(&mut self, call: &MethodCall, loc: SourceLoc) -> Ty<'a> {
+        let Some(ty) = &call.type_arg else {
+            self.diagnostics
+                .report_error(format!("`{}.reserve` needs the record type", call.receiver), loc)
+                .with_help(format!("Write `{}.reserve(T)` with the type of the record", call.receiver));
+            return Ty::Error;
+        };
+        match self.resolve(ty) {
+            Some(object) => Ty::Ptr(object),
+            None => {
+                self.diagnostics
+                    .report_error(format!("Unknown type: `{}`", ty), loc)
+                    .with_help("Declare it with `struct` or use a primitive type");
+                Ty::Error
+            }
+        }
+    }
+
+    /// The argument of `submit` and `discard` must be a record from
+    /// `reserve`.
+    fn check_record(&mut self, call: &MethodCall, record: &Expr) {
+        let ty = self.check_expr(record);
+        let is_record = match &record.kind {
+            ExprKind::Variable(name) => {
+                matches!(self.lookup(name), Some(Binding { kind: BindingKind::Record, .. }))
+            }
+            _ => false,
+        };
+        if !is_record && ty != Ty::Error {
+            self.diagnostics
+                .report_error(
+                    format!("`{}.{}` takes a record from `reserve`, found {}", call.receiver, call.method, describe(ty)),
+                    record.loc,
+                )
+                .with_help(format!("Reserve it with `heap r = {}.reserve(T);`", call.receiver));
+        }
+    }
+
+    /// Checks `tail_call(map, index)`. A tail call cannot change the
+    /// program type, so a constant index must not name a program of
+    /// another type; `check_programs` keeps the whole map to one type.
+    fn check_tail_call(&mut self, call: &TailCall, loc: SourceLoc) {
+        let index = self.check_expr(&call.index);
+
+        let Some(map) = self.maps.get(call.map_name.as_str()).copied() else {
+            self.diagnostics.report_error(format!("Unknown map: `{}`", call.map_name), loc);
+            return;
+        };
+        if map.map_type != MapType::ProgArray {
+            self.diagnostics
+                .report_error(format!("Cannot tail call through map `{}`", call.map_name), loc)
+                .with_help(format!("`{}` is declared `type: .{};`, not `.prog_array`", call.map_name, map.map_type));
+            return;
+        }
+        self.check_assignable(index, Type::U32, call.index.loc);
+
+        let Some(caller) = self.program_type else {
+            return;
+        };
+        // `check_programs` keeps a prog_array to one program type, so any
+        // slot stands for the map; name the one a constant index picks.
+        let index = const_eval(&call.index, &|name| self.constant(name));
+        let Some(slot) = index
+            .and_then(|index| map.programs.iter().find(|slot| i64::from(slot.index) == index))
+            .or_else(|| map.programs.first())
+        else {
+            return;
+        };
+        let callee = self
+            .units
+            .get(slot.unit.as_str())
+            .and_then(|unit| unit.sections.first())
+            .and_then(|s| SectionValidator::program_type(s));
+        if let Some(callee) = callee.filter(|&callee| callee != caller) {
+            self.diagnostics
+                .report_error(
+                    format!("Cannot tail call from a {} program into `{}`, a {} program", caller, slot.unit, callee),
+                    loc,
+                )
+                .with_help("A tail call cannot change the program type");
+        }
+    }
+
+    /// Checks `redirect(map, key, fallback)`. The fallback is the verdict
+    /// returned when the slot is empty, by name or value, and must be a
+    /// constant, as it is passed in the helper's flags.
+    fn check_redirect(&mut self, redirect: &Redirect, loc: SourceLoc) -> Ty<'a> {
+        let Some(map) = self.maps.get(redirect.map_name.as_str()).copied() else {
+            self.diagnostics.report_error(format!("Unknown map: `{}`", redirect.map_name), loc);
+            return Ty::Error;
+        };
+        let program_types = redirect_targets(map.map_type);
+        if program_types.is_empty() {
+            self.diagnostics
+                .report_error(format!("Cannot redirect through map `{}`", redirect.map_name), loc)
+                .with_help(format!(
+                    "`{}` is declared `type: .{};`; redirect through a devmap, devmap_hash, cpumap, xskmap, \
+                     sockmap or sockhash",
+                    redirect.map_name, map.map_type
+                ));
+            return Ty::Error;
+        }
+        self.check_map_operand(&redirect.key, map.key_type.as_ref());
+
+        if let Some(caller) = self.program_type.filter(|caller| !program_types.contains(caller)) {
+            self.diagnostics
+                .report_error(
+                    format!("Cannot redirect through `{}` in a {} program", redirect.map_name, caller),
+                    loc,
+                )
+                .with_help(format!(
+                    "A {} is only a redirect target in {} programs",
+                    map.map_type,
+                    program_types.join(" and ")
+                ));
+            return Ty::Error;
+        }
+
+        let fallbacks = SectionValidator::redirect_fallbacks(self.section).unwrap_or_default();
+        let help = format!("Expected one of: {}", fallbacks.join(", "));
+        let value = fallback_value(&redirect.fallback, self.section, &|name| self.constant(name));
+        match value {
+            Some(value) if fallbacks.iter().any(|&name| SectionValidator::verdict_value(name) == Some(value)) => {}
+            Some(value) => {
+                self.diagnostics
+                    .report_error(format!("Invalid redirect fallback: {}", value), redirect.fallback.loc)
+                    .with_help(help);
+            }
+            None => match &redirect.fallback.kind {
+                ExprKind::Variable(name) if self.lookup(name).is_none() => {
+                    self.diagnostics
+                        .report_error(format!("Unknown redirect fallback: `{}`", name), redirect.fallback.loc)
+                        .with_help(help);
+                }
+                _ if self.check_expr(&redirect.fallback) != Ty::Error => {
+                    self.diagnostics
+                        .report_error("Redirect fallback must be a constant", redirect.fallback.loc)
+                        .with_help(help);
+                }
+                _ => {}
+            },
+        }
+        Ty::Int(Type::I64)
+    }
+
+    /// Reports a call with fewer or more arguments than `min..=max`.
+    fn check_arity(&mut self, call: &MethodCall, (min, max): (usize, usize), loc: SourceLoc) -> bool {
+        let found = call.args.len();
+        if (min..=max).contains(&found) {
+            return true;
+        }
+        let expected = match (min, max) {
+            (0, 0) => "no arguments".to_string(),
+            (1, 1) => "1 argument".to_string(),
+            _ if min == max => format!("{} arguments", min),
+            _ => format!("{} or {} arguments", min, max),
+        };
+        self.diagnostics.report_error(
+            format!("`{}.{}` takes {}, found {}", call.receiver, call.method, expected, found),
+            loc,
+        );
+        false
+    }
+
+    /// Checks a key or value passed to a map helper against the map's
+    /// declared type.
+    fn check_map_operand(&mut self, expr: &Expr, ty: Option<&DataType>) {
+        if let ExprKind::Cidr(cidr) = &expr.kind {
+            self.check_cidr_key(cidr, ty, expr.loc);
+            return;
+        }
+        let value = self.check_expr(expr);
+        match ty.and_then(|ty| self.resolve(ty)) {
+            Some(Object::Scalar(ty)) => self.check_assignable(value, ty, expr.loc),
+            Some(object) if value != Ty::Aggregate(object) && value != Ty::Error => {
+                let help = match object {
+                    Object::Struct(decl) => format!("Build it with `{} {{ ... }}`", decl.name),
+                    _ => format!("Fill a `reg` buffer declared as `{}` and pass it", object),
+                };
+                self.diagnostics
+                    .report_error(format!("Type mismatch: expected {}, found {}", object, describe(value)), expr.loc)
+                    .with_help(help);
+            }
+            _ => {}
+        }
+    }
+
+    /// Checks the second lookup of `outer.lookup(k).lookup(key)`, which
+    /// happens in a map shaped like the `inner` prototype.
+    fn check_inner_lookup(&mut self, inner: &str, key: &Expr) -> Ty<'a> {
+        // An unknown prototype is reported with the map declaration.
+        let Some(prototype) = self.maps.get(inner).copied() else {
+            return Ty::Error;
+        };
+        if !supports_method(prototype.map_type, "lookup") {
+            self.diagnostics
+                .report_error(format!("Inner map `{}` does not support `lookup`", inner), key.loc)
+                .with_help(format!("`{}` is declared `type: .{};`", inner, prototype.map_type));
+            return Ty::Error;
+        }
+        self.check_map_operand(key, prototype.key_type.as_ref());
+        prototype.value_type.as_ref().map_or(Ty::Error, |ty| self.pointer_to(ty))
+    }
+
+    /// A CIDR literal builds an `lpm_trie` key, whose address must be as wide
+    /// as the literal's.
+    fn check_cidr_key(&mut self, cidr: &Cidr, ty: Option<&DataType>, loc: SourceLoc) {
+        let data = match ty.and_then(|ty| self.resolve(ty)) {
+            Some(Object::Struct(decl)) => decl.lpm_data(),
+            None => return,
+            Some(_) => None,
+        };
+        let Some(data) = data else {
+            self.diagnostics
+                .report_error("A CIDR literal can only be the key of an `lpm_trie` map", loc)
+                .with_help("The key struct needs `prefixlen: u32` followed by the address");
+            return;
+        };
+
+        let width = cidr.octets().len() as u32;
+        let (size, _) = field_layout(&data.ty);
+        if size != width {
+            let family = if cidr.addr.is_ipv4() { "IPv4" } else { "IPv6" };
+            self.diagnostics
+                .report_error(
+                    format!(
+                        "Type mismatch: {} is an {} prefix but the key address `{}` is {} bytes",
+                        cidr.addr, family, data.name, size
+                    ),
+                    loc,
+                )
+                .with_help(format!("{} addresses are {} bytes", family, width));
+        }
+    }
+
+    /// Flags select the kernel's `BPF_ANY`, `BPF_NOEXIST` or `BPF_EXIST`
+    /// behaviour, by name or number, and must be known at compile time.
+    fn check_flags(&mut self, flags: &Expr, map: &MapDecl, method: &str) {
+        let accepted = accepted_flags(map.map_type, method);
+        let help = format!("Expected one of: {}", accepted.join(", "));
+        let Some(value) = flags_value(flags, &|name| self.constant(name)) else {
+            if self.check_expr(flags) != Ty::Error {
+                self.diagnostics
+                    .report_error("Map flags must be a constant", flags.loc)
+                    .with_help(help);
+            }
+            return;
+        };
+        if accepted.iter().any(|name| map_flag(name) == Some(value)) {
+            return;
+        }
+        let message = match MAP_FLAGS.iter().find(|(_, flag)| *flag == value) {
+            Some((name, _)) => format!("`{}` does not apply to `{}.{}`", name, map.name, method),
+            None => format!("Invalid map flags: {}", value),
+        };
+        self.diagnostics.report_error(message, flags.loc).with_help(help);
+    }
+
+    /// Checks that `ptr` is a map pointer and returns its pointee type.
+    fn check_deref(&mut self, ptr: &Expr) -> Option<Type> {
+        match self.check_expr(ptr) {
+            Ty::Ptr(Object::Scalar(pointee)) => Some(pointee),
+            Ty::Error => None,
+            Ty::Ptr(object) => {
+                let help = match object {
+                    Object::Struct(_) => "Access its fields with `.`",
+                    _ => "Access its elements with `[i]`",
+                };
+                self.diagnostics
+                    .report_error(format!("Cannot dereference a pointer to {}", object), ptr.loc)
+                    .with_help(help);
+                None
+            }
+            other => {
+                self.diagnostics
+                    .report_error(format!("Cannot dereference {}", describe(other)), ptr.loc)
+                    .with_help("Only `heap` bindings from a map lookup can be dereferenced");
+                None
+            }
+        }
+    }
+
+    fn check_assignable(&mut self, value: Ty<'a>, target: Type, loc: SourceLoc) {
+        match value {
+            Ty::Int(ty) if !ty.widens_to(target) => {
+                self.diagnostics
+                    .report_error(format!("Type mismatch: expected {}, found {}", target, ty), loc)
+                    .with_help(format!("Convert explicitly with `as {}`", target));
+            }
+            Ty::Const(n) => self.check_constant_fits(n, target, loc),
+            Ty::Ptr(object) => {
+                let help = match object {
+                    Object::Scalar(_) => "Dereference it with `*` to use the value",
+                    Object::Struct(_) => "Read one of its fields with `.`",
+                    Object::Array(..) => "Read one of its elements with `[i]`",
+                };
+                self.diagnostics
+                    .report_error(format!("Type mismatch: expected {}, found {}", target, describe(value)), loc)
+                    .with_help(help);
+            }
+            Ty::Aggregate(object) => self.report_aggregate_value(object, loc),
+            _ => {}
+        }
+    }
+
+    /// Checks `atomic place op= value`. Only the operators with a fetch-and-op
+    /// instruction can be atomic.
+    fn check_atomic_update(&mut self, assign: &Assignment, value: Ty<'a>, loc: SourceLoc) {
+        if atomic_op(assign.op).is_none() {
+            self.diagnostics
+                .report_error("`atomic` only applies to `+=`, `-=`, `&=`, `|=` and `^=`", loc)
+                .with_help("Replace a value with `atomic xchg(place, value)`");
+        }
+        if let Some(ty) = self.check_atomic_place(&assign.target) {
+            self.check_assigned_value(assign.op, value, ty, assign.value.loc);
+        }
+    }
+
+    fn check_atomic_expr(&mut self, atomic: &AtomicExpr) -> Ty<'a> {
+        let ty = self.check_atomic_place(&atomic.place);
+        for arg in &atomic.args {
+            let value = self.check_expr(arg);
+            if let Some(ty) = ty {
+                self.check_assignable(value, ty, arg.loc);
+            }
+        }
+        ty.map_or(Ty::Error, Ty::Int)
+    }
+
+    /// Checks the target of an atomic operation and returns its type. The
+    /// instructions only exist for 32- and 64-bit words, and only map values
+    /// are shared with other CPUs.
+    fn check_atomic_place(&mut self, place: &Expr) -> Option<Type> {
+        let in_map_value = match &place.kind {
+            ExprKind::Dereference(_) | ExprKind::Field(_) => true,
+            ExprKind::Index(index) => match &index.base.kind {
+                ExprKind::Field(_) => true,
+                ExprKind::Variable(name) => {
+                    matches!(self.lookup(name), Some(Binding { kind: BindingKind::Heap, .. }))
+                }
+                _ => false,
+            },
+            _ => false,
+        };
+        if !in_map_value {
+            self.diagnostics
+                .report_error("Atomic operations only apply to map values", place.loc)
+                .with_help("Use `*p`, `p.field` or `p[i]` where `p` is a `heap` lookup");
+            return None;
+        }
+
+        let ty = match &place.kind {
+            ExprKind::Dereference(ptr) => self.check_deref(ptr)?,
+            _ => match self.check_expr(place) {
+                Ty::Int(ty) => ty,
+                Ty::Aggregate(object) => {
+                    self.report_whole_assignment(object, place.loc);
+                    return None;
+                }
+                _ => return None,
+            },
+        };
+        if !matches!(ty.size(), 4 | 8) {
+            self.diagnostics
+                .report_error(format!("Atomic operations need a 32- or 64-bit value, found {}", ty), place.loc)
+                .with_help("Declare the map value or field as u32 or u64");
+            return None;
+        }
+        Some(ty)
+    }
+
+    /// Checks `left << right` or `left >> right`. The result has the left
+    /// operand's type; the right one only needs to be an integer.
+    fn check_shift(&mut self, bin: &BinaryExpr, left: Ty<'a>, right: Ty<'a>, loc: SourceLoc) -> Ty<'a> {
+        match (left, right) {
+            (Ty::Const(l), Ty::Const(r)) => match fold_binary(bin.op, l, r) {
+                Some(n) => Ty::Const(n),
+                None => {
+                    self.diagnostics
+                        .report_error("Constant expression overflows or divides by zero", loc);
+                    Ty::Error
+                }
+            },
+            (Ty::Int(t), Ty::Const(n)) => {
+                self.check_shift_amount(n, t, bin.right.loc);
+                Ty::Int(t)
+            }
+            (Ty::Const(n), Ty::Int(t)) => {
+                self.check_constant_fits(n, t, bin.left.loc);
+                Ty::Int(t)
+            }
+            (Ty::Int(t), Ty::Int(_)) => Ty::Int(t),
+            _ => Ty::Error,
+        }
+    }
+
+    /// Shifting by the width of the type or more is undefined in C and
+    /// the verifier's results for it differ from the CPU's.
+    fn check_shift_amount(&mut self, amount: i64, ty: Type, loc: SourceLoc) {
+        let bits = i64::from(ty.size()) * 8;
+        if !(0..bits).contains(&amount) {
+            self.diagnostics
+                .report_warning(format!("Shift by {} is out of range for {}", amount, ty), loc)
+                .with_help(format!("A {} can only be shifted by 0 to {} bits", ty, bits - 1));
+        }
+    }
+
+    /// Checks a constant operand of `op` against the other operand's type.
+    fn check_operand_constant(&mut self, op: BinOp, value: i64, ty: Type, loc: SourceLoc) {
+        if !(is_bitwise_op(op) && ty.holds_bits(value)) {
+            self.check_constant_fits(value, ty, loc);
+        }
+    }
+
+    /// Checks the value of `target op= value` against the target's type.
+    fn check_assigned_value(&mut self, op: AssignmentOp, value: Ty<'a>, target: Type, loc: SourceLoc) {
+        match (op.binary_op(), value) {
+            (Some(op), Ty::Const(n)) if is_shift_op(op) => self.check_shift_amount(n, target, loc),
+            (Some(op), Ty::Int(_)) if is_shift_op(op) => {}
+            (Some(op), Ty::Const(n)) if is_bitwise_op(op) && target.holds_bits(n) => {}
+            _ => self.check_assignable(value, target, loc),
+        }
+    }
+
+    /// Checks the constant initialiser of a binding declared with type `ty`.
+    fn check_declared_constant(&mut self, expr: &Expr, value: i64, ty: Type) {
+        if !(is_bit_pattern(expr) && ty.holds_bits(value)) {
+            self.check_constant_fits(value, ty, expr.loc);
+        }
+    }
+
+    fn check_constant_fits(&mut self, value: i64, ty: Type, loc: SourceLoc) {
+        if !ty.fits(value) {
+            self.diagnostics
+                .report_error(format!("Constant {} does not fit in {}", value, ty), loc);
+        }
+    }
+}
+
+fn describe(ty: Ty) -> String {
+    match ty {
+        Ty::Int(t) => t.to_string(),
+        Ty::Const(n) => format!("constant {}", n),
+        Ty::Ptr(object) => format!("pointer to {}", object),
+        Ty::Aggregate(object) => object.to_string(),
+        Ty::Error => "an invalid value".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::testing::{assert_error, errors};
+
+    /// `body` in an XDP unit, after the map declarations in `maps`.
+    fn xdp(maps: &str, body: &str) -> String {
+        format!("{}\nunit t {{\n    section: \"xdp\";\n    license: \"GPL\";\n\n{}\n}}\n", maps, body)
+    }
+
+    const COUNTS: &str = "map counts {\n    type: .hash;\n    key: u16;\n    value: u32;\n    max: 64;\n}\n";
+
+    #[test]
+    fn keys_and_values_match_the_map() {
+        let src = xdp(
+            COUNTS,
+            "    reg port = ctx.load_u16(36);\n    heap c = counts.lookup(port);\n    if guard(c) {\n        *c += 1;\n    }\n    return 2;",
+        );
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn mismatched_key_is_rejected() {
+        let src = xdp(COUNTS, "    reg ip = ctx.load_u32(26);\n    heap c = counts.lookup(ip);\n    return 2;");
+        assert_error(&src, "Type mismatch: expected u16, found u32");
+
+        let src = xdp(COUNTS, "    imm k = 70000;\n    heap c = counts.lookup(k);\n    return 2;");
+        assert_error(&src, "Constant 70000 does not fit in u16");
+    }
+
+    #[test]
+    fn operands_must_agree() {
+        let src = xdp("", "    reg a = ctx.load_u32(26);\n    reg b = ctx.load_i32(10);\n    reg m = a + b;\n    return 2;");
+        assert_error(&src, "Mismatched operand types: u32 and i32");
+    }
+
+    #[test]
+    fn bindings_are_used_as_declared() {
+        let src = xdp("", "    reg ip = ctx.load_u32(26);\n    imm x = ip;\n    return 2;");
+        assert_error(&src, "`imm x` must be initialised with a constant");
+
+        let src = xdp("", "    reg ip = ctx.load_u32(26);\n    *ip = 1;\n    return 2;");
+        assert_error(&src, "Cannot dereference u32");
+
+        let src = xdp(COUNTS, "    reg q = counts.lookup(1);\n    return 2;");
+        assert_error(&src, "A map lookup result must be bound with `heap`");
+
+        let src = xdp("", "    reg z = ctx.load_u7(0);\n    return 2;");
+        assert_error(&src, "Unknown context method: `ctx.load_u7`");
+    }
+
+    #[test]
+    fn annotations_and_casts_convert_explicitly() {
+        let maps = "map by_port {\n    type: .hash;\n    key: u16;\n    value: u64;\n    max: 64;\n}\n";
+        let src = xdp(
+            maps,
+            "    reg ip: u64 = ctx.load_u32(26);\n    reg wide: i64 = ctx.load_i8(3);\n    imm base: u16 = 1000;\n    reg port = (ip as u16) + base;\n    heap h: u64 = by_port.lookup(port);\n    if guard(h) {\n        *h += wide as u64;\n    }\n    return 2;",
+        );
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn annotations_do_not_narrow_implicitly() {
+        let maps = "map by_port {\n    type: .hash;\n    key: u16;\n    value: u64;\n    max: 64;\n}\n";
+        let src = xdp("", "    reg ip: u16 = ctx.load_u32(26);\n    return 2;");
+        assert_error(&src, "Type mismatch: expected u16, found u32");
+
+        let src = xdp("", "    imm b: u8 = 300;\n    return 2;");
+        assert_error(&src, "Constant 300 does not fit in u8");
+
+        let src = xdp(maps, "    heap h: u32 = by_port.lookup(1);\n    return 2;");
+        assert_error(&src, "Type mismatch: map `by_port` holds u64 values, not u32");
+    }
+
+    #[test]
+    fn loops_run_a_constant_number_of_times() {
+        let src = xdp("", "    imm n = 8192;\n    reg total: u64 = 0;\n    for i in 0..n {\n        total += i as u64;\n    }\n    return 2;");
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+
+        let src = xdp("", "    reg n = ctx.load_u8(0);\n    for i in 0..n {\n    }\n    return 2;");
+        assert_error(&src, "Loop bound must be a constant");
+    }
+
+    #[test]
+    fn loops_the_verifier_cannot_walk_are_rejected() {
+        let src = xdp("", "    for i in 0..8193 {\n    }\n    return 2;");
+        assert_error(&src, "Loop runs 8193 times; at most 8192 iterations are allowed");
+
+        let src = xdp("", "    for i in 0..100 {\n        for j in 0..100 {\n            for k in 0..2 {\n            }\n        }\n    }\n    return 2;");
+        assert_eq!(
+            errors(&src),
+            ["Loop body runs 10000 times counting the loops around it; at most 8192 iterations are allowed"]
+        );
+    }
+
+    #[test]
+    fn struct_keys_and_fields_are_checked() {
+        let maps = "struct k { a: u32, b: u16 }\nstruct v { n: u64 }\nmap m { type: .hash; key: k; value: v; max: 8; }\n";
+        let src = xdp(
+            maps,
+            "    heap p = m.lookup(k { a: ctx.load_u32(26), b: 2 });\n    if guard(p) {\n        p.n += 1;\n    }\n    return 2;",
+        );
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+
+        let src = xdp(maps, "    heap p = m.lookup(k { a: 1, c: 2 });\n    return 2;");
+        assert_error(&src, "Struct `k` has no field `c`");
+
+        let src = xdp(maps, "    heap p = m.lookup(5);\n    return 2;");
+        assert_error(&src, "Type mismatch: expected struct k, found constant 5");
+
+        let src = xdp(maps, "    reg x = v { n: 1 };\n    return 2;");
+        assert_error(&src, "A struct value can only be used as a map key");
+
+        let src = xdp(maps, "    heap p = m.lookup(k { a: 1, b: 2 });\n    if guard(p) {\n        reg z: u8 = p.n;\n    }\n    return 2;");
+        assert_error(&src, "Type mismatch: expected u8, found u64");
+    }
+
+    #[test]
+    fn arrays_are_indexed_within_bounds() {
+        let maps = "map by_name {\n    type: .hash;\n    key: u8[4];\n    value: u64;\n    max: 8;\n}\n";
+        let src = xdp(
+            maps,
+            "    reg name: u8[4];\n    for i in 0..4 {\n        name[i] = ctx.load_u8(14);\n    }\n    heap n = by_name.lookup(name);\n    return name[3];",
+        );
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn out_of_bounds_and_whole_array_uses_are_rejected() {
+        let src = xdp("", "    reg buf: u8[4];\n    buf[4] = 1;\n    return 2;");
+        assert_error(&src, "Index 4 is out of bounds for an array of 4 elements");
+
+        let src = xdp("", "    reg buf: u8[4];\n    reg k: u32 = 2;\n    buf[k] = 1;\n    return 2;");
+        assert_error(&src, "Array index must be a constant or a loop counter");
+
+        let src = xdp("", "    reg buf: u8[4];\n    for i in 0..8 {\n        buf[i] = 1;\n    }\n    return 2;");
+        assert_error(&src, "Loop counter `i` runs over 0..8, outside an array of 4 elements");
+
+        let src = xdp("", "    reg buf: u8[4] = 1;\n    return 2;");
+        assert_error(&src, "An array buffer starts zeroed and cannot be initialised");
+
+        let src = xdp("", "    reg buf: u8[4];\n    buf = 3;\n    return 2;");
+        assert_error(&src, "Cannot assign to a u8[4] as a whole");
+    }
+
+    #[test]
+    fn shifts_fold_to_masks_of_the_full_width() {
+        let src = xdp(
+            "",
+            "    imm TOP: u64 = 1 << 63;\n    imm HIGH = 3 << 62;\n    reg m: u64 = 1 << 63;\n    reg x = ctx.load_u64(0) & TOP | HIGH;\n    reg low: u8 = ctx.load_u8(1) & ~0x0f;\n    return 2;",
+        );
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn masks_wider_than_the_type_are_rejected() {
+        let src = xdp("", "    imm A = 1 << 64;\n    return 2;");
+        assert_error(&src, "Constant expression overflows or divides by zero");
+
+        let src = xdp("", "    reg m: u32 = 1 << 40;\n    return 2;");
+        assert_error(&src, "Constant 1099511627776 does not fit in u32");
+
+        let src = xdp("", "    reg c: u8 = ctx.load_u8(1);\n    reg d: u8 = c & 0x1ff;\n    return 2;");
+        assert_error(&src, "Constant 511 does not fit in u8");
+
+        let src = xdp("", "    reg m: u64 = -1;\n    return 2;");
+        assert_error(&src, "Constant -1 does not fit in u64");
+    }
+
+    #[test]
+    fn compound_assignments_keep_the_target_type() {
+        let maps = "struct stats { packets: u64, bytes: u64 }\nmap per_flow { type: .hash; key: u32; value: stats; max: 16; }\n";
+        let src = xdp(
+            maps,
+            "    reg x: u32 = ctx.load_u32(26);\n    x -= 3;\n    x *= 7;\n    x /= 2;\n    x %= 100;\n    heap s = per_flow.lookup(x);\n    if guard(s) {\n        s.bytes -= 10;\n        s.packets *= x;\n    }\n    return 2;",
+        );
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn compound_assignments_do_not_narrow() {
+        let src = xdp("", "    reg a: u8 = 1;\n    a -= ctx.load_u32(0);\n    return 2;");
+        assert_error(&src, "Type mismatch: expected u8, found u32");
+
+        let src = xdp("", "    imm a = 1;\n    a %= 2;\n    return 2;");
+        assert_error(&src, "Cannot assign to `imm a`");
+    }
+
+    #[test]
+    fn atomic_updates_apply_to_wide_map_values() {
+        let maps = "struct stats { packets: u64, hist: u32[4], small: u16 }\nmap per_flow { type: .hash; key: u32; value: stats; max: 16; }\n";
+        let src = xdp(
+            maps,
+            "    heap s = per_flow.lookup(1);\n    if guard(s) {\n        atomic s.packets += 1;\n        atomic s.hist[2] |= 4;\n        reg old = atomic xchg(s.packets, 0);\n        reg prev = atomic cmpxchg(s.packets, old, old + 1);\n    }\n    return 2;",
+        );
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn atomic_updates_elsewhere_are_rejected() {
+        let maps = "struct stats { packets: u64, small: u16 }\nmap per_flow { type: .hash; key: u32; value: stats; max: 16; }\n";
+        let src = xdp("", "    reg x: u32 = 1;\n    atomic x += 1;\n    return 2;");
+        assert_error(&src, "Atomic operations only apply to map values");
+
+        let guarded = |stmt: &str| xdp(maps, &format!("    heap s = per_flow.lookup(1);\n    if guard(s) {{\n        {}\n    }}\n    return 2;", stmt));
+        assert_error(&guarded("atomic s.small += 1;"), "Atomic operations need a 32- or 64-bit value, found u16");
+        assert_error(&guarded("atomic s.packets *= 2;"), "`atomic` only applies to `+=`, `-=`, `&=`, `|=` and `^=`");
+    }
+
+    const SLOTS: &str = "map slots {\n    type: .array;\n    key: u32;\n    value: u64;\n    max: 4;\n}\n";
+
+    #[test]
+    fn map_flags_are_named_or_numbered() {
+        let maps = format!("{}{}", COUNTS, SLOTS);
+        let src = xdp(
+            &maps,
+            "    counts.update(1, 1, BPF_NOEXIST);\n    counts.update(1, 1, 2);\n    slots.update(0, 1, BPF_EXIST);\n    return 2;",
+        );
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn map_flags_are_checked_per_map_kind() {
+        let maps = format!("{}{}", COUNTS, SLOTS);
+        let src = xdp(&maps, "    slots.update(0, 1, BPF_NOEXIST);\n    return 2;");
+        assert_error(&src, "`BPF_NOEXIST` does not apply to `slots.update`");
+
+        let src = xdp(&maps, "    counts.update(1, 1, BPF_F_LOCK);\n    return 2;");
+        assert_error(&src, "`BPF_F_LOCK` does not apply to `counts.update`");
+
+        let src = xdp(&maps, "    counts.update(1, 1, BPF_NONE);\n    return 2;");
+        assert_error(&src, "Undefined variable: `BPF_NONE`");
+    }
+
+    const EVENTS: &str = "map events {\n    type: .ringbuf;\n    max: 4096;\n}\n";
+
+    #[test]
+    fn ring_buffer_records_are_typed() {
+        let src = xdp(
+            EVENTS,
+            "    heap e = events.reserve(u64);\n    if guard(e) {\n        *e = 5;\n        events.submit(e);\n    }\n    reg rc = events.output(7);\n    return 2;",
+        );
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn ring_buffer_misuse_is_rejected() {
+        let src = xdp(EVENTS, "    heap e = events.reserve();\n    return 2;");
+        assert_error(&src, "`events.reserve` needs the record type");
+
+        let maps = format!("{}{}", EVENTS, COUNTS);
+        let src = xdp(&maps, "    heap c = counts.lookup(1);\n    if guard(c) {\n        events.submit(c);\n    }\n    return 2;");
+        assert_error(&src, "`events.submit` takes a record from `reserve`, found pointer to u32");
+
+        let src = xdp(&maps, "    heap c = counts.lookup(1);\n    events.output(c);\n    return 2;");
+        assert_error(&src, "Cannot send pointer to u32");
+    }
+
+    const PERF: &str = "map perf {\n    type: .perf_event_array;\n}\n";
+
+    #[test]
+    fn perf_events_send_values_with_the_context() {
+        let src = xdp(
+            PERF,
+            "    reg src = ctx.load_u32(26);\n    perf.emit(ctx, src);\n    if guard(perf.emit(ctx, 5) != 0) {\n        return 1;\n    }\n    return 2;",
+        );
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn perf_event_misuse_is_rejected() {
+        let src = xdp(PERF, "    perf.emit(1, 2);\n    return 2;");
+        assert_error(&src, "`perf.emit` takes the program context first");
+
+        let maps = format!("{}{}", PERF, COUNTS);
+        let src = xdp(&maps, "    counts.emit(ctx, 1);\n    return 2;");
+        assert_error(&src, "Map `counts` does not support `emit`");
+    }
+
+    const JUMPS: &str = "map jumps {\n    type: .prog_array;\n    max: 2;\n    programs: [1: k];\n}\n\n\
+                         unit k {\n    section: \"kprobe/sys_open\";\n    license: \"GPL\";\n    return 0;\n}\n";
+
+    #[test]
+    fn tail_call_checks_the_map_program_type_for_any_index() {
+        let src = xdp(JUMPS, "    reg i = ctx.load_u8(0);\n    tail_call(jumps, i & 1);\n    return 2;");
+        assert_eq!(errors(&src), ["Cannot tail call from a xdp program into `k`, a kprobe program"]);
+    }
+
+    #[test]
+    fn tail_call_into_another_program_type_is_rejected() {
+        let src = xdp(JUMPS, "    imm SLOT = 1;\n    tail_call(jumps, SLOT);\n    return 2;");
+        assert_eq!(errors(&src), ["Cannot tail call from a xdp program into `k`, a kprobe program"]);
+
+        let maps = format!("{}{}", JUMPS, COUNTS);
+        let src = xdp(&maps, "    tail_call(counts, 0);\n    return 2;");
+        assert_error(&src, "Cannot tail call through map `counts`");
+    }
+
+    const DENY: &str = "struct prefix_v4 {\n    prefixlen: u32,\n    addr: u32\n}\n\n\
+                        map deny {\n    type: .lpm_trie;\n    key: prefix_v4;\n    value: u32;\n    max: 16;\n}\n";
+
+    #[test]
+    fn cidr_literals_key_lpm_tries() {
+        let src = xdp(
+            DENY,
+            "    deny.update(10.0.0.0/8, 1);\n    heap hit = deny.lookup(192.168.1.0/24);\n    if guard(hit) {\n        return 1;\n    }\n    deny.delete(10.0.0.0/8);\n    return 2;",
+        );
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn cidr_literals_are_checked_against_the_key() {
+        let src = xdp(DENY, "    deny.update(2001:db8::/32, 1);\n    return 2;");
+        assert_error(&src, "Type mismatch: 2001:db8:: is an IPv6 prefix but the key address `addr` is 4 bytes");
+
+        let maps = format!("{}{}", DENY, COUNTS);
+        let src = xdp(&maps, "    counts.delete(10.0.0.0/8);\n    return 2;");
+        assert_error(&src, "A CIDR literal can only be the key of an `lpm_trie` map");
+
+        let src = xdp(DENY, "    heap p = deny.lookup_or_init(10.0.0.0/8, 0);\n    return 2;");
+        assert_error(&src, "Map `deny` does not support `lookup_or_init`");
+    }
+
+    const TENANTS: &str = "map tenants {\n    type: .hash_of_maps;\n    key: u32;\n    max: 8;\n    inner: counts;\n}\n";
+
+    #[test]
+    fn map_of_maps_is_looked_up_twice() {
+        let maps = format!("{}{}", COUNTS, TENANTS);
+        let src = xdp(
+            &maps,
+            "    reg t = ctx.load_u32(26);\n    reg port = ctx.load_u16(36);\n    heap c = tenants.lookup(t).lookup(port);\n    if guard(c) {\n        *c += 1;\n    }\n    return 2;",
+        );
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn map_of_maps_lookups_are_checked() {
+        let maps = format!("{}{}", COUNTS, TENANTS);
+        let src = xdp(&maps, "    heap m = tenants.lookup(1);\n    return 2;");
+        assert_error(&src, "A lookup in `tenants` finds a map, not a value");
+
+        let src = xdp(&maps, "    heap c = counts.lookup(1).lookup(2);\n    return 2;");
+        assert_error(&src, "`counts.lookup` does not find a map to look up in");
+
+        let src = xdp(&maps, "    reg ip = ctx.load_u32(26);\n    heap c = tenants.lookup(1).lookup(ip);\n    return 2;");
+        assert_error(&src, "Type mismatch: expected u16, found u32");
+
+        let src = xdp(&maps, "    tenants.update(1, 2);\n    return 2;");
+        assert_error(&src, "Map `tenants` does not support `update`");
+    }
+
+    const REDIRECT_MAPS: &str = "map ports {\n    type: .devmap;\n    max: 8;\n}\n\n\
+                                 map socks {\n    type: .sockmap;\n    max: 8;\n}\n";
+
+    #[test]
+    fn redirect_fallback_is_a_verdict_name_or_value() {
+        let src = xdp(REDIRECT_MAPS, "    reg p = ctx.load_u8(14);\n    if guard(p == 1) {\n        return redirect(ports, p, XDP_DROP);\n    }\n    return redirect(ports, 0, 2);");
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+
+        let src = format!(
+            "{}unit m {{\n    section: \"sk_msg\";\n    license: \"GPL\";\n    return redirect(socks, 1, SK_PASS);\n}}\n",
+            REDIRECT_MAPS
+        );
+        assert!(errors(&src).is_empty(), "{:?}", errors(&src));
+    }
+
+    #[test]
+    fn redirect_fallback_is_checked_against_the_program_type() {
+        let src = xdp(REDIRECT_MAPS, "    return redirect(ports, 0, SK_PASS);");
+        assert_error(&src, "Unknown redirect fallback: `SK_PASS`");
+
+        let src = xdp(REDIRECT_MAPS, "    return redirect(ports, 0, 4);");
+        assert_error(&src, "Invalid redirect fallback: 4");
+
+        let src = xdp(REDIRECT_MAPS, "    reg v = ctx.load_u8(14);\n    return redirect(ports, 0, v);");
+        assert_error(&src, "Redirect fallback must be a constant");
+    }
+}
@@ -1,61 +1,89 @@
 
 use crate::ast::Unit;
-use crate::diagnostics::{DiagnosticReporter, Severity};
-use crate::sema::SectionValidator;
-
-#[derive(Debug, thiserror::Error)]
-pub enum UnitValidationError {
-    #[error("Unit name cannot be empty")]
-    EmptyUnitName,
-    
-    #[error("Unit must have at least one section")]
-    NoSections,
-    
-    #[error("Invalid section name")]
-    InvalidSection,
-    
-    #[error("License is required for eBPF programs")]
-    MissingLicense,
-    
-    #[error("Unit must have at least one return statement or instruction")]
-    NoReturnOrInstructions,
+use crate::diagnostics::DiagnosticReporter;
+use crate::sema::{check_name, SectionValidator};
+use std::collections::HashSet;
+
+/// The object has a single license section, so every unit in the file must
+/// declare the same license.
+pub fn check_licenses(units: &[Unit], diagnostics: &mut DiagnosticReporter) {
+    let mut declared = units.iter().filter_map(|unit| Some((unit, unit.license.as_deref()?)));
+    let Some((first, license)) = declared.next() else {
+        return;
+    };
+    for (unit, other) in declared {
+        if other != license {
+            diagnostics
+                .report_error(
+                    format!(
+                        "Unit '{}' declares license '{}' but '{}' declares '{}'",
+                        unit.name, other, first.name, license
+                    ),
+                    unit.loc,
+                )
+                .with_help("Units in one file are loaded as one object and share its license");
+        }
+    }
 }
 
 pub fn check_unit(
     unit: &Unit,
     diagnostics: &mut DiagnosticReporter,
-) -> Result<(), UnitValidationError> {
+    unit_names: &mut HashSet<String>,
+) {
     if unit.name.is_empty() {
         diagnostics.report_error("Unit name cannot be empty", unit.loc);
-        return Err(UnitValidationError::EmptyUnitName);
+    } else if !unit_names.insert(unit.name.clone()) {
+        diagnostics.report_error(format!("Duplicate unit name: '{}'", unit.name), unit.loc);
     }
+    check_name("Unit", &unit.name, unit.loc, diagnostics);
 
     if unit.sections.is_empty() {
         diagnostics.report_error("Unit must have at least one section", unit.loc);
-        return Err(UnitValidationError::NoSections);
     }
 
     for section in &unit.sections {
         if !SectionValidator::is_valid(section) {
-            diagnostics.report_error(
-                format!("Invalid section name: '{}'", section),
-                unit.loc,
-            );
-            return Err(UnitValidationError::InvalidSection);
+            diagnostics
+                .report_error(format!("Invalid section name: '{}'", section), unit.loc)
+                .with_help(SectionValidator::valid_formats());
         }
     }
 
-    let license = unit.license.as_deref().ok_or_else(|| {
-        diagnostics.report_error("License is required for eBPF programs", unit.loc);
-        UnitValidationError::MissingLicense
-    })?;
+    match unit.license.as_deref() {
+        None => {
+            diagnostics
+                .report_error("License is required for eBPF programs", unit.loc)
+                .with_help("Add `license: \"GPL\";` to the unit");
+        }
+        Some(license) => {
+            let valid_licenses = ["GPL", "Dual BSD/GPL", "GPL v2", "GPL-2.0"];
+            if !valid_licenses.contains(&license) {
+                diagnostics.report_warning(
+                    format!("Unknown license: '{}'. Recommended: GPL", license),
+                    unit.loc,
+                );
+            }
+        }
+    }
 
-    let valid_licenses = ["GPL", "Dual BSD/GPL", "GPL v2", "GPL-2.0"];
-    if !valid_licenses.contains(&license) {
-        diagnostics.report_warning(
-            format!("Unknown license: '{}'. Recommended: GPL", license),
-            unit.loc,
-        );
+    if let Some(fallback) = &unit.fallback {
+        let verdicts = unit.sections.first().and_then(|s| SectionValidator::packet_verdicts(s));
+        match verdicts {
+            None => {
+                diagnostics
+                    .report_error("`fallback` only applies to programs with packet access", unit.loc)
+                    .with_help("XDP, TC, sk_skb and cgroup/skb programs bounds-check packet loads");
+            }
+            Some(verdicts) => {
+                let numeric = fallback.parse::<i64>().is_ok();
+                if !numeric && !verdicts.contains(&fallback.as_str()) {
+                    diagnostics
+                        .report_error(format!("Unknown fallback verdict: '{}'", fallback), unit.loc)
+                        .with_help(format!("Expected one of: {}", verdicts.join(", ")));
+                }
+            }
+        }
     }
 
     if unit.body.is_empty() {
@@ -63,8 +91,52 @@ pub fn check_unit(
             "Unit must have at least one return statement or instruction",
             unit.loc,
         );
-        return Err(UnitValidationError::NoReturnOrInstructions);
     }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::compiler::testing::{assert_error, errors, warnings};
+
+    #[test]
+    fn well_formed_unit_has_no_diagnostics() {
+        let src = "unit ok {\n    section: \"xdp\";\n    license: \"GPL\";\n    return 2;\n}\n";
+        assert!(errors(src).is_empty());
+        assert!(warnings(src).is_empty());
+    }
+
+    #[test]
+    fn every_problem_is_reported_at_once() {
+        let src = "unit bad {\n    section: \"nonsense\";\n    return 2;\n}\n";
+        let errors = errors(src);
+        assert_eq!(errors.len(), 2, "{:?}", errors);
+        assert_error(src, "Invalid section name: 'nonsense'");
+        assert_error(src, "License is required for eBPF programs");
+    }
+
+    #[test]
+    fn unknown_license_is_only_a_warning() {
+        let src = "unit odd {\n    section: \"xdp\";\n    license: \"MIT\";\n    return 2;\n}\n";
+        assert!(errors(src).is_empty());
+        assert_eq!(warnings(src), ["Unknown license: 'MIT'. Recommended: GPL"]);
+    }
+
+    #[test]
+    fn duplicate_units_are_rejected() {
+        let unit = "unit twice {\n    section: \"xdp\";\n    license: \"GPL\";\n    return 2;\n}\n";
+        assert_error(&format!("{}{}", unit, unit), "Duplicate unit name: 'twice'");
+    }
+
+    #[test]
+    fn fallback_names_a_verdict_of_the_program_type() {
+        let src = "unit lb {\n    section: \"xdp\";\n    license: \"GPL\";\n    fallback: XDP_DROP;\n    return ctx.load_u8(23);\n}\n";
+        assert!(errors(src).is_empty(), "{:?}", errors(src));
+
+        let src = "unit lb {\n    section: \"tc\";\n    license: \"GPL\";\n    fallback: XDP_DROP;\n    return 0;\n}\n";
+        assert_error(src, "Unknown fallback verdict: 'XDP_DROP'");
+
+        let src = "unit tp {\n    section: \"tracepoint/x/y\";\n    license: \"GPL\";\n    fallback: 1;\n    return 0;\n}\n";
+        assert_error(src, "`fallback` only applies to programs with packet access");
+    }
+}
 
-    Ok(())
-}
\ No newline at end of file